@@ -0,0 +1,168 @@
+use crate::plan::Change;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use serde::Serialize;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+
+// keys the digests, see load_key
+static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// The digest of the `.dockerconfigjson` of a secret before and after a
+/// change, None when it didn't exist. The credentials are never logged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Digests {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// One mutation made by the sync worker, a line of the audit log.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub change: Change,
+    // the kubeconfig context of the --clusters, the local cluster has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster: Option<String>,
+    // what queued the namespace, e.g. "config secret changed"
+    pub trigger: String,
+    // the resourceVersions the registry configs were read at
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<String>,
+}
+
+/// Writes the AuditEntries as JSON lines to stdout or a file.
+pub struct AuditLog {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AuditLog {
+    /// Write to stdout for `-`, else append to the file `target`.
+    pub fn open(target: &str) -> Result<Self> {
+        if target == "-" {
+            return Ok(AuditLog::new(std::io::stdout()));
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(target)
+            .with_context(|| format!("open audit log {}", target))?;
+        Ok(AuditLog::new(file))
+    }
+
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        AuditLog {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        let mut line = serde_json::to_vec(entry).expect("serialize audit entry");
+        line.push(b'\n');
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(&line).and_then(|_| out.flush()) {
+            warn!("write audit log err: {}", e);
+        }
+    }
+}
+
+/// Key the digests with the content of the file `path`, so they can be
+/// compared across restarts and replicas. Without a key file every process
+/// uses a random key.
+pub fn load_key(path: &Path) -> Result<()> {
+    let key = fs::read(path).with_context(|| format!("read audit key {:?}", path))?;
+    if key.len() < 16 {
+        return Err(anyhow!("audit key {:?} must have at least 16 bytes", path));
+    }
+    KEY.set(key)
+        .map_err(|_| anyhow!("the audit key is already in use"))
+}
+
+fn key() -> &'static [u8] {
+    KEY.get_or_init(|| {
+        let mut key = vec![0; 32];
+        openssl::rand::rand_bytes(&mut key).expect("generate audit key");
+        key
+    })
+}
+
+/// "hmac-sha256:<hex>" of `data` under the audit key. A plain hash of a
+/// `.dockerconfigjson` would let guessed passwords be checked against the log.
+pub fn digest(data: &[u8]) -> String {
+    format!("hmac-sha256:{}", hmac(key(), data))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> String {
+    let key = PKey::hmac(key).expect("hmac key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("hmac signer");
+    signer
+        .sign_oneshot_to_vec(data)
+        .expect("hmac sign")
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{digest, hmac, AuditEntry};
+    use crate::plan::{Action, Change};
+    use serde_json::json;
+
+    #[test]
+    fn entry() {
+        assert_eq!(
+            hmac(b"key", b"The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+        // keyed, not the plain sha256 of "abc"
+        assert!(digest(b"abc").starts_with("hmac-sha256:"));
+        assert_ne!(
+            digest(b"abc"),
+            "hmac-sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digest(b"abc"), digest(b"abc"));
+
+        let entry = AuditEntry {
+            time: "2021-01-01T00:00:00Z".parse().unwrap(),
+            change: Change {
+                action: Action::Patch,
+                kind: "secret",
+                namespace: "team-a".to_string(),
+                name: "quay.io".to_string(),
+                detail: Some("data".to_string()),
+            },
+            cluster: None,
+            trigger: "config secret changed".to_string(),
+            config_revision: Some("secret=42".to_string()),
+            before: Some(digest(b"old")),
+            after: Some(digest(b"new")),
+        };
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            json!({
+                "time": "2021-01-01T00:00:00Z",
+                "action": "patch",
+                "kind": "secret",
+                "namespace": "team-a",
+                "name": "quay.io",
+                "detail": "data",
+                "trigger": "config secret changed",
+                "configRevision": "secret=42",
+                "before": digest(b"old"),
+                "after": digest(b"new"),
+            })
+        );
+    }
+}
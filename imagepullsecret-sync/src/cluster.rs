@@ -0,0 +1,25 @@
+use anyhow::{Context, Result};
+use kube::{
+    config::{KubeConfigOptions, Kubeconfig},
+    Client, Config,
+};
+use std::{convert::TryFrom, path::Path};
+
+/// A client for the kubeconfig `context`, from the `kubeconfig` file or the
+/// default one like kubectl reads it.
+pub async fn client(context: &str, kubeconfig: Option<&Path>) -> Result<Client> {
+    let options = KubeConfigOptions {
+        context: Some(context.to_string()),
+        ..KubeConfigOptions::default()
+    };
+    let config = match kubeconfig {
+        Some(path) => {
+            let file = Kubeconfig::read_from(path)
+                .with_context(|| format!("read kubeconfig {}", path.display()))?;
+            Config::from_custom_kubeconfig(file, &options).await
+        }
+        None => Config::from_kubeconfig(&options).await,
+    }
+    .with_context(|| format!("load kubeconfig context '{}'", context))?;
+    Ok(Client::try_from(config)?)
+}
@@ -1,14 +1,281 @@
+use crate::{
+    credentials::{CredentialSource, OPT_IN_SOURCES},
+    selector::LabelSelector,
+    settings::Settings,
+};
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    path::{Component, Path},
+};
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub server: String,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
-    // if namespaces.len()==0 || namespaces.contains("*")
-    // then this config effect all namespace, else this config
-    // only effect on the specify namespaces.
+    // an external source of username and password, replaces the inline ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<CredentialSource>,
+    // each entry is an exact name, "*" for all namespaces, a glob
+    // like "dev-*" or a regex wrapped in slashes like "/^team-(a|b)$/".
+    #[serde(default)]
     pub namespaces: Vec<String>,
+    // namespaces whose labels match this selector are selected too,
+    // e.g. "team=payments".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace_selector: Option<String>,
+    // same patterns as namespaces, an excluded namespace is never selected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude_namespaces: Vec<String>,
+    // the service accounts referencing the secret instead of the
+    // --service-accounts and --service-account-selector ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service_accounts: Vec<String>,
+}
+
+impl Config {
+    pub fn namespace_filter(&self) -> Result<NamespaceFilter> {
+        let include = compile_patterns(&self.namespaces)?;
+        let exclude = compile_patterns(&self.exclude_namespaces)?;
+        let selector = match &self.namespace_selector {
+            Some(s) => Some(
+                LabelSelector::parse(s)
+                    .with_context(|| format!("invalid namespace_selector '{}'", s))?,
+            ),
+            None => None,
+        };
+
+        Ok(NamespaceFilter {
+            include,
+            selector,
+            exclude,
+        })
+    }
+
+    /// The mistakes in this config as (field, message) under the settings,
+    /// e.g. an exec token must outlive the --token-refresh-before.
+    pub fn validate(&self, settings: &Settings) -> Vec<(&'static str, String)> {
+        let refresh_before = settings.token_refresh_before;
+        let mut problems = Vec::new();
+        if self.server.trim().is_empty() {
+            problems.push(("server", "server must not be empty".to_string()));
+        }
+        match &self.credentials {
+            Some(source) => {
+                let kind = source.kind();
+                if OPT_IN_SOURCES.contains(&kind)
+                    && !settings.credential_sources.iter().any(|s| s == kind)
+                {
+                    problems.push((
+                        "credentials",
+                        format!(
+                            "credentials source {} is disabled, it can be enabled with --credential-sources",
+                            kind
+                        ),
+                    ));
+                }
+                for field in source.empty_fields() {
+                    problems.push((
+                        "credentials",
+                        format!("credentials {} must not be empty", field),
+                    ));
+                }
+                if let CredentialSource::SecretRef { namespace, .. } = source {
+                    if !namespace.trim().is_empty()
+                        && !settings.credential_namespace_allowed(namespace)
+                    {
+                        problems.push((
+                            "credentials",
+                            format!(
+                                "credentials namespace '{}' is not allowed, only the config namespace {} or the --credential-namespaces are",
+                                namespace, settings.config_namespace
+                            ),
+                        ));
+                    }
+                }
+                if let CredentialSource::File {
+                    username_path,
+                    password_path,
+                } = source
+                {
+                    for path in [username_path, password_path] {
+                        if !path.trim().is_empty()
+                            && !in_credential_dir(path, settings.credential_dir.as_deref())
+                        {
+                            problems.push((
+                                "credentials",
+                                match &settings.credential_dir {
+                                    Some(dir) => format!(
+                                        "credentials path '{}' must be below the --credential-dir {}",
+                                        path,
+                                        dir.display()
+                                    ),
+                                    None => format!(
+                                        "credentials path '{}' can't be read without a --credential-dir",
+                                        path
+                                    ),
+                                },
+                            ));
+                        }
+                    }
+                }
+                if let CredentialSource::Exec { ttl, .. } = source {
+                    if *ttl <= refresh_before {
+                        problems.push((
+                            "credentials",
+                            format!(
+                                "credentials ttl {}s must be longer than the token refresh lead of {}s",
+                                ttl, refresh_before
+                            ),
+                        ));
+                    }
+                }
+            }
+            None => {
+                if self.username.is_empty() || self.password.is_empty() {
+                    problems.push((
+                        "server",
+                        "username and password or credentials are required".to_string(),
+                    ));
+                }
+            }
+        }
+        if let Err(e) = compile_patterns(&self.namespaces) {
+            problems.push(("namespaces", format!("{:#}", e)));
+        }
+        if let Err(e) = compile_patterns(&self.exclude_namespaces) {
+            problems.push(("exclude_namespaces", format!("{:#}", e)));
+        }
+        if let Some(s) = &self.namespace_selector {
+            if let Err(e) = LabelSelector::parse(s) {
+                problems.push((
+                    "namespace_selector",
+                    format!("invalid namespace_selector '{}': {:#}", s, e),
+                ));
+            } else if settings.namespace_scoped() {
+                problems.push((
+                    "namespace_selector",
+                    "namespace_selector can't be used with --watch-namespaces, their labels can't be read"
+                        .to_string(),
+                ));
+            }
+        }
+        if self.service_accounts.iter().any(|sa| sa.trim().is_empty()) {
+            problems.push((
+                "service_accounts",
+                "service_accounts entries must not be empty".to_string(),
+            ));
+        }
+        if self.namespaces.is_empty() && self.namespace_selector.is_none() {
+            problems.push((
+                "server",
+                "namespaces or namespace_selector is required, the registry selects no namespace"
+                    .to_string(),
+            ));
+        }
+        problems
+    }
+}
+
+// a file source only reads below the directory the operator mounted the
+// credentials in, never e.g. the service account token of the worker
+fn in_credential_dir(path: &str, dir: Option<&Path>) -> bool {
+    let path = Path::new(path);
+    dir.is_some_and(|dir| {
+        path.starts_with(dir)
+            && path
+                .components()
+                .all(|c| matches!(c, Component::RootDir | Component::Normal(_)))
+    })
+}
+
+/// Decides whether a [`Config`] applies to a namespace.
+#[derive(Debug, Clone)]
+pub struct NamespaceFilter {
+    include: Vec<NamespacePattern>,
+    selector: Option<LabelSelector>,
+    exclude: Vec<NamespacePattern>,
+}
+
+impl NamespaceFilter {
+    /// Whether matching needs the labels of the namespace.
+    pub fn has_selector(&self) -> bool {
+        self.selector.is_some()
+    }
+
+    pub fn matches(&self, name: &str, labels: &Option<BTreeMap<String, String>>) -> bool {
+        if self.exclude.iter().any(|p| p.matches(name)) {
+            return false;
+        }
+
+        self.include.iter().any(|p| p.matches(name))
+            || self
+                .selector
+                .as_ref()
+                .is_some_and(|s| s.matches_opt(labels))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum NamespacePattern {
+    All,
+    Exact(String),
+    Regex(Regex),
+}
+
+impl NamespacePattern {
+    fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(NamespacePattern::All);
+        }
+        if s.len() > 1 && s.starts_with('/') && s.ends_with('/') {
+            let re = Regex::new(&s[1..s.len() - 1])
+                .with_context(|| format!("invalid namespace regex '{}'", s))?;
+            return Ok(NamespacePattern::Regex(re));
+        }
+        if s.contains(['*', '?']) {
+            return Ok(NamespacePattern::Regex(glob_to_regex(s)?));
+        }
+        if s.is_empty() {
+            return Err(anyhow!("empty namespace pattern"));
+        }
+        Ok(NamespacePattern::Exact(s.to_string()))
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NamespacePattern::All => true,
+            NamespacePattern::Exact(n) => n == name,
+            NamespacePattern::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Result<Vec<NamespacePattern>> {
+    patterns
+        .iter()
+        .map(|p| NamespacePattern::parse(p))
+        .collect()
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+
+    Regex::new(&re).with_context(|| format!("invalid namespace glob '{}'", glob))
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -23,6 +290,40 @@ impl RegistryAuth {
 
         RegistryAuth { auths }
     }
+
+    /// One auth entry per config, a later config wins on duplicate servers.
+    pub fn from_configs<'a>(configs: impl IntoIterator<Item = &'a Config>) -> Self {
+        let mut auth = RegistryAuth::default();
+        for cfg in configs {
+            auth.insert(
+                cfg.username.clone(),
+                cfg.password.clone(),
+                cfg.server.clone(),
+            );
+        }
+        auth
+    }
+
+    pub fn insert(&mut self, username: String, password: String, server: String) {
+        self.auths.insert(server, UserInfo::new(username, password));
+    }
+
+    /// Copy the entries of `servers` missing here from `current`, returns
+    /// the servers copied.
+    pub fn keep(&mut self, current: &RegistryAuth, servers: &[String]) -> Vec<String> {
+        let mut kept = Vec::new();
+        for server in servers.iter() {
+            if self.auths.contains_key(server) {
+                continue;
+            }
+            if let Some(info) = current.auths.get(server) {
+                self.auths.insert(server.clone(), info.clone());
+                kept.push(server.clone());
+            }
+        }
+        kept
+    }
+
     pub fn base64_encode(&self) -> String {
         base64::encode(serde_json::to_string(self).unwrap())
     }
@@ -48,7 +349,8 @@ impl UserInfo {
 
 #[cfg(test)]
 mod test {
-    use super::RegistryAuth;
+    use super::{Config, RegistryAuth};
+    use std::collections::BTreeMap;
 
     #[test]
     fn base64_encode() {
@@ -62,4 +364,88 @@ mod test {
         let got = ra.base64_encode();
         assert_eq!(want, got);
     }
+
+    #[test]
+    fn merge_configs() {
+        let configs = [
+            Config {
+                server: "registry.zihua.com".to_string(),
+                username: "yuanzihua".to_string(),
+                password: "123456".to_string(),
+                ..Config::default()
+            },
+            Config {
+                server: "registry.yahaa.com".to_string(),
+                username: "yahaa".to_string(),
+                password: "654321".to_string(),
+                ..Config::default()
+            },
+        ];
+
+        let ra = RegistryAuth::from_configs(configs.iter());
+        let servers: Vec<&String> = ra.auths.keys().collect();
+        assert_eq!(servers, vec!["registry.yahaa.com", "registry.zihua.com"]);
+
+        let single = RegistryAuth::from_configs(configs.iter().take(1));
+        let want = RegistryAuth::new(
+            "yuanzihua".to_string(),
+            "123456".to_string(),
+            "registry.zihua.com".to_string(),
+        );
+        assert_eq!(single.base64_encode(), want.base64_encode());
+    }
+
+    #[test]
+    fn namespace_filter() {
+        let cfg = Config {
+            namespaces: vec![
+                "default".to_string(),
+                "dev-*".to_string(),
+                "/^team-(a|b)$/".to_string(),
+            ],
+            namespace_selector: Some("team=payments".to_string()),
+            exclude_namespaces: vec!["dev-secret".to_string()],
+            ..Config::default()
+        };
+        let filter = cfg.namespace_filter().unwrap();
+
+        let mut labels = BTreeMap::new();
+        labels.insert("team".to_string(), "payments".to_string());
+        let labels = Some(labels);
+
+        assert!(filter.matches("default", &None));
+        assert!(filter.matches("dev-foo", &None));
+        assert!(filter.matches("team-a", &None));
+        assert!(filter.matches("billing", &labels));
+        assert!(!filter.matches("team-c", &None));
+        assert!(!filter.matches("xdev-foo", &None));
+        assert!(!filter.matches("dev-secret", &None));
+        assert!(!filter.matches("billing", &None));
+    }
+
+    #[test]
+    fn namespace_filter_exclude_all() {
+        let cfg = Config {
+            namespaces: vec!["*".to_string()],
+            exclude_namespaces: vec!["kube-*".to_string()],
+            ..Config::default()
+        };
+        let filter = cfg.namespace_filter().unwrap();
+
+        assert!(filter.matches("default", &None));
+        assert!(!filter.matches("kube-system", &None));
+        assert!(!Config::default()
+            .namespace_filter()
+            .unwrap()
+            .matches("default", &None));
+    }
+
+    #[test]
+    fn namespace_filter_invalid() {
+        let cfg = Config {
+            namespaces: vec!["/(/".to_string()],
+            ..Config::default()
+        };
+        assert!(cfg.namespace_filter().is_err());
+    }
 }
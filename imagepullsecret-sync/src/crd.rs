@@ -0,0 +1,354 @@
+use crate::{
+    config::Config, credentials::CredentialSource, settings::Settings, status::RegistryStatus,
+};
+use chrono::{DateTime, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::Meta;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const GROUP: &str = "imagepullsecret-sync.io";
+pub const VERSION: &str = "v1alpha1";
+pub const KIND: &str = "RegistryCredential";
+
+pub const READY: &str = "Ready";
+// reasons of the Ready condition besides the event reasons
+pub const REASON_INVALID_SPEC: &str = "InvalidSpec";
+pub const REASON_DUPLICATE: &str = "DuplicateServer";
+
+/// A cluster scoped registry to sync, the alternative to an entry of the
+/// config secret. The spec has the fields of an entry, except that the
+/// credentials always come from a secret_ref. The other sources run in the
+/// worker, e.g. exec, which creating a RegistryCredential must not grant.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RegistryCredential {
+    pub metadata: ObjectMeta,
+    pub spec: RegistryCredentialSpec,
+    #[serde(default)]
+    pub status: Option<RegistryCredentialStatus>,
+}
+
+impl k8s_openapi::Resource for RegistryCredential {
+    const API_VERSION: &'static str = "imagepullsecret-sync.io/v1alpha1";
+    const GROUP: &'static str = GROUP;
+    const KIND: &'static str = KIND;
+    const VERSION: &'static str = VERSION;
+}
+
+impl k8s_openapi::Metadata for RegistryCredential {
+    type Ty = ObjectMeta;
+
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+
+    fn metadata_mut(&mut self) -> &mut ObjectMeta {
+        &mut self.metadata
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegistryCredentialSpec {
+    pub server: String,
+    pub credentials: CredentialSource,
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    #[serde(default)]
+    pub namespace_selector: Option<String>,
+    #[serde(default)]
+    pub exclude_namespaces: Vec<String>,
+    #[serde(default)]
+    pub service_accounts: Vec<String>,
+}
+
+impl RegistryCredential {
+    /// The mistakes in the spec, see [`Config::validate`].
+    pub fn validate(&self, settings: &Settings) -> Vec<String> {
+        let secret_ref = matches!(self.spec.credentials, CredentialSource::SecretRef { .. });
+        let mut problems: Vec<String> = self
+            .config()
+            .validate(settings)
+            .into_iter()
+            // the other sources are never allowed, whatever the settings enable
+            .filter(|(field, _)| secret_ref || *field != "credentials")
+            .map(|(_, m)| m)
+            .collect();
+        if !secret_ref {
+            problems.push("credentials must be a secret_ref".to_string());
+        }
+        problems
+    }
+
+    pub fn config(&self) -> Config {
+        let spec = self.spec.clone();
+        Config {
+            server: spec.server,
+            credentials: Some(spec.credentials),
+            namespaces: spec.namespaces,
+            namespace_selector: spec.namespace_selector,
+            exclude_namespaces: spec.exclude_namespaces,
+            service_accounts: spec.service_accounts,
+            ..Config::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryCredentialStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed_generation: Option<i64>,
+    #[serde(default)]
+    pub synced_namespaces: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_namespaces: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    // "True" or "False"
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: DateTime<Utc>,
+}
+
+/// A RegistryCredential which is not synced, by name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejected {
+    pub reason: &'static str,
+    pub message: String,
+}
+
+/// Append the registries of `items` to the `legacy` configs of the config
+/// secret. Invalid ones and those repeating a server of the config secret or
+/// of an older RegistryCredential are rejected.
+pub fn merge(
+    legacy: Vec<Config>,
+    mut items: Vec<RegistryCredential>,
+    settings: &Settings,
+) -> (Vec<Config>, BTreeMap<String, Rejected>) {
+    // the oldest claim of a server wins, like the first entry of the secret
+    items.sort_by(|a, b| {
+        let created = |r: &RegistryCredential| r.metadata.creation_timestamp.clone();
+        created(a)
+            .map(|t| t.0)
+            .cmp(&created(b).map(|t| t.0))
+            .then_with(|| a.name().cmp(&b.name()))
+    });
+
+    let key = |server: &str| server.trim().trim_end_matches('/').to_lowercase();
+    let mut owners: BTreeMap<String, String> = legacy
+        .iter()
+        .map(|cfg| (key(&cfg.server), "the config secret".to_string()))
+        .collect();
+    let mut configs = legacy;
+    let mut rejected = BTreeMap::new();
+    for item in items.iter() {
+        let name = item.name();
+        let cfg = item.config();
+        let problems = item.validate(settings);
+        if !problems.is_empty() {
+            rejected.insert(
+                name,
+                Rejected {
+                    reason: REASON_INVALID_SPEC,
+                    message: problems.join("; "),
+                },
+            );
+            continue;
+        }
+        match owners.get(&key(&cfg.server)) {
+            Some(owner) => {
+                let message = format!("server '{}' is already defined by {}", cfg.server, owner);
+                rejected.insert(
+                    name,
+                    Rejected {
+                        reason: REASON_DUPLICATE,
+                        message,
+                    },
+                );
+            }
+            None => {
+                owners.insert(key(&cfg.server), format!("{} '{}'", KIND, name));
+                configs.push(cfg);
+            }
+        }
+    }
+    (configs, rejected)
+}
+
+/// The status of `item` from its coverage or the reason it was rejected,
+/// None while it was never synced.
+pub fn status(
+    item: &RegistryCredential,
+    coverage: Option<&RegistryStatus>,
+    rejected: Option<&Rejected>,
+    now: DateTime<Utc>,
+) -> Option<RegistryCredentialStatus> {
+    let mut status = RegistryCredentialStatus {
+        observed_generation: item.metadata.generation,
+        ..RegistryCredentialStatus::default()
+    };
+    let (ready, reason, message) = match (rejected, coverage) {
+        (Some(r), _) => (false, r.reason, r.message.clone()),
+        (None, Some(c)) => {
+            status.synced_namespaces = c.synced;
+            status.failed_namespaces = c.failed_namespaces.clone();
+            if let Some(skipped) = &c.skipped {
+                (false, crate::events::REASON_SKIPPED, skipped.clone())
+            } else if c.failed > 0 {
+                let message = format!(
+                    "failed in {} of {} namespaces: {}",
+                    c.failed,
+                    c.failed + c.synced,
+                    c.last_error.as_deref().unwrap_or_default()
+                );
+                (false, crate::events::REASON_FAILED, message)
+            } else {
+                let message = format!("synced to {} namespaces", c.synced);
+                (true, crate::events::REASON_SYNCED, message)
+            }
+        }
+        (None, None) => return None,
+    };
+
+    let ready = if ready { "True" } else { "False" };
+    // the transition time only moves when the condition flips
+    let last_transition_time = item
+        .status
+        .as_ref()
+        .and_then(|s| s.conditions.iter().find(|c| c.type_ == READY))
+        .filter(|c| c.status == ready)
+        .map_or(now, |c| c.last_transition_time);
+    status.conditions.push(Condition {
+        type_: READY.to_string(),
+        status: ready.to_string(),
+        reason: reason.to_string(),
+        message,
+        last_transition_time,
+    });
+    Some(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{merge, status, RegistryCredential, REASON_DUPLICATE, REASON_INVALID_SPEC};
+    use crate::{config::Config, settings::Settings, status::RegistryStatus};
+    use chrono::{TimeZone, Utc};
+    use serde_json::json;
+
+    fn item(name: &str, created: &str, spec: serde_json::Value) -> RegistryCredential {
+        serde_json::from_value(json!({
+            "metadata": { "name": name, "creationTimestamp": created, "generation": 2 },
+            "spec": spec,
+        }))
+        .unwrap()
+    }
+
+    fn spec(server: &str) -> serde_json::Value {
+        json!({
+            "server": server,
+            "credentials": { "secret_ref": { "namespace": "infra", "name": "creds" } },
+            "namespaces": ["*"],
+            "service_accounts": ["builder"],
+        })
+    }
+
+    #[test]
+    fn merge_rejects() {
+        let legacy = vec![Config {
+            server: "a.io".to_string(),
+            ..Config::default()
+        }];
+        let items = vec![
+            item("b-new", "2021-02-01T00:00:00Z", spec("B.io/")),
+            item("b-old", "2021-01-01T00:00:00Z", spec("b.io")),
+            item("a", "2021-01-01T00:00:00Z", spec("a.io")),
+            item(
+                "invalid",
+                "2021-01-01T00:00:00Z",
+                json!({ "server": "c.io", "credentials": { "secret_ref": { "namespace": "", "name": "c" } } }),
+            ),
+            item(
+                "elsewhere",
+                "2021-01-01T00:00:00Z",
+                json!({ "server": "e.io", "credentials": { "secret_ref": { "namespace": "kube-system", "name": "e" } }, "namespaces": ["*"] }),
+            ),
+            item(
+                "exec",
+                "2021-01-01T00:00:00Z",
+                json!({ "server": "d.io", "credentials": { "exec": { "command": "sh" } }, "namespaces": ["*"] }),
+            ),
+        ];
+
+        let settings = Settings {
+            credential_namespaces: vec!["infra".to_string()],
+            ..Settings::default()
+        };
+        let (configs, rejected) = merge(legacy, items, &settings);
+        let servers: Vec<&str> = configs.iter().map(|c| c.server.as_str()).collect();
+        assert_eq!(servers, vec!["a.io", "b.io"]);
+        assert_eq!(configs[1].service_accounts, vec!["builder"]);
+        assert!(configs[1].credentials.is_some());
+
+        assert_eq!(rejected.len(), 5);
+        assert_eq!(rejected["a"].reason, REASON_DUPLICATE);
+        assert_eq!(
+            rejected["b-new"].message,
+            "server 'B.io/' is already defined by RegistryCredential 'b-old'"
+        );
+        assert_eq!(rejected["invalid"].reason, REASON_INVALID_SPEC);
+        assert_eq!(rejected["elsewhere"].reason, REASON_INVALID_SPEC);
+        assert_eq!(rejected["exec"].message, "credentials must be a secret_ref");
+    }
+
+    #[test]
+    fn ready_condition() {
+        let mut cr = item("b", "2021-01-01T00:00:00Z", spec("b.io"));
+        let t1 = Utc.ymd(2021, 3, 1).and_hms(0, 0, 0);
+        let t2 = Utc.ymd(2021, 3, 2).and_hms(0, 0, 0);
+        assert_eq!(status(&cr, None, None, t1), None);
+
+        let synced = RegistryStatus {
+            synced: 3,
+            ..RegistryStatus::default()
+        };
+        let s = status(&cr, Some(&synced), None, t1).unwrap();
+        assert_eq!(s.observed_generation, Some(2));
+        assert_eq!(s.synced_namespaces, 3);
+        assert_eq!(s.conditions[0].status, "True");
+        assert_eq!(s.conditions[0].message, "synced to 3 namespaces");
+
+        // unchanged readiness keeps the transition time
+        cr.status = Some(s.clone());
+        let more = RegistryStatus {
+            synced: 4,
+            ..RegistryStatus::default()
+        };
+        let s = status(&cr, Some(&more), None, t2).unwrap();
+        assert_eq!(s.conditions[0].last_transition_time, t1);
+
+        let failed = RegistryStatus {
+            synced: 3,
+            failed: 1,
+            failed_namespaces: vec!["team-b".to_string()],
+            last_error: Some("forbidden".to_string()),
+            ..RegistryStatus::default()
+        };
+        let s = status(&cr, Some(&failed), None, t2).unwrap();
+        assert_eq!(s.conditions[0].status, "False");
+        assert_eq!(s.conditions[0].reason, "SyncFailed");
+        assert_eq!(
+            s.conditions[0].message,
+            "failed in 1 of 4 namespaces: forbidden"
+        );
+        assert_eq!(s.conditions[0].last_transition_time, t2);
+    }
+}
@@ -0,0 +1,452 @@
+use crate::{
+    config::Config,
+    token::{ExecProvider, TokenCache, DEFAULT_TOKEN_TTL},
+};
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::OnceLock,
+    time::Duration,
+};
+
+// per request, a hung source must not hold up the sync
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// shared by the http sources, it pools their connections
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .expect("build http client")
+    })
+}
+
+/// The sources which run something in the worker or read its files and
+/// environment, they have to be enabled with --credential-sources.
+pub const OPT_IN_SOURCES: &[&str] = &["file", "env", "http", "exec"];
+
+/// Where the username and password of a registry come from, when a config
+/// has no `credentials` the inline `username`/`password` are used.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum CredentialSource {
+    /// keys of a Secret, possibly in another namespace
+    SecretRef {
+        namespace: String,
+        name: String,
+        #[serde(default = "default_username_key")]
+        username_key: String,
+        #[serde(default = "default_password_key")]
+        password_key: String,
+    },
+    /// files, e.g. the keys of a mounted Secret volume
+    File {
+        username_path: String,
+        password_path: String,
+    },
+    /// environment variables of the worker
+    Env { username: String, password: String },
+    /// a json document fetched with GET, by default shaped like a Vault KV v2 secret
+    Http {
+        url: String,
+        #[serde(default)]
+        token_env: Option<String>,
+        #[serde(default = "default_token_header")]
+        token_header: String,
+        #[serde(default = "default_username_pointer")]
+        username_pointer: String,
+        #[serde(default = "default_password_pointer")]
+        password_pointer: String,
+    },
+    /// a short-lived token printed by a command, refreshed before it expires
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        // when the output only has the token, e.g. "AWS" for ECR
+        #[serde(default)]
+        username: Option<String>,
+        // seconds, when the output has no expiry
+        #[serde(default = "default_token_ttl")]
+        ttl: u64,
+    },
+}
+
+impl CredentialSource {
+    /// The name of the source in the configs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CredentialSource::SecretRef { .. } => "secret_ref",
+            CredentialSource::File { .. } => "file",
+            CredentialSource::Env { .. } => "env",
+            CredentialSource::Http { .. } => "http",
+            CredentialSource::Exec { .. } => "exec",
+        }
+    }
+
+    /// Whether resolving the credentials only reads, a dry run doesn't run
+    /// commands or send requests.
+    pub fn is_read_only(&self) -> bool {
+        !matches!(
+            self,
+            CredentialSource::Http { .. } | CredentialSource::Exec { .. }
+        )
+    }
+
+    /// Whether the credentials can change without the config changing.
+    pub fn is_external(&self) -> bool {
+        !matches!(self, CredentialSource::Exec { .. })
+    }
+
+    /// The required fields which are empty.
+    pub fn empty_fields(&self) -> Vec<&'static str> {
+        let required = match self {
+            CredentialSource::SecretRef {
+                namespace, name, ..
+            } => vec![("namespace", namespace), ("name", name)],
+            CredentialSource::File {
+                username_path,
+                password_path,
+            } => vec![
+                ("username_path", username_path),
+                ("password_path", password_path),
+            ],
+            CredentialSource::Env { username, password } => {
+                vec![("username", username), ("password", password)]
+            }
+            CredentialSource::Http { url, .. } => vec![("url", url)],
+            CredentialSource::Exec { command, .. } => vec![("command", command)],
+        };
+        required
+            .into_iter()
+            .filter(|(_, v)| v.trim().is_empty())
+            .map(|(field, _)| field)
+            .collect()
+    }
+}
+
+fn default_username_key() -> String {
+    "username".to_string()
+}
+
+fn default_password_key() -> String {
+    "password".to_string()
+}
+
+fn default_token_header() -> String {
+    "X-Vault-Token".to_string()
+}
+
+fn default_username_pointer() -> String {
+    "/data/data/username".to_string()
+}
+
+fn default_password_pointer() -> String {
+    "/data/data/password".to_string()
+}
+
+fn default_token_ttl() -> u64 {
+    DEFAULT_TOKEN_TTL
+}
+
+#[derive(Clone, PartialEq, Hash)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+// never print the password
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+/// Resolve the credentials of `cfg` from its source, tokens come from the
+/// `tokens` cache while they are fresh.
+pub async fn resolve(client: &Client, tokens: &TokenCache, cfg: &Config) -> Result<Credentials> {
+    let source = match &cfg.credentials {
+        Some(source) => source,
+        None => {
+            return Ok(Credentials {
+                username: cfg.username.clone(),
+                password: cfg.password.clone(),
+            })
+        }
+    };
+
+    match source {
+        CredentialSource::SecretRef {
+            namespace,
+            name,
+            username_key,
+            password_key,
+        } => {
+            let api = Api::<Secret>::namespaced(client.clone(), namespace);
+            let secret = api
+                .get(name)
+                .await
+                .with_context(|| format!("get secret '{}/{}'", namespace, name))?;
+            let data = secret.data.unwrap_or_default();
+            let read = |key: &str| -> Result<String> {
+                let v = data.get(key).ok_or_else(|| {
+                    anyhow!("key '{}' not in secret '{}/{}'", key, namespace, name)
+                })?;
+                Ok(String::from_utf8(v.0.clone())?.trim().to_string())
+            };
+            Ok(Credentials {
+                username: read(username_key)?,
+                password: read(password_key)?,
+            })
+        }
+        CredentialSource::File {
+            username_path,
+            password_path,
+        } => Ok(Credentials {
+            username: read_file(username_path).await?,
+            password: read_file(password_path).await?,
+        }),
+        CredentialSource::Env { username, password } => Ok(Credentials {
+            username: std::env::var(username).with_context(|| format!("read env {}", username))?,
+            password: std::env::var(password).with_context(|| format!("read env {}", password))?,
+        }),
+        CredentialSource::Http {
+            url,
+            token_env,
+            token_header,
+            username_pointer,
+            password_pointer,
+        } => {
+            let mut req = http_client().get(url);
+            if let Some(env) = token_env {
+                let token = std::env::var(env).with_context(|| format!("read env {}", env))?;
+                req = req.header(token_header.as_str(), token);
+            }
+            let doc: serde_json::Value = req
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+                .with_context(|| format!("get {}", url))?
+                .json()
+                .await
+                .with_context(|| format!("decode {}", url))?;
+            let read = |pointer: &str| -> Result<String> {
+                doc.pointer(pointer)
+                    .and_then(|v| v.as_str())
+                    .map(String::from)
+                    .ok_or_else(|| anyhow!("no string at '{}' in {}", pointer, url))
+            };
+            Ok(Credentials {
+                username: read(username_pointer)?,
+                password: read(password_pointer)?,
+            })
+        }
+        CredentialSource::Exec {
+            command,
+            args,
+            username,
+            ttl,
+        } => {
+            let provider = ExecProvider {
+                command: command.clone(),
+                args: args.clone(),
+                username: username.clone(),
+                ttl: chrono::Duration::seconds(*ttl as i64),
+            };
+            let key = format!("{}|{:?}", cfg.server, source);
+            let token = tokens.token(&key, &provider).await?;
+            Ok(Credentials {
+                username: token.username,
+                password: token.password,
+            })
+        }
+    }
+}
+
+async fn read_file(path: &str) -> Result<String> {
+    let data = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("read file {}", path))?;
+    Ok(data.trim().to_string())
+}
+
+/// Resolve every config, the returned configs carry the resolved inline
+/// credentials. Configs whose source fails are returned as errors.
+pub async fn resolve_all(
+    client: &Client,
+    tokens: &TokenCache,
+    configs: Vec<Config>,
+) -> (Vec<Config>, Vec<(Config, anyhow::Error)>) {
+    let mut resolved = Vec::new();
+    let mut failed = Vec::new();
+    for mut cfg in configs.into_iter() {
+        match resolve(client, tokens, &cfg).await {
+            Ok(c) => {
+                cfg.username = c.username;
+                cfg.password = c.password;
+                resolved.push(cfg);
+            }
+            Err(e) => failed.push((cfg, e)),
+        }
+    }
+    (resolved, failed)
+}
+
+/// A fingerprint of resolved configs, used to notice credential changes
+/// without keeping the plaintext around.
+pub fn fingerprint(configs: &[Config]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for cfg in configs.iter() {
+        cfg.server.hash(&mut hasher);
+        cfg.username.hash(&mut hasher);
+        cfg.password.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fingerprint, CredentialSource, Credentials};
+    use crate::{config::Config, token::TokenCache};
+    use anyhow::Result;
+    use std::io::Write;
+
+    async fn resolve(cfg: &Config) -> Result<Credentials> {
+        let client = kube::Client::new(kube::Config::new("http://127.0.0.1:1".parse().unwrap()));
+        let tokens = TokenCache::new(chrono::Duration::seconds(60));
+        super::resolve(&client, &tokens, cfg).await
+    }
+
+    #[tokio::test]
+    async fn inline() {
+        let cfg = Config {
+            username: "u".to_string(),
+            password: "p".to_string(),
+            ..Config::default()
+        };
+        let c = resolve(&cfg).await.unwrap();
+        assert_eq!((c.username.as_str(), c.password.as_str()), ("u", "p"));
+    }
+
+    #[tokio::test]
+    async fn env_and_file() {
+        std::env::set_var("IPS_TEST_REGISTRY_USER", "env-user");
+        std::env::set_var("IPS_TEST_REGISTRY_PASS", "env-pass");
+        let cfg = Config {
+            credentials: Some(CredentialSource::Env {
+                username: "IPS_TEST_REGISTRY_USER".to_string(),
+                password: "IPS_TEST_REGISTRY_PASS".to_string(),
+            }),
+            ..Config::default()
+        };
+        let c = resolve(&cfg).await.unwrap();
+        assert_eq!(
+            (c.username.as_str(), c.password.as_str()),
+            ("env-user", "env-pass")
+        );
+
+        let dir = std::env::temp_dir().join(format!("ips-creds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (user, pass) = (dir.join("username"), dir.join("password"));
+        writeln!(std::fs::File::create(&user).unwrap(), "file-user").unwrap();
+        writeln!(std::fs::File::create(&pass).unwrap(), "file-pass").unwrap();
+        let cfg = Config {
+            credentials: Some(CredentialSource::File {
+                username_path: user.to_string_lossy().to_string(),
+                password_path: pass.to_string_lossy().to_string(),
+            }),
+            ..Config::default()
+        };
+        let c = resolve(&cfg).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            (c.username.as_str(), c.password.as_str()),
+            ("file-user", "file-pass")
+        );
+
+        let cfg = Config {
+            credentials: Some(CredentialSource::Env {
+                username: "IPS_TEST_REGISTRY_MISSING".to_string(),
+                password: "IPS_TEST_REGISTRY_PASS".to_string(),
+            }),
+            ..Config::default()
+        };
+        assert!(resolve(&cfg).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn exec() {
+        let cfg = Config {
+            credentials: Some(CredentialSource::Exec {
+                command: "sh".to_string(),
+                args: vec!["-c".to_string(), "echo token".to_string()],
+                username: Some("AWS".to_string()),
+                ttl: 60,
+            }),
+            ..Config::default()
+        };
+        let c = resolve(&cfg).await.unwrap();
+        assert_eq!((c.username.as_str(), c.password.as_str()), ("AWS", "token"));
+        assert!(!cfg.credentials.unwrap().is_external());
+    }
+
+    #[test]
+    fn parse_sources() {
+        let configs: Vec<Config> = serde_yaml::from_str(
+            r#"
+- server: a.example.com
+  credentials:
+    secret_ref:
+      namespace: infra
+      name: registry-a
+- server: b.example.com
+  credentials:
+    http:
+      url: http://127.0.0.1:8200/v1/secret/data/registry-b
+      token_env: VAULT_TOKEN
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            configs[0].credentials,
+            Some(CredentialSource::SecretRef {
+                namespace: "infra".to_string(),
+                name: "registry-a".to_string(),
+                username_key: "username".to_string(),
+                password_key: "password".to_string(),
+            })
+        );
+        match &configs[1].credentials {
+            Some(CredentialSource::Http {
+                token_header,
+                username_pointer,
+                ..
+            }) => {
+                assert_eq!(token_header, "X-Vault-Token");
+                assert_eq!(username_pointer, "/data/data/username");
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+    }
+
+    #[test]
+    fn fingerprint_changes() {
+        let mut cfg = Config {
+            server: "a".to_string(),
+            password: "1".to_string(),
+            ..Config::default()
+        };
+        let before = fingerprint(&[cfg.clone()]);
+        cfg.password = "2".to_string();
+        assert_ne!(before, fingerprint(&[cfg]));
+    }
+}
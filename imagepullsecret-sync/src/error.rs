@@ -0,0 +1,95 @@
+use thiserror::Error;
+
+/// Errors of the per namespace sync path, one namespace failing never stops
+/// the others.
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("conflict on {kind} '{ns}/{name}': {message}")]
+    Conflict {
+        kind: &'static str,
+        ns: String,
+        name: String,
+        message: String,
+    },
+
+    #[error("forbidden to {verb} {kind} '{ns}/{name}': {message}")]
+    Forbidden {
+        verb: &'static str,
+        kind: &'static str,
+        ns: String,
+        name: String,
+        message: String,
+    },
+
+    #[error("{kind} '{ns}/{name}' not found")]
+    NotFound {
+        kind: &'static str,
+        ns: String,
+        name: String,
+    },
+
+    #[error("{verb} {kind} '{ns}/{name}' err: {source}")]
+    Kube {
+        verb: &'static str,
+        kind: &'static str,
+        ns: String,
+        name: String,
+        #[source]
+        source: kube::Error,
+    },
+
+    #[error("encode {kind} '{ns}/{name}' err: {source}")]
+    Encode {
+        kind: &'static str,
+        ns: String,
+        name: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl SyncError {
+    /// Classify a kube api error of `verb` on the object `kind` `ns/name`.
+    pub fn from_kube(
+        source: kube::Error,
+        verb: &'static str,
+        kind: &'static str,
+        ns: &str,
+        name: &str,
+    ) -> Self {
+        let (ns, name) = (ns.to_string(), name.to_string());
+        match source {
+            kube::Error::Api(e) if e.code == 409 => SyncError::Conflict {
+                kind,
+                ns,
+                name,
+                message: e.message,
+            },
+            kube::Error::Api(e) if e.code == 403 => SyncError::Forbidden {
+                verb,
+                kind,
+                ns,
+                name,
+                message: e.message,
+            },
+            kube::Error::Api(e) if e.code == 404 => SyncError::NotFound { kind, ns, name },
+            source => SyncError::Kube {
+                verb,
+                kind,
+                ns,
+                name,
+                source,
+            },
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, SyncError::NotFound { .. })
+    }
+
+    pub fn is_conflict(&self) -> bool {
+        matches!(self, SyncError::Conflict { .. })
+    }
+}
+
+pub type SyncResult<T> = std::result::Result<T, SyncError>;
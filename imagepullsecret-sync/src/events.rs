@@ -0,0 +1,149 @@
+use chrono::Utc;
+use k8s_openapi::{
+    api::core::v1::{Event, EventSource, ObjectReference},
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, Time},
+};
+use kube::{
+    api::{PatchParams, PostParams},
+    Api, Client,
+};
+use serde_json::json;
+use std::{collections::HashMap, sync::Mutex};
+
+pub const COMPONENT: &str = "imagepullsecret-sync";
+
+pub const REASON_SYNCED: &str = "Synced";
+pub const REASON_FAILED: &str = "SyncFailed";
+pub const REASON_SKIPPED: &str = "Skipped";
+pub const REASON_INVALID: &str = "InvalidConfig";
+
+// repeated events remembered for counting, like the client-go cache
+const MAX_SEEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+impl EventType {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventType::Normal => "Normal",
+            EventType::Warning => "Warning",
+        }
+    }
+}
+
+/// A reference to a core/v1 object for the involvedObject of an event.
+pub fn object_ref(kind: &str, namespace: Option<&str>, name: &str) -> ObjectReference {
+    ObjectReference {
+        api_version: Some("v1".to_string()),
+        kind: Some(kind.to_string()),
+        namespace: namespace.map(String::from),
+        name: Some(name.to_string()),
+        ..ObjectReference::default()
+    }
+}
+
+/// Publishes Kubernetes Events, so `kubectl describe` shows the sync
+/// outcomes. Like client-go an event repeating an earlier one only bumps its
+/// count. Publishing is best effort, failures are logged and never fail the
+/// sync.
+pub struct Recorder {
+    client: Client,
+    instance: String,
+    // "<ns>/<kind>/<name>/<reason>/<message>" -> (event name, count)
+    seen: Mutex<HashMap<String, (String, i32)>>,
+}
+
+impl Recorder {
+    pub fn new(client: Client, instance: &str) -> Self {
+        Recorder {
+            client,
+            instance: instance.to_string(),
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record an event about `object` in namespace `ns`.
+    pub async fn publish(
+        &self,
+        ns: &str,
+        object: &ObjectReference,
+        type_: EventType,
+        reason: &str,
+        message: &str,
+    ) {
+        let name = object.name.as_deref().unwrap_or_default();
+        let key = format!(
+            "{}/{}/{}/{}/{}",
+            ns,
+            object.kind.as_deref().unwrap_or_default(),
+            name,
+            reason,
+            message
+        );
+        let api = Api::<Event>::namespaced(self.client.clone(), ns);
+        let now = Time(Utc::now());
+
+        let seen = self.seen.lock().unwrap().get(&key).cloned();
+        if let Some((event_name, count)) = seen {
+            let patch = json!({ "count": count + 1, "lastTimestamp": now });
+            match api
+                .patch(
+                    &event_name,
+                    &PatchParams::default(),
+                    patch.to_string().into(),
+                )
+                .await
+            {
+                Ok(_) => {
+                    self.remember(key, event_name, count + 1);
+                    return;
+                }
+                // expired by the api server, record it anew
+                Err(kube::Error::Api(e)) if e.code == 404 => {}
+                Err(e) => {
+                    warn!("update event '{}/{}' err: {}", ns, event_name, e);
+                    return;
+                }
+            }
+        }
+
+        let event_name = format!("{}.{:x}", name, Utc::now().timestamp_nanos());
+        let event = Event {
+            metadata: ObjectMeta {
+                name: Some(event_name.clone()),
+                namespace: Some(ns.to_string()),
+                ..ObjectMeta::default()
+            },
+            involved_object: object.clone(),
+            type_: Some(type_.as_str().to_string()),
+            reason: Some(reason.to_string()),
+            message: Some(message.to_string()),
+            count: Some(1),
+            first_timestamp: Some(now.clone()),
+            last_timestamp: Some(now),
+            source: Some(EventSource {
+                component: Some(COMPONENT.to_string()),
+                host: None,
+            }),
+            reporting_component: Some(COMPONENT.to_string()),
+            reporting_instance: Some(self.instance.clone()),
+            ..Event::default()
+        };
+        match api.create(&PostParams::default(), &event).await {
+            Ok(_) => self.remember(key, event_name, 1),
+            Err(e) => warn!("create event '{}/{}' err: {}", ns, event_name, e),
+        }
+    }
+
+    fn remember(&self, key: String, event_name: String, count: i32) {
+        let mut seen = self.seen.lock().unwrap();
+        if seen.len() >= MAX_SEEN && !seen.contains_key(&key) {
+            seen.clear();
+        }
+        seen.insert(key, (event_name, count));
+    }
+}
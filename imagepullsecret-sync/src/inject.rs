@@ -0,0 +1,255 @@
+use crate::{
+    config::{Config, NamespaceFilter},
+    settings::Settings,
+    worker::SyncWorker,
+};
+use anyhow::Result;
+use k8s_openapi::api::core::v1::{Namespace, Pod};
+use kube::{Api, Client};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// the configs are read again at most this often
+const CONFIG_TTL: Duration = Duration::from_secs(30);
+
+// the configs with their namespace filter and secret name
+type Filtered = Arc<Vec<(Config, NamespaceFilter, String)>>;
+
+/// The host of a config server or image registry in lower case, Docker Hub
+/// aliases like "https://index.docker.io/v1/" are "docker.io".
+pub fn registry_host(server: &str) -> String {
+    let s = server.trim();
+    let s = s
+        .strip_prefix("https://")
+        .or_else(|| s.strip_prefix("http://"))
+        .unwrap_or(s);
+    let host = s.split('/').next().unwrap_or_default().to_lowercase();
+    match host.as_str() {
+        "index.docker.io" | "registry-1.docker.io" => "docker.io".to_string(),
+        _ => host,
+    }
+}
+
+/// The registry host of an image reference like docker resolves it, images
+/// without one like "nginx" or "library/nginx:1.19" are on "docker.io".
+pub fn image_registry(image: &str) -> String {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains(['.', ':']) || first == "localhost" => {
+            registry_host(first)
+        }
+        _ => "docker.io".to_string(),
+    }
+}
+
+/// The registry secrets the containers of `pod` pull from which it doesn't
+/// reference yet, `configs` are the ones matching its namespace with the
+/// name of their secret.
+pub fn missing_secrets(pod: &Pod, configs: &[(&Config, &str)]) -> Vec<String> {
+    let spec = match &pod.spec {
+        Some(spec) => spec,
+        None => return vec![],
+    };
+    let images: Vec<String> = spec
+        .containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+        .filter_map(|c| c.image.as_deref())
+        .map(image_registry)
+        .collect();
+    let present: Vec<&str> = spec
+        .image_pull_secrets
+        .iter()
+        .flatten()
+        .filter_map(|r| r.name.as_deref())
+        .collect();
+
+    let mut missing = Vec::new();
+    for (cfg, name) in configs.iter().copied() {
+        if !images.contains(&registry_host(&cfg.server)) {
+            continue;
+        }
+        if !present.contains(&name) && !missing.iter().any(|m| m == name) {
+            missing.push(name.to_string());
+        }
+    }
+    missing
+}
+
+/// The JSON patch adding references to `secrets` to `pod`.
+pub fn json_patch(pod: &Pod, secrets: &[String]) -> Value {
+    let refs: Vec<Value> = secrets.iter().map(|name| json!({ "name": name })).collect();
+    let has_list = pod
+        .spec
+        .as_ref()
+        .is_some_and(|spec| spec.image_pull_secrets.is_some());
+    if has_list {
+        let ops: Vec<Value> = refs
+            .into_iter()
+            .map(|r| json!({ "op": "add", "path": "/spec/imagePullSecrets/-", "value": r }))
+            .collect();
+        Value::Array(ops)
+    } else {
+        json!([{ "op": "add", "path": "/spec/imagePullSecrets", "value": refs }])
+    }
+}
+
+/// Adds the registry secrets of the matching configs to new Pods, for the
+/// mutating admission webhook.
+pub struct Injector {
+    worker: SyncWorker,
+    client: Client,
+    // the only namespaces with registry secrets in namespace-scoped mode
+    watch_namespaces: Vec<String>,
+    cache: Mutex<Option<(Instant, Filtered)>>,
+}
+
+impl Injector {
+    pub fn new(client: Client, settings: Settings) -> Result<Self> {
+        Ok(Injector {
+            watch_namespaces: settings.watch_namespaces.clone(),
+            worker: SyncWorker::new(client.clone(), settings)?.with_dry_run(true),
+            client,
+            cache: Mutex::new(None),
+        })
+    }
+
+    // the configs with their namespace filters, invalid ones are left out
+    async fn configs(&self) -> Result<Filtered> {
+        let cached = self.cache.lock().unwrap().clone();
+        if let Some((read_at, configs)) = cached {
+            if read_at.elapsed() < CONFIG_TTL {
+                return Ok(configs);
+            }
+        }
+        let configs = self.worker.read_config().await?;
+        let configs: Vec<(Config, NamespaceFilter, String)> = self
+            .worker
+            .secret_names(configs)
+            .into_iter()
+            .filter_map(|(cfg, name)| cfg.namespace_filter().ok().map(|f| (cfg, f, name)))
+            .collect();
+        let configs = Arc::new(configs);
+        *self.cache.lock().unwrap() = Some((Instant::now(), configs.clone()));
+        Ok(configs)
+    }
+
+    /// The JSON patch adding the missing registry secrets to `pod` in `ns`,
+    /// None when it misses none.
+    pub async fn patch(&self, ns: &str, pod: &Pod) -> Result<Option<Value>> {
+        let scoped = !self.watch_namespaces.is_empty();
+        if scoped && !self.watch_namespaces.iter().any(|n| n == ns) {
+            return Ok(None);
+        }
+        let configs = self.configs().await?;
+        // namespace labels can't be read in namespace-scoped mode
+        let labels: Option<BTreeMap<String, String>> =
+            if !scoped && configs.iter().any(|(_, f, _)| f.has_selector()) {
+                let ns = Api::<Namespace>::all(self.client.clone()).get(ns).await?;
+                ns.metadata.labels
+            } else {
+                None
+            };
+        let matched: Vec<(&Config, &str)> = configs
+            .iter()
+            .filter(|(_, filter, _)| filter.matches(ns, &labels))
+            .map(|(cfg, _, name)| (cfg, name.as_str()))
+            .collect();
+
+        let missing = missing_secrets(pod, &matched);
+        if missing.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(json_patch(pod, &missing)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{image_registry, json_patch, missing_secrets, registry_host};
+    use crate::config::Config;
+    use k8s_openapi::api::core::v1::Pod;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn registries() {
+        assert_eq!(image_registry("nginx"), "docker.io");
+        assert_eq!(image_registry("library/nginx:1.19"), "docker.io");
+        assert_eq!(image_registry("docker.io/library/nginx"), "docker.io");
+        assert_eq!(image_registry("localhost/app"), "localhost");
+        assert_eq!(image_registry("localhost:5000/app:v1"), "localhost:5000");
+        assert_eq!(
+            image_registry("Registry.Example.com/team/app@sha256:abc"),
+            "registry.example.com"
+        );
+        assert_eq!(registry_host("https://index.docker.io/v1/"), "docker.io");
+        assert_eq!(
+            registry_host(" registry.example.com/ "),
+            "registry.example.com"
+        );
+    }
+
+    fn config(server: &str) -> Config {
+        Config {
+            server: server.to_string(),
+            ..Config::default()
+        }
+    }
+
+    // a review recorded from a Deployment's ReplicaSet creating a Pod
+    fn recorded_pod() -> Pod {
+        let review: Value =
+            serde_json::from_str(include_str!("../tests/admission/pod-create.json")).unwrap();
+        serde_json::from_value(review["request"]["object"].clone()).unwrap()
+    }
+
+    #[test]
+    fn recorded_review() {
+        let pod = recorded_pod();
+        let configs = [
+            config("registry.example.com"),
+            config("docker.io"),
+            config("quay.io"),
+        ];
+        let named: Vec<(&Config, &str)> = configs.iter().map(|c| (c, c.server.as_str())).collect();
+
+        // the pod already references the docker hub secret
+        let missing = missing_secrets(&pod, &named);
+        assert_eq!(missing, vec!["registry.example.com"]);
+        assert_eq!(
+            json_patch(&pod, &missing),
+            json!([{
+                "op": "add",
+                "path": "/spec/imagePullSecrets/-",
+                "value": { "name": "registry.example.com" },
+            }])
+        );
+
+        let merged: Vec<(&Config, &str)> = configs.iter().map(|c| (c, "regcred")).collect();
+        let missing = missing_secrets(&pod, &merged);
+        assert_eq!(missing, vec!["regcred"]);
+    }
+
+    #[test]
+    fn pod_without_pull_secrets() {
+        let mut pod = recorded_pod();
+        pod.spec.as_mut().unwrap().image_pull_secrets = None;
+        let configs = [config("registry.example.com"), config("docker.io")];
+        let named: Vec<(&Config, &str)> = configs.iter().map(|c| (c, c.server.as_str())).collect();
+
+        let missing = missing_secrets(&pod, &named);
+        assert_eq!(missing, vec!["registry.example.com", "docker.io"]);
+        assert_eq!(
+            json_patch(&pod, &missing),
+            json!([{
+                "op": "add",
+                "path": "/spec/imagePullSecrets",
+                "value": [{ "name": "registry.example.com" }, { "name": "docker.io" }],
+            }])
+        );
+        assert!(missing_secrets(&pod, &[]).is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{api::PostParams, Api, Client};
+
+/// Lease based leader election, only the holder of the
+/// `coordination.k8s.io/v1` Lease reconciles.
+pub struct LeaderElector {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    lease_duration: Duration,
+}
+
+/// The identity of this replica: the pod name, else the hostname.
+pub fn default_identity() -> String {
+    std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| format!("imagepullsecret-sync-{}", std::process::id()))
+}
+
+impl LeaderElector {
+    pub fn new(
+        client: Client,
+        namespace: &str,
+        name: &str,
+        identity: &str,
+        lease_duration: Duration,
+    ) -> Self {
+        LeaderElector {
+            api: Api::namespaced(client, namespace),
+            name: name.to_string(),
+            identity: identity.to_string(),
+            lease_duration,
+        }
+    }
+
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Acquire the lease when it is free or expired, or renew it when it is
+    /// held already. Returns whether this replica holds the lease.
+    pub async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = Utc::now();
+        let lease = match self.api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..ObjectMeta::default()
+                    },
+                    spec: Some(self.spec(now, 0)),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => {
+                        info!("acquired new lease '{}' as '{}'", self.name, self.identity);
+                        Ok(true)
+                    }
+                    // another replica created it first
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let holder = spec.holder_identity.clone().unwrap_or_default();
+        let held = holder == self.identity;
+        if !held && !holder.is_empty() && !is_expired(&spec, now) {
+            debug!("lease '{}' is held by '{}'", self.name, holder);
+            return Ok(false);
+        }
+
+        let new_spec = if held {
+            LeaseSpec {
+                renew_time: Some(MicroTime(now)),
+                lease_duration_seconds: Some(self.lease_duration.num_seconds() as i32),
+                ..spec
+            }
+        } else {
+            self.spec(now, spec.lease_transitions.unwrap_or(0) + 1)
+        };
+
+        // the resourceVersion makes concurrent takeovers conflict
+        let lease = Lease {
+            spec: Some(new_spec),
+            ..lease
+        };
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => {
+                if !held {
+                    info!(
+                        "acquired lease '{}' as '{}' from '{}'",
+                        self.name, self.identity, holder
+                    );
+                }
+                Ok(true)
+            }
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Block until the lease is acquired, trying every `retry_period`.
+    pub async fn acquire(&self, retry_period: std::time::Duration) {
+        info!(
+            "waiting for lease '{}' as '{}' ...",
+            self.name, self.identity
+        );
+        loop {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => warn!("acquire lease '{}' err: {}", self.name, e),
+            }
+            tokio::time::sleep(retry_period).await;
+        }
+    }
+
+    /// Renew the lease every `retry_period`, returns an error once the lease
+    /// is lost or could not be renewed within `renew_deadline`.
+    pub async fn keep(
+        &self,
+        retry_period: std::time::Duration,
+        renew_deadline: Duration,
+    ) -> Result<()> {
+        let mut renewed = Utc::now();
+        loop {
+            tokio::time::sleep(retry_period).await;
+            match self.try_acquire_or_renew().await {
+                Ok(true) => renewed = Utc::now(),
+                Ok(false) => return Err(anyhow!("lease '{}' taken by another replica", self.name)),
+                Err(e) => warn!("renew lease '{}' err: {}", self.name, e),
+            }
+            if Utc::now() - renewed > renew_deadline {
+                return Err(anyhow!(
+                    "lease '{}' not renewed within {}s",
+                    self.name,
+                    renew_deadline.num_seconds()
+                ));
+            }
+        }
+    }
+
+    /// Give up the lease so another replica takes over without waiting for
+    /// it to expire.
+    pub async fn release(&self) -> Result<()> {
+        let lease = self.api.get(&self.name).await?;
+        let spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+
+        let lease = Lease {
+            spec: Some(LeaseSpec {
+                holder_identity: None,
+                lease_duration_seconds: Some(1),
+                renew_time: Some(MicroTime(Utc::now())),
+                ..spec
+            }),
+            ..lease
+        };
+        self.api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await?;
+        info!("released lease '{}'", self.name);
+        Ok(())
+    }
+
+    // a freshly acquired lease
+    fn spec(&self, now: DateTime<Utc>, transitions: i32) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(self.identity.clone()),
+            lease_duration_seconds: Some(self.lease_duration.num_seconds() as i32),
+            acquire_time: Some(MicroTime(now)),
+            renew_time: Some(MicroTime(now)),
+            lease_transitions: Some(transitions),
+        }
+    }
+}
+
+fn is_expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    match &spec.renew_time {
+        Some(renewed) => {
+            let duration = Duration::seconds(spec.lease_duration_seconds.unwrap_or(0) as i64);
+            renewed.0 + duration < now
+        }
+        None => true,
+    }
+}
@@ -1 +1,26 @@
+#[macro_use]
+extern crate log;
+
+pub mod audit;
+pub mod cluster;
 pub mod config;
+pub mod crd;
+pub mod credentials;
+pub mod error;
+pub mod events;
+pub mod inject;
+pub mod leader;
+pub mod metrics;
+pub mod naming;
+pub mod plan;
+pub mod queue;
+pub mod rbac;
+pub mod selector;
+pub mod settings;
+pub mod status;
+pub mod supervisor;
+pub mod token;
+pub mod validate;
+pub mod verify;
+pub mod webhook;
+pub mod worker;
@@ -1,18 +1,45 @@
 #[macro_use]
 extern crate log;
 
-mod worker;
-
+use anyhow::Context;
+use imagepullsecret_sync::{
+    audit::{self, AuditLog},
+    cluster,
+    inject::Injector,
+    leader::{self, LeaderElector},
+    metrics::{
+        self, SERVE_WEBHOOK, WATCH_CONFIG, WATCH_CREDENTIALS, WATCH_NS, WATCH_QUEUE,
+        WATCH_REGISTRY_CREDENTIALS, WATCH_RESYNC, WATCH_SA, WATCH_SECRETS, WATCH_STATUS,
+        WATCH_TOKENS,
+    },
+    plan, rbac,
+    settings::{Command, Opts, Settings},
+    supervisor::{Backoff, Supervisor},
+    validate, webhook, worker,
+};
 use kube::Client;
 
 use chrono::Local;
-use std::{io::Write, sync::Arc};
+use futures::future::select_all;
+use std::{io::Write, sync::Arc, time::Duration};
+use structopt::StructOpt;
 use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    std::env::set_var("RUST_LOG", "info,kube=debug");
-    env_logger::Builder::from_env(env_logger::Env::default())
+    let mut opts = Opts::from_args();
+    let cmd = opts.cmd.take();
+    let settings = Settings::load(opts)?;
+    if let Some(Command::Validate(args)) = &cmd {
+        std::process::exit(validate::run(args, &settings));
+    }
+    if let Some(Command::Rbac(args)) = &cmd {
+        print!("{}", rbac::render(&settings, args)?);
+        return Ok(());
+    }
+
+    env_logger::Builder::new()
+        .parse_filters(&settings.log_level)
         .format(|buf, record| {
             let level = { buf.default_styled_level(record.level()) };
             writeln!(
@@ -27,31 +54,218 @@ async fn main() -> anyhow::Result<()> {
         })
         .init();
 
+    if let Some(Command::Plan(args)) = cmd {
+        let client = Client::try_default().await?;
+        let code = plan::run(client, settings, &args).await?;
+        std::process::exit(code);
+    }
+
+    info!("starting with {:?}", settings);
+
+    let metrics_addr = settings.metrics_addr.parse()?;
     let client = Client::try_default().await?;
 
-    let worker = Arc::new(worker::SyncWorker::new(
-        client,
-        "default",
-        "docker-registry",
-    ));
+    // followers answer admission reviews too, the webhook service spans all replicas
+    let mut webhook_server = None;
+    if let Some(addr) = &settings.webhook_addr {
+        let acceptor =
+            webhook::ReloadingAcceptor::new(&settings.webhook_cert, &settings.webhook_key)?;
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("bind webhook server to {}", addr))?;
+        let mut webhook = webhook::Webhook::new(settings.clone());
+        if settings.inject_pull_secrets {
+            webhook = webhook.with_injector(Injector::new(client.clone(), settings.clone())?);
+        }
+        webhook_server = Some((listener, acceptor, webhook));
+    }
+
+    let elector = if settings.leader_election {
+        Some(Arc::new(LeaderElector::new(
+            client.clone(),
+            &settings.lease_namespace,
+            &settings.lease_name,
+            &leader::default_identity(),
+            chrono::Duration::seconds(settings.lease_duration as i64),
+        )))
+    } else {
+        None
+    };
+    let retry_period = Duration::from_secs(settings.lease_retry_period);
+    // like client-go, give up 2/3 into the lease so the next leader never overlaps
+    let renew_deadline = chrono::Duration::seconds(settings.lease_duration as i64 * 2 / 3);
+
+    let poll_credentials = settings.credentials_poll_interval > 0;
+    let resync = settings.resync_interval > 0;
+    let registry_credentials = settings.registry_credentials;
+    let clusters = settings.clusters.clone();
+    let kubeconfig = settings.kubeconfig.clone();
+    let audit_log = settings.audit_log.clone();
+    if let Some(path) = &settings.audit_key_file {
+        audit::load_key(path)?;
+    }
+    let mut worker = worker::SyncWorker::new(client, settings)?;
+    if let Some(target) = &audit_log {
+        worker = worker.with_audit(Arc::new(AuditLog::open(target)?));
+    }
+    let mut remotes = Vec::new();
+    for context in clusters.iter() {
+        let client = cluster::client(context, kubeconfig.as_deref()).await?;
+        remotes.push(worker.remote(context, client));
+    }
+    let worker = Arc::new(worker.with_remotes(remotes));
+
+    // the servers run on followers too, their errors end the process like
+    // the fatal ones of the watchers
+    let mut servers = Vec::new();
+
+    if let Some((listener, acceptor, webhook)) = webhook_server {
+        // a dead webhook fails the probes, it rejects the config changes
+        let metrics = worker.metrics();
+        metrics.health.require_server(SERVE_WEBHOOK);
+        servers.push(tokio::spawn(async move {
+            let _alive = metrics.health.alive(SERVE_WEBHOOK);
+            metrics.health.synced(SERVE_WEBHOOK);
+            webhook::serve(listener, acceptor, webhook)
+                .await
+                .context("webhook server")
+        }));
+    }
 
-    let watch_ns = worker.clone();
-    let watch_cfg = worker.clone();
+    let worker_metrics = worker.metrics();
+    servers.push(tokio::spawn(async move {
+        metrics::serve(metrics_addr, worker_metrics)
+            .await
+            .context("metrics server")
+    }));
 
-    tokio::spawn(async move {
-        if let Err(e) = watch_ns.watch_ns().await {
-            panic!("sync worker watch ns err: {}", e);
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    if let Some(elector) = &elector {
+        worker.metrics().set_leader(false);
+        tokio::select! {
+            _ = elector.acquire(retry_period) => {}
+            _ = terminate.recv() => {
+                info!("recv SIGTERM while waiting for the lease, shutdown...");
+                return Ok(());
+            }
+            (joined, _, _) = select_all(servers.iter_mut()) => return ended(joined),
         }
-    });
-    tokio::spawn(async move {
-        if let Err(e) = watch_cfg.watch_cfg_secret().await {
-            panic!("sync worker watch config secret err: {}", e);
+        info!("leading as '{}'", elector.identity());
+        worker.metrics().set_leader(true);
+
+        let keeper = elector.clone();
+        tokio::spawn(async move {
+            if let Err(e) = keeper.keep(retry_period, renew_deadline).await {
+                // the watchers can't be stopped safely mid reconcile, exit and
+                // let kubernetes restart the replica as a follower
+                error!("lost leadership: {}", e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    let supervisor = Arc::new(Supervisor::new(worker.metrics(), Backoff::default()));
+    let mut tasks = servers;
+
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_QUEUE, || w.process_queue()).await
+    }));
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_NS, || w.watch_ns()).await
+    }));
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_SA, || w.watch_sa()).await
+    }));
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_SECRETS, || w.watch_secrets()).await
+    }));
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_CONFIG, || w.watch_cfg_secret()).await
+    }));
+    if registry_credentials {
+        let (sup, w) = (supervisor.clone(), worker.clone());
+        tasks.push(tokio::spawn(async move {
+            sup.run(WATCH_REGISTRY_CREDENTIALS, || {
+                w.watch_registry_credentials()
+            })
+            .await
+        }));
+    }
+    if resync {
+        let (sup, w) = (supervisor.clone(), worker.clone());
+        tasks.push(tokio::spawn(async move {
+            sup.run(WATCH_RESYNC, || w.resync()).await
+        }));
+    }
+    if poll_credentials {
+        let (sup, w) = (supervisor.clone(), worker.clone());
+        tasks.push(tokio::spawn(async move {
+            sup.run(WATCH_CREDENTIALS, || w.watch_credentials()).await
+        }));
+    }
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_TOKENS, || w.watch_tokens()).await
+    }));
+    let (sup, w) = (supervisor.clone(), worker.clone());
+    tasks.push(tokio::spawn(async move {
+        sup.run(WATCH_STATUS, || w.report_status()).await
+    }));
+
+    // the configs are fanned out by the worker above, each cluster only
+    // watches its own objects and never ends the process
+    for (context, remote) in clusters.iter().zip(worker.remotes()) {
+        let supervisor =
+            Arc::new(Supervisor::new(remote.metrics(), Backoff::default()).for_cluster(context));
+        let remote = Arc::new(remote.clone());
+        for task in [WATCH_QUEUE, WATCH_NS, WATCH_SA, WATCH_SECRETS]
+            .iter()
+            .copied()
+        {
+            let (sup, w) = (supervisor.clone(), remote.clone());
+            tasks.push(tokio::spawn(async move {
+                sup.run(task, || async {
+                    match task {
+                        WATCH_QUEUE => w.process_queue().await,
+                        WATCH_NS => w.watch_ns().await,
+                        WATCH_SA => w.watch_sa().await,
+                        _ => w.watch_secrets().await,
+                    }
+                })
+                .await
+            }));
         }
-    });
+    }
 
-    signal(SignalKind::terminate())?.recv().await;
+    // transient errors are retried by the supervisor, only fatal ones end up here
+    let result = tokio::select! {
+        _ = terminate.recv() => {
+            info!("recv SIGTERM, graceful shutdown...");
+            Ok(())
+        }
+        (joined, _, _) = select_all(tasks) => ended(joined),
+    };
+
+    if let Some(elector) = elector {
+        if let Err(e) = elector.release().await {
+            warn!("release lease err: {}", e);
+        }
+    }
 
-    info!("recv SIGTERM, graceful shutdown...");
+    result
+}
 
-    Ok(())
+// the outcome of the first task or server which ended
+fn ended(joined: Result<anyhow::Result<()>, tokio::task::JoinError>) -> anyhow::Result<()> {
+    match joined {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(e.into()),
+    }
 }
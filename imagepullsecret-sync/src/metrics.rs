@@ -0,0 +1,477 @@
+use anyhow::{Context, Result};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    Encoder, Gauge, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    sync::{Arc, Mutex},
+};
+
+/// The watchers `/healthz` and `/readyz` report on.
+pub const WATCH_NS: &str = "namespaces";
+pub const WATCH_CONFIG: &str = "config";
+/// Supervised tasks which are reported but don't affect the probes.
+pub const WATCH_SA: &str = "service_accounts";
+pub const WATCH_CREDENTIALS: &str = "credentials";
+pub const WATCH_TOKENS: &str = "tokens";
+pub const WATCH_QUEUE: &str = "queue";
+pub const WATCH_SECRETS: &str = "secrets";
+pub const WATCH_RESYNC: &str = "resync";
+pub const WATCH_STATUS: &str = "status";
+pub const WATCH_REGISTRY_CREDENTIALS: &str = "registry_credentials";
+/// The admission webhook server, it affects the probes of followers too.
+pub const SERVE_WEBHOOK: &str = "webhook";
+
+/// Prometheus metrics of the sync worker, kept in an own registry.
+pub struct Metrics {
+    registry: Registry,
+    secrets: IntCounterVec,
+    reconcile_duration: Histogram,
+    watch_restarts: IntCounterVec,
+    last_success: Gauge,
+    leader: IntGauge,
+    pub health: Health,
+    // the metrics of the --clusters workers, served along with these
+    clusters: Mutex<Vec<(String, Arc<Metrics>)>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::with_registry(Registry::new(), true)
+    }
+
+    /// The metrics of the worker syncing the kubeconfig context `cluster`,
+    /// labeled with `cluster="<context>"`. Their watchers don't affect the
+    /// probes, one unreachable cluster must not restart the pod.
+    pub fn for_cluster(cluster: &str) -> Self {
+        let labels: HashMap<String, String> =
+            std::iter::once(("cluster".to_string(), cluster.to_string())).collect();
+        let registry = Registry::new_custom(None, Some(labels)).unwrap();
+        let mut metrics = Metrics::with_registry(registry, false);
+        metrics.health = Health::new(&[]);
+        metrics
+    }
+
+    // the leader gauge is only registered once, the lease is the same for all clusters
+    fn with_registry(registry: Registry, leader_gauge: bool) -> Self {
+        let secrets = IntCounterVec::new(
+            Opts::new(
+                "imagepullsecret_sync_secrets_total",
+                "Registry secrets created, patched or failed to sync",
+            ),
+            &["namespace", "action"],
+        )
+        .unwrap();
+        let reconcile_duration = Histogram::with_opts(HistogramOpts::new(
+            "imagepullsecret_sync_reconcile_duration_seconds",
+            "Duration of syncing one namespace",
+        ))
+        .unwrap();
+        let watch_restarts = IntCounterVec::new(
+            Opts::new(
+                "imagepullsecret_sync_watch_restarts_total",
+                "Restarts of the watch streams",
+            ),
+            &["watcher"],
+        )
+        .unwrap();
+        let last_success = Gauge::new(
+            "imagepullsecret_sync_last_success_timestamp_seconds",
+            "Unix time of the last sync of all namespaces without errors",
+        )
+        .unwrap();
+        let leader = IntGauge::new(
+            "imagepullsecret_sync_leader",
+            "1 while this replica holds the leader election lease",
+        )
+        .unwrap();
+
+        registry.register(Box::new(secrets.clone())).unwrap();
+        registry
+            .register(Box::new(reconcile_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(watch_restarts.clone())).unwrap();
+        registry.register(Box::new(last_success.clone())).unwrap();
+        if leader_gauge {
+            registry.register(Box::new(leader.clone())).unwrap();
+        }
+
+        Metrics {
+            registry,
+            secrets,
+            reconcile_duration,
+            watch_restarts,
+            last_success,
+            leader,
+            health: Health::new(&[WATCH_NS, WATCH_CONFIG]),
+            clusters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Serve the metrics of the worker of `cluster` along with these.
+    pub fn add_cluster(&self, cluster: &str, metrics: Arc<Metrics>) {
+        self.clusters
+            .lock()
+            .unwrap()
+            .push((cluster.to_string(), metrics));
+    }
+
+    /// Count a secret `action` in `ns`: created, patched or failed.
+    pub fn secret(&self, ns: &str, action: &str) {
+        self.secrets.with_label_values(&[ns, action]).inc();
+    }
+
+    pub fn secret_count(&self, ns: &str, action: &str) -> u64 {
+        self.secrets.with_label_values(&[ns, action]).get()
+    }
+
+    pub fn observe_reconcile(&self, seconds: f64) {
+        self.reconcile_duration.observe(seconds);
+    }
+
+    pub fn watch_restarted(&self, watcher: &str) {
+        self.watch_restarts.with_label_values(&[watcher]).inc();
+    }
+
+    pub fn synced_all(&self) {
+        self.last_success.set(chrono::Utc::now().timestamp() as f64);
+    }
+
+    /// Replicas waiting for the lease run no watchers, they are reported
+    /// healthy and ready as standby.
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.set(leader as i64);
+        self.health.standby.store(!leader, Ordering::SeqCst);
+    }
+
+    /// The metrics in the prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut families = self.registry.gather();
+        // a metric family may only appear once, the ones of the clusters
+        // differ by their label
+        for (_, metrics) in self.clusters.lock().unwrap().iter() {
+            for mut family in metrics.registry.gather() {
+                match families
+                    .iter_mut()
+                    .find(|f| f.get_name() == family.get_name())
+                {
+                    Some(f) => f.mut_metric().extend(family.take_metric()),
+                    None => families.push(family),
+                }
+            }
+        }
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&families, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WatchState {
+    // the watch stream is being consumed
+    pub alive: bool,
+    // the initial list of the stream was received
+    pub synced: bool,
+    // restarts by the supervisor
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+/// Liveness and readiness of the watch streams.
+pub struct Health {
+    watchers: Mutex<BTreeMap<&'static str, WatchState>>,
+    // the watchers the probes depend on
+    critical: Vec<&'static str>,
+    // the servers the probes depend on, on followers too
+    servers: Mutex<Vec<&'static str>>,
+    standby: AtomicBool,
+}
+
+impl Health {
+    pub fn new(critical: &[&'static str]) -> Self {
+        let watchers = critical
+            .iter()
+            .map(|w| (*w, WatchState::default()))
+            .collect();
+        Health {
+            watchers: Mutex::new(watchers),
+            critical: critical.to_vec(),
+            servers: Mutex::new(Vec::new()),
+            standby: AtomicBool::new(false),
+        }
+    }
+
+    /// Make the probes depend on `server`, which runs on every replica.
+    pub fn require_server(&self, server: &'static str) {
+        self.update(server, |_| {});
+        self.servers.lock().unwrap().push(server);
+    }
+
+    /// Mark `watcher` alive until the returned guard is dropped.
+    pub fn alive(&self, watcher: &'static str) -> AliveGuard<'_> {
+        self.update(watcher, |s| s.alive = true);
+        AliveGuard {
+            health: self,
+            watcher,
+        }
+    }
+
+    pub fn synced(&self, watcher: &'static str) {
+        self.update(watcher, |s| s.synced = true);
+    }
+
+    /// Record that `watcher` failed with `err` and is restarted.
+    pub fn restarted(&self, watcher: &'static str, err: String) {
+        self.update(watcher, |s| {
+            s.restarts += 1;
+            s.last_error = Some(err);
+        });
+    }
+
+    fn update(&self, watcher: &'static str, f: impl FnOnce(&mut WatchState)) {
+        f(self.watchers.lock().unwrap().entry(watcher).or_default());
+    }
+
+    pub fn states(&self) -> BTreeMap<&'static str, WatchState> {
+        self.watchers.lock().unwrap().clone()
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    // whether `f` holds for the required servers, and for the critical
+    // watchers unless on standby
+    fn critical_all(&self, f: impl Fn(&WatchState) -> bool) -> bool {
+        let states = self.states();
+        let ok = |w: &&'static str| states.get(w).is_some_and(&f);
+        self.servers.lock().unwrap().iter().all(ok)
+            && (self.is_standby() || self.critical.iter().all(ok))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.critical_all(|s| s.alive)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.critical_all(|s| s.alive && s.synced)
+    }
+}
+
+pub struct AliveGuard<'a> {
+    health: &'a Health,
+    watcher: &'static str,
+}
+
+impl Drop for AliveGuard<'_> {
+    fn drop(&mut self) {
+        // a restarted stream has to list again before it is ready
+        self.health.update(self.watcher, |s| {
+            s.alive = false;
+            s.synced = false;
+        });
+    }
+}
+
+/// Serve `/metrics`, `/healthz` and `/readyz` on `addr`.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let resp = handle(&metrics, req);
+                async move { Ok::<_, Infallible>(resp) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&addr)
+        .with_context(|| format!("bind metrics server to {}", addr))?
+        .serve(make_svc);
+    info!("serving metrics and health on {} ...", server.local_addr());
+    server.await?;
+
+    Ok(())
+}
+
+fn handle(metrics: &Metrics, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+    }
+
+    match req.uri().path() {
+        "/metrics" => Response::builder()
+            .header("Content-Type", TextEncoder::new().format_type())
+            .body(Body::from(metrics.encode()))
+            .unwrap(),
+        "/healthz" => probe(metrics, metrics.health.is_healthy()),
+        "/readyz" => probe(metrics, metrics.health.is_ready()),
+        _ => respond(StatusCode::NOT_FOUND, "not found\n"),
+    }
+}
+
+// one line per watcher, e.g. "namespaces: alive, synced, 1 restarts (last error: ..)"
+fn probe(metrics: &Metrics, ok: bool) -> Response<Body> {
+    let mut body = String::new();
+    if metrics.health.is_standby() {
+        body.push_str("standby: waiting for the leader election lease\n");
+    }
+    for (watcher, state) in metrics.health.states() {
+        body.push_str(&format!(
+            "{}: {}, {}, {} restarts",
+            watcher,
+            if state.alive { "alive" } else { "dead" },
+            if state.synced { "synced" } else { "not synced" },
+            state.restarts,
+        ));
+        if let Some(e) = &state.last_error {
+            body.push_str(&format!(" (last error: {})", e));
+        }
+        body.push('\n');
+    }
+    // reported for the --clusters, they don't affect the probes
+    for (cluster, cluster_metrics) in metrics.clusters.lock().unwrap().iter() {
+        for (watcher, state) in cluster_metrics.health.states() {
+            body.push_str(&format!(
+                "{}/{}: {}, {} restarts\n",
+                cluster,
+                watcher,
+                if state.alive { "alive" } else { "dead" },
+                state.restarts,
+            ));
+        }
+    }
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    respond(status, body)
+}
+
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Health, Metrics, SERVE_WEBHOOK, WATCH_CONFIG, WATCH_NS, WATCH_SA};
+    use std::sync::Arc;
+
+    #[test]
+    fn health() {
+        let health = Health::new(&[WATCH_NS, WATCH_CONFIG]);
+        assert!(!health.is_healthy());
+
+        let ns = health.alive(WATCH_NS);
+        let cfg = health.alive(WATCH_CONFIG);
+        assert!(health.is_healthy());
+        assert!(!health.is_ready());
+
+        health.synced(WATCH_NS);
+        health.synced(WATCH_CONFIG);
+        assert!(health.is_ready());
+
+        // other tasks are reported but don't affect the probes
+        drop(health.alive(WATCH_SA));
+        assert!(health.is_ready());
+
+        drop(cfg);
+        health.restarted(WATCH_CONFIG, "boom".to_string());
+        assert!(!health.is_healthy());
+        assert!(!health.is_ready());
+        let state = health.states()[WATCH_CONFIG].clone();
+        assert_eq!(
+            (state.restarts, state.last_error),
+            (1, Some("boom".to_string()))
+        );
+        drop(ns);
+    }
+
+    #[test]
+    fn webhook_server() {
+        let metrics = Metrics::new();
+        metrics.health.require_server(SERVE_WEBHOOK);
+        metrics.set_leader(false);
+        assert!(!metrics.health.is_healthy());
+
+        let webhook = metrics.health.alive(SERVE_WEBHOOK);
+        metrics.health.synced(SERVE_WEBHOOK);
+        assert!(metrics.health.is_ready());
+
+        // a dead webhook fails the probes of a follower too
+        drop(webhook);
+        assert!(!metrics.health.is_healthy());
+        assert!(!metrics.health.is_ready());
+    }
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::new();
+        metrics.secret("team-a", "created");
+        metrics.watch_restarted(WATCH_NS);
+        metrics.observe_reconcile(0.5);
+
+        metrics.set_leader(false);
+        assert!(metrics.health.is_ready());
+        metrics.set_leader(true);
+        assert!(!metrics.health.is_healthy());
+
+        let text = metrics.encode();
+        assert!(text.contains(
+            r#"imagepullsecret_sync_secrets_total{action="created",namespace="team-a"} 1"#
+        ));
+        assert!(
+            text.contains(r#"imagepullsecret_sync_watch_restarts_total{watcher="namespaces"} 1"#)
+        );
+        assert!(text.contains("imagepullsecret_sync_reconcile_duration_seconds_count 1"));
+    }
+
+    #[test]
+    fn clusters() {
+        let metrics = Metrics::new();
+        let edge = Arc::new(Metrics::for_cluster("edge"));
+        metrics.add_cluster("edge", edge.clone());
+        metrics.secret("team-a", "created");
+        edge.secret("team-a", "created");
+        edge.secret("team-a", "created");
+
+        // unhealthy cluster watchers don't fail the probes
+        edge.health.restarted(WATCH_NS, "unreachable".to_string());
+        let ns = metrics.health.alive(WATCH_NS);
+        let _cfg = metrics.health.alive(WATCH_CONFIG);
+        assert!(metrics.health.is_healthy());
+        assert!(edge.health.is_healthy());
+        drop(ns);
+
+        let text = metrics.encode();
+        assert_eq!(
+            text.matches("# TYPE imagepullsecret_sync_secrets_total")
+                .count(),
+            1
+        );
+        assert!(text.contains(
+            r#"imagepullsecret_sync_secrets_total{action="created",namespace="team-a"} 1"#
+        ));
+        assert!(text.contains(
+            r#"imagepullsecret_sync_secrets_total{action="created",namespace="team-a",cluster="edge"} 2"#
+        ));
+        assert!(!text.contains(r#"imagepullsecret_sync_leader{cluster="edge"}"#));
+    }
+}
@@ -0,0 +1,240 @@
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+
+// the longest object name
+const MAX_NAME_LEN: usize = 253;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    // the server without the scheme and trailing slashes
+    Server,
+    Host,
+    Port,
+    Path,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// The name of the secret of a registry, e.g. `regcred-{{host}}`. The
+/// placeholders are `{{server}}`, `{{host}}`, `{{port}}` and `{{path}}` of
+/// the config's server, the rendered name is sanitized to a DNS-1123
+/// subdomain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl NameTemplate {
+    pub fn parse(template: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or_else(|| anyhow!("unclosed '{{{{' in secret name template '{}'", template))?;
+            let field = match rest[start + 2..start + end].trim() {
+                "server" => Field::Server,
+                "host" => Field::Host,
+                "port" => Field::Port,
+                "path" => Field::Path,
+                other => {
+                    return Err(anyhow!(
+                        "unknown placeholder '{{{{{}}}}}' in secret name template '{}'",
+                        other,
+                        template
+                    ))
+                }
+            };
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        // the registries would all share one secret, which is what
+        // --merge-secret-name is for
+        if !parts.iter().any(|p| matches!(p, Part::Field(_))) {
+            return Err(anyhow!(
+                "secret name template '{}' has no placeholder",
+                template
+            ));
+        }
+        Ok(NameTemplate { parts })
+    }
+
+    /// The secret name of the registry `server`, empty when nothing of it is
+    /// left after the sanitization.
+    pub fn render(&self, server: &str) -> String {
+        let (host, port, path) = split_server(server);
+        let mut name = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => name.push_str(s),
+                Part::Field(Field::Server) => {
+                    name.push_str(host);
+                    if !port.is_empty() {
+                        name.push(':');
+                        name.push_str(port);
+                    }
+                    if !path.is_empty() {
+                        name.push('/');
+                        name.push_str(path);
+                    }
+                }
+                Part::Field(Field::Host) => name.push_str(host),
+                Part::Field(Field::Port) => name.push_str(port),
+                Part::Field(Field::Path) => name.push_str(path),
+            }
+        }
+        sanitize(&name)
+    }
+
+    /// Drop the configs whose secret name is empty or already taken by an
+    /// earlier config, they are returned as (server, reason).
+    pub fn dedup(&self, configs: Vec<Config>) -> (Vec<Config>, Vec<(String, String)>) {
+        let mut owners: BTreeMap<String, String> = BTreeMap::new();
+        let mut kept = Vec::new();
+        let mut collided = Vec::new();
+        for cfg in configs.into_iter() {
+            let name = self.render(&cfg.server);
+            if name.is_empty() {
+                let reason = "secret name template renders an empty name".to_string();
+                collided.push((cfg.server, reason));
+            } else if let Some(owner) = owners.get(&name) {
+                let reason = format!(
+                    "secret name '{}' is already used by registry '{}'",
+                    name, owner
+                );
+                collided.push((cfg.server, reason));
+            } else {
+                owners.insert(name, cfg.server.clone());
+                kept.push(cfg);
+            }
+        }
+        (kept, collided)
+    }
+}
+
+// (host, port, path) of a server like "https://registry.local:5000/team/"
+fn split_server(server: &str) -> (&str, &str, &str) {
+    let s = server.trim();
+    let s = s
+        .strip_prefix("https://")
+        .or_else(|| s.strip_prefix("http://"))
+        .unwrap_or(s);
+    let (authority, path) = s.split_once('/').unwrap_or((s, ""));
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => (host, port),
+        _ => (authority, ""),
+    };
+    (host, port, path.trim_matches('/'))
+}
+
+/// `name` as a DNS-1123 subdomain: lower case, other characters replaced by
+/// '-' and the labels trimmed. Valid names are returned unchanged.
+pub fn sanitize(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' | '.' | '-' => c,
+            _ => '-',
+        })
+        .collect();
+    let labels: Vec<&str> = name
+        .split('.')
+        .map(|label| label.trim_matches('-'))
+        .filter(|label| !label.is_empty())
+        .collect();
+    let mut name = labels.join(".");
+    if name.len() > MAX_NAME_LEN {
+        name.truncate(MAX_NAME_LEN);
+        name = name.trim_end_matches(['-', '.']).to_string();
+    }
+    name
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sanitize, NameTemplate};
+    use crate::{config::Config, settings::is_dns1123_subdomain};
+
+    #[test]
+    fn render() {
+        let t = NameTemplate::parse("{{server}}").unwrap();
+        assert_eq!(t.render("registry.example.com"), "registry.example.com");
+        assert_eq!(
+            t.render("registry.local:5000/team"),
+            "registry.local-5000-team"
+        );
+        assert_eq!(
+            t.render("https://index.docker.io/v1/"),
+            "index.docker.io-v1"
+        );
+        assert_eq!(t.render("Quay.IO"), "quay.io");
+
+        let t = NameTemplate::parse("regcred-{{ host }}{{port}}").unwrap();
+        assert_eq!(
+            t.render("http://registry.local:5000/team"),
+            "regcred-registry.local5000"
+        );
+        let t = NameTemplate::parse("{{host}}-{{path}}").unwrap();
+        assert_eq!(t.render("ghcr.io/acme/"), "ghcr.io-acme");
+        assert_eq!(t.render("ghcr.io"), "ghcr.io");
+
+        assert!(NameTemplate::parse("regcred").is_err());
+        assert!(NameTemplate::parse("regcred-{{registry}}").is_err());
+        assert!(NameTemplate::parse("regcred-{{host").is_err());
+    }
+
+    #[test]
+    fn sanitized_names_are_valid() {
+        for name in [
+            "registry.example.com",
+            "-a..b-",
+            "A_B/C:D",
+            "ümlaut.io",
+            &"x.".repeat(200),
+        ]
+        .iter()
+        {
+            let s = sanitize(name);
+            assert!(is_dns1123_subdomain(&s), "{:?} -> {:?}", name, s);
+            assert_eq!(sanitize(&s), s);
+        }
+        assert_eq!(sanitize("a--b.c"), "a--b.c");
+        assert_eq!(sanitize("://"), "");
+    }
+
+    #[test]
+    fn collisions() {
+        let config = |server: &str| Config {
+            server: server.to_string(),
+            ..Config::default()
+        };
+        let t = NameTemplate::parse("regcred-{{host}}").unwrap();
+        let (kept, collided) = t.dedup(vec![
+            config("registry.local:5000"),
+            config("registry.local:5001"),
+            config("quay.io"),
+        ]);
+        let servers: Vec<&str> = kept.iter().map(|c| c.server.as_str()).collect();
+        assert_eq!(servers, vec!["registry.local:5000", "quay.io"]);
+        assert_eq!(
+            collided,
+            vec![(
+                "registry.local:5001".to_string(),
+                "secret name 'regcred-registry.local' is already used by registry 'registry.local:5000'"
+                    .to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    cluster,
+    config::Config,
+    settings::Settings,
+    validate,
+    worker::{NamespaceReport, SyncWorker},
+};
+use anyhow::{anyhow, Context, Result};
+use kube::Client;
+use serde::Serialize;
+use std::{fmt, fs, path::PathBuf, str::FromStr};
+use structopt::StructOpt;
+
+// exit codes of `plan --exit-code`, like `terraform plan -detailed-exitcode`
+pub const EXIT_ERRORS: i32 = 1;
+pub const EXIT_CHANGES: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Create,
+    Patch,
+    Delete,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            Action::Create => "create",
+            Action::Patch => "patch",
+            Action::Delete => "delete",
+        };
+        f.write_str(action)
+    }
+}
+
+/// One change the sync worker made, or would make in dry-run mode.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub action: Action,
+    // secret or serviceaccount
+    pub kind: &'static str,
+    pub namespace: String,
+    pub name: String,
+    // the drifted fields of a patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.action {
+            Action::Create => '+',
+            Action::Patch => '~',
+            Action::Delete => '-',
+        };
+        write!(f, "{} {} {}/{}", sign, self.kind, self.namespace, self.name)?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanError {
+    pub namespace: String,
+    pub error: String,
+}
+
+/// A registry the plan leaves as it is.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanSkip {
+    pub server: String,
+    pub reason: String,
+}
+
+/// The changes needed to bring the clusters in sync with the configs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+    pub errors: Vec<PlanError>,
+    pub skipped: Vec<PlanSkip>,
+}
+
+impl Plan {
+    pub fn from_reports(reports: Vec<NamespaceReport>) -> Self {
+        let mut plan = Plan::default();
+        plan.add_reports(None, reports);
+        plan
+    }
+
+    // the namespaces of a --clusters context are prefixed with it
+    fn add_reports(&mut self, cluster: Option<&str>, reports: Vec<NamespaceReport>) {
+        let label = |ns: &str| match cluster {
+            Some(cluster) => format!("{}/{}", cluster, ns),
+            None => ns.to_string(),
+        };
+        for report in reports {
+            let namespace = label(&report.namespace);
+            self.changes
+                .extend(report.changes.into_iter().map(|change| Change {
+                    namespace: label(&change.namespace),
+                    ..change
+                }));
+            self.errors.extend(report.errors.iter().map(|e| PlanError {
+                namespace: namespace.clone(),
+                error: e.to_string(),
+            }));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn count(&self, action: Action) -> usize {
+        self.changes.iter().filter(|c| c.action == action).count()
+    }
+
+    pub fn render_text(&self) -> String {
+        let mut out = String::new();
+        for change in self.changes.iter() {
+            out.push_str(&format!("{}\n", change));
+        }
+        for e in self.errors.iter() {
+            out.push_str(&format!("! ns {}: {}\n", e.namespace, e.error));
+        }
+        for skip in self.skipped.iter() {
+            out.push_str(&format!("? registry {}: {}\n", skip.server, skip.reason));
+        }
+        if self.is_empty() && self.errors.is_empty() {
+            out.push_str("No changes, all namespaces are in sync.\n");
+        } else {
+            out.push_str(&format!(
+                "Plan: {} to create, {} to patch, {} to delete, {} errors.\n",
+                self.count(Action::Create),
+                self.count(Action::Patch),
+                self.count(Action::Delete),
+                self.errors.len()
+            ));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            s => Err(anyhow!("unknown output format '{}', use text or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct PlanArgs {
+    /// Read the registry configs from this YAML file instead of the config secret
+    #[structopt(long, parse(from_os_str))]
+    pub config_file: Option<PathBuf>,
+
+    /// Output format, text or json
+    #[structopt(short, long, default_value = "text")]
+    pub output: OutputFormat,
+
+    /// Exit with 2 when there are changes, 1 on errors
+    #[structopt(long)]
+    pub exit_code: bool,
+}
+
+/// Compute the plan against the cluster and the --clusters without changing
+/// anything.
+pub async fn plan(
+    client: Client,
+    settings: Settings,
+    config_file: Option<&PathBuf>,
+) -> Result<Plan> {
+    let configs = match config_file {
+        Some(path) => Some(read_config_file(path, &settings)?),
+        None => None,
+    };
+    let worker = SyncWorker::new(client, settings.clone())?;
+    let mut remotes = Vec::new();
+    for context in settings.clusters.iter() {
+        let client = cluster::client(context, settings.kubeconfig.as_deref()).await?;
+        remotes.push(worker.remote(context, client));
+    }
+    let worker = worker.with_remotes(remotes);
+    let configs = match configs {
+        Some(configs) => configs,
+        None => worker.read_config().await.context("read config secret")?,
+    };
+    plan_configs(&worker, configs).await
+}
+
+/// The plan of `configs` for the cluster of `worker` and of its remotes, in
+/// a dry run. Registries whose credentials would run a command or send a
+/// request are skipped, as is the verification of the credentials.
+pub async fn plan_configs(worker: &SyncWorker, configs: Vec<Config>) -> Result<Plan> {
+    let worker = worker.clone().with_dry_run(true);
+    let mut plan = Plan {
+        skipped: configs
+            .iter()
+            .filter_map(|cfg| {
+                let source = cfg.credentials.as_ref().filter(|c| !c.is_read_only())?;
+                Some(PlanSkip {
+                    server: cfg.server.clone(),
+                    reason: format!("{} credentials are not resolved by a plan", source.kind()),
+                })
+            })
+            .collect(),
+        ..Plan::default()
+    };
+
+    let all_ns = worker.get_all_ns().await?;
+    plan.add_reports(None, worker.ensure(all_ns, configs.clone()).await);
+    // one cluster being down doesn't hide the plan of the others
+    for remote in worker.remotes() {
+        let cluster = remote.cluster().unwrap_or_default();
+        match remote.get_all_ns().await {
+            Ok(all_ns) => {
+                let reports = remote.ensure(all_ns, configs.clone()).await;
+                plan.add_reports(Some(cluster), reports);
+            }
+            Err(e) => plan.errors.push(PlanError {
+                namespace: format!("{}/*", cluster),
+                error: format!("list namespaces: {:#}", e),
+            }),
+        }
+    }
+    Ok(plan)
+}
+
+/// Print the plan to stdout, returns the process exit code.
+pub async fn run(client: Client, settings: Settings, args: &PlanArgs) -> Result<i32> {
+    let plan = plan(client, settings, args.config_file.as_ref()).await?;
+    match args.output {
+        OutputFormat::Text => print!("{}", plan.render_text()),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+    }
+
+    let code = if !plan.errors.is_empty() {
+        EXIT_ERRORS
+    } else if args.exit_code && !plan.is_empty() {
+        EXIT_CHANGES
+    } else {
+        0
+    };
+    Ok(code)
+}
+
+fn read_config_file(path: &PathBuf, settings: &Settings) -> Result<Vec<Config>> {
+    let data = fs::read(path).with_context(|| format!("read config file {:?}", path))?;
+    validate::parse_configs(&data, settings)
+        .with_context(|| format!("invalid config file {:?}", path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Action, Change, Plan, PlanError, PlanSkip};
+
+    fn change(action: Action, kind: &'static str, name: &str, detail: Option<&str>) -> Change {
+        Change {
+            action,
+            kind,
+            namespace: "team-a".to_string(),
+            name: name.to_string(),
+            detail: detail.map(String::from),
+        }
+    }
+
+    #[test]
+    fn render() {
+        let plan = Plan {
+            changes: vec![
+                change(Action::Create, "secret", "r.io", None),
+                change(Action::Patch, "secret", "q.io", Some("data, labels")),
+                change(Action::Delete, "secret", "old.io", None),
+                change(
+                    Action::Patch,
+                    "serviceaccount",
+                    "default",
+                    Some("imagePullSecrets +r.io"),
+                ),
+            ],
+            errors: vec![PlanError {
+                namespace: "team-b".to_string(),
+                error: "forbidden".to_string(),
+            }],
+            skipped: vec![PlanSkip {
+                server: "ecr.io".to_string(),
+                reason: "exec credentials are not resolved by a plan".to_string(),
+            }],
+        };
+        assert_eq!(
+            plan.render_text(),
+            "+ secret team-a/r.io\n\
+             ~ secret team-a/q.io (data, labels)\n\
+             - secret team-a/old.io\n\
+             ~ serviceaccount team-a/default (imagePullSecrets +r.io)\n\
+             ! ns team-b: forbidden\n\
+             ? registry ecr.io: exec credentials are not resolved by a plan\n\
+             Plan: 1 to create, 2 to patch, 1 to delete, 1 errors.\n"
+        );
+
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["changes"][0]["action"], "create");
+        assert!(json["changes"][0].get("detail").is_none());
+        assert_eq!(json["changes"][1]["detail"], "data, labels");
+        assert_eq!(json["errors"][0]["namespace"], "team-b");
+        assert_eq!(json["skipped"][0]["server"], "ecr.io");
+
+        assert_eq!(
+            Plan::default().render_text(),
+            "No changes, all namespaces are in sync.\n"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::Notify, time::Instant};
+
+const INITIAL_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+struct Inner {
+    queue: VecDeque<String>,
+    // keys in the queue, a key is queued at most once
+    queued: HashSet<String>,
+    processing: HashSet<String>,
+    // keys added again while being processed
+    dirty: HashSet<String>,
+    // consecutive failures of a key
+    failures: HashMap<String, u32>,
+}
+
+/// A deduplicating work queue of namespaces, like the client-go workqueue: a
+/// key is never queued twice nor processed concurrently, keys added while
+/// being processed are processed once more afterwards, failed keys are retried
+/// with exponential backoff and keys are handed out at most `qps` per second.
+pub struct WorkQueue {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    // the earliest time the next key is handed out
+    next_slot: Mutex<Instant>,
+    interval: Duration,
+    initial_retry: Duration,
+    max_retry: Duration,
+}
+
+impl WorkQueue {
+    /// A queue handing out at most `qps` keys per second, 0 is unlimited.
+    pub fn new(qps: u32) -> Arc<Self> {
+        WorkQueue::with_retry(qps, INITIAL_RETRY, MAX_RETRY)
+    }
+
+    /// Like `new`, failed keys are retried after `initial_retry` doubling up
+    /// to `max_retry`.
+    pub fn with_retry(qps: u32, initial_retry: Duration, max_retry: Duration) -> Arc<Self> {
+        let interval = match qps {
+            0 => Duration::from_secs(0),
+            qps => Duration::from_secs(1) / qps,
+        };
+        Arc::new(WorkQueue {
+            inner: Mutex::new(Inner::default()),
+            notify: Notify::new(),
+            next_slot: Mutex::new(Instant::now()),
+            interval,
+            initial_retry,
+            max_retry,
+        })
+    }
+
+    pub fn add(&self, key: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.queued.contains(key) {
+            return;
+        }
+        if inner.processing.contains(key) {
+            inner.dirty.insert(key.to_string());
+            return;
+        }
+        inner.queued.insert(key.to_string());
+        inner.queue.push_back(key.to_string());
+        drop(inner);
+        self.notify.notify_one();
+    }
+
+    /// Wait for the next key, which has to be passed to `done` afterwards.
+    pub async fn next(&self) -> String {
+        loop {
+            let key = {
+                let mut inner = self.inner.lock().unwrap();
+                match inner.queue.pop_front() {
+                    Some(key) => {
+                        inner.queued.remove(&key);
+                        inner.processing.insert(key.clone());
+                        Some(key)
+                    }
+                    None => None,
+                }
+            };
+            if let Some(key) = key {
+                self.throttle().await;
+                return key;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Finish processing `key`, a failed key is added again after a backoff.
+    pub fn done(self: &Arc<Self>, key: &str, ok: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.processing.remove(key);
+        let dirty = inner.dirty.remove(key);
+
+        if ok {
+            inner.failures.remove(key);
+        } else {
+            let failures = inner.failures.entry(key.to_string()).or_insert(0);
+            *failures += 1;
+            let delay = self.retry_delay(*failures);
+            if !dirty {
+                debug!("retry '{}' in {:?}", key, delay);
+                let (queue, key) = (self.clone(), key.to_string());
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    queue.add(&key);
+                });
+            }
+        }
+        drop(inner);
+
+        if dirty {
+            self.add(key);
+        }
+    }
+
+    /// Nothing is queued, processed or failing.
+    pub fn is_idle(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.queue.is_empty() && inner.processing.is_empty() && inner.failures.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn retry_delay(&self, failures: u32) -> Duration {
+        self.initial_retry
+            .checked_mul(1 << failures.saturating_sub(1).min(16))
+            .map_or(self.max_retry, |d| d.min(self.max_retry))
+    }
+
+    async fn throttle(&self) {
+        let slot = {
+            let mut next = self.next_slot.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WorkQueue;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn dedup() {
+        let q = WorkQueue::new(0);
+        q.add("a");
+        q.add("b");
+        q.add("a");
+        assert_eq!(q.len(), 2);
+
+        let a = q.next().await;
+        assert_eq!(a, "a");
+        // added while processing, handed out again once done
+        q.add("a");
+        assert_eq!(q.next().await, "b");
+        assert!(q.is_empty());
+        q.done("a", true);
+        assert_eq!(q.next().await, "a");
+        q.done("a", true);
+        q.done("b", true);
+        assert!(q.is_idle());
+    }
+
+    #[tokio::test]
+    async fn retry_failed() {
+        let q = WorkQueue::with_retry(0, Duration::from_millis(20), Duration::from_secs(1));
+        q.add("a");
+        let a = q.next().await;
+        q.done(&a, false);
+        assert!(q.is_empty());
+        assert!(!q.is_idle());
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(q.next().await, "a");
+        q.done("a", true);
+        assert!(q.is_idle());
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let q = WorkQueue::new(50);
+        for key in ["a", "b", "c"].iter() {
+            q.add(key);
+        }
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            q.next().await;
+        }
+        // the first key is handed out right away
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn retry_delays() {
+        let q = WorkQueue::new(0);
+        let delays: Vec<u64> = [1, 2, 3, 9, 100]
+            .iter()
+            .map(|n| q.retry_delay(*n).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 256, 300]);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::{crd, settings::Settings};
+use anyhow::Result;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use structopt::StructOpt;
+
+const NAME: &str = "imagepullsecret-sync";
+// the verbs in the order of `kubectl describe`
+const VERBS: [&str; 7] = [
+    "get", "list", "watch", "create", "update", "patch", "delete",
+];
+
+#[derive(Debug, StructOpt)]
+pub struct RbacArgs {
+    /// Service account the sync runs as
+    #[structopt(long, default_value = NAME)]
+    pub service_account: String,
+
+    /// Namespace of the service account [default: the config namespace]
+    #[structopt(long)]
+    pub service_account_namespace: Option<String>,
+}
+
+// the verbs per (api group, resource) of one Role or ClusterRole
+#[derive(Debug, Default)]
+struct Rules(BTreeMap<(&'static str, &'static str), BTreeSet<usize>>);
+
+impl Rules {
+    fn allow(&mut self, group: &'static str, resource: &'static str, verbs: &[&str]) {
+        let allowed = self.0.entry((group, resource)).or_default();
+        allowed.extend(
+            verbs
+                .iter()
+                .filter_map(|v| VERBS.iter().position(|k| k == v)),
+        );
+    }
+
+    // what the sync does in a target namespace
+    fn allow_target(&mut self) {
+        self.allow(
+            "",
+            "secrets",
+            &["get", "list", "watch", "create", "patch", "delete"],
+        );
+        self.allow("", "serviceaccounts", &["get", "list", "watch", "patch"]);
+        self.allow("", "events", &["create", "patch"]);
+    }
+
+    fn to_json(&self) -> Vec<Value> {
+        self.0
+            .iter()
+            .map(|((group, resource), verbs)| {
+                let verbs: Vec<&str> = verbs.iter().map(|i| VERBS[*i]).collect();
+                json!({ "apiGroups": [group], "resources": [resource], "verbs": verbs })
+            })
+            .collect()
+    }
+}
+
+/// The least privileged RBAC objects of `settings`: a ClusterRole when all
+/// namespaces are synced, else a Role per watch namespace. The config and
+/// lease namespaces get a Role for what they need beyond that.
+pub fn manifests(settings: &Settings, args: &RbacArgs) -> Vec<Value> {
+    let subject = json!({
+        "kind": "ServiceAccount",
+        "name": args.service_account,
+        "namespace": args
+            .service_account_namespace
+            .as_deref()
+            .unwrap_or(&settings.config_namespace),
+    });
+
+    let mut cluster = Rules::default();
+    let mut namespaced: BTreeMap<&str, Rules> = BTreeMap::new();
+    if settings.namespace_scoped() {
+        for ns in settings.watch_namespaces.iter() {
+            namespaced.entry(ns).or_default().allow_target();
+        }
+        // the config secret, its status annotation and events
+        let cfg = namespaced.entry(&settings.config_namespace).or_default();
+        cfg.allow("", "secrets", &["get", "list", "watch", "patch"]);
+        cfg.allow("", "events", &["create", "patch"]);
+    } else {
+        cluster.allow("", "namespaces", &["get", "list", "watch"]);
+        cluster.allow_target();
+        if settings.registry_credentials {
+            cluster.allow(crd::GROUP, "registrycredentials", &["get", "list", "watch"]);
+            cluster.allow(crd::GROUP, "registrycredentials/status", &["patch"]);
+        }
+    }
+    if settings.leader_election {
+        namespaced
+            .entry(&settings.lease_namespace)
+            .or_default()
+            .allow(
+                "coordination.k8s.io",
+                "leases",
+                &["get", "create", "update"],
+            );
+    }
+
+    let mut objects = Vec::new();
+    if !cluster.0.is_empty() {
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRole",
+            "metadata": { "name": NAME },
+            "rules": cluster.to_json(),
+        }));
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "ClusterRoleBinding",
+            "metadata": { "name": NAME },
+            "roleRef": { "apiGroup": "rbac.authorization.k8s.io", "kind": "ClusterRole", "name": NAME },
+            "subjects": [subject],
+        }));
+    }
+    for (ns, rules) in namespaced.iter() {
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "Role",
+            "metadata": { "name": NAME, "namespace": ns },
+            "rules": rules.to_json(),
+        }));
+        objects.push(json!({
+            "apiVersion": "rbac.authorization.k8s.io/v1",
+            "kind": "RoleBinding",
+            "metadata": { "name": NAME, "namespace": ns },
+            "roleRef": { "apiGroup": "rbac.authorization.k8s.io", "kind": "Role", "name": NAME },
+            "subjects": [subject],
+        }));
+    }
+    objects
+}
+
+/// The manifests as multi-document YAML, for `kubectl apply -f -`.
+pub fn render(settings: &Settings, args: &RbacArgs) -> Result<String> {
+    let mut out = if settings.namespace_scoped() {
+        format!(
+            "# RBAC of imagepullsecret-sync syncing the namespaces {}\n",
+            settings.watch_namespaces.join(", ")
+        )
+    } else {
+        "# RBAC of imagepullsecret-sync syncing all namespaces\n".to_string()
+    };
+    out.push_str("# registries with secret_ref credentials also need get on those secrets\n");
+    for object in manifests(settings, args).iter() {
+        out.push_str(&serde_yaml::to_string(object)?);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{manifests, RbacArgs};
+    use crate::settings::Settings;
+    use serde_json::{json, Value};
+
+    fn args() -> RbacArgs {
+        RbacArgs {
+            service_account: "sync".to_string(),
+            service_account_namespace: None,
+        }
+    }
+
+    fn kinds(objects: &[Value]) -> Vec<String> {
+        objects
+            .iter()
+            .map(|o| {
+                let ns = o["metadata"]["namespace"].as_str().unwrap_or_default();
+                format!("{} {}", o["kind"].as_str().unwrap(), ns)
+                    .trim()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn cluster_wide() {
+        let settings = Settings {
+            registry_credentials: true,
+            ..Settings::default()
+        };
+        let objects = manifests(&settings, &args());
+        assert_eq!(kinds(&objects), vec!["ClusterRole", "ClusterRoleBinding"]);
+        let rules = objects[0]["rules"].as_array().unwrap();
+        assert!(rules.contains(&json!({
+            "apiGroups": [""],
+            "resources": ["namespaces"],
+            "verbs": ["get", "list", "watch"],
+        })));
+        assert!(rules.contains(&json!({
+            "apiGroups": ["imagepullsecret-sync.io"],
+            "resources": ["registrycredentials/status"],
+            "verbs": ["patch"],
+        })));
+        assert_eq!(
+            objects[1]["subjects"],
+            json!([{ "kind": "ServiceAccount", "name": "sync", "namespace": "default" }])
+        );
+    }
+
+    #[test]
+    fn namespace_scoped() {
+        let settings = Settings {
+            watch_namespaces: vec!["team-a".to_string(), "team-b".to_string()],
+            config_namespace: "infra".to_string(),
+            lease_namespace: "infra".to_string(),
+            leader_election: true,
+            ..Settings::default()
+        };
+        let objects = manifests(&settings, &args());
+        assert_eq!(
+            kinds(&objects),
+            vec![
+                "Role infra",
+                "RoleBinding infra",
+                "Role team-a",
+                "RoleBinding team-a",
+                "Role team-b",
+                "RoleBinding team-b",
+            ]
+        );
+        assert_eq!(
+            objects[0]["rules"],
+            json!([
+                { "apiGroups": [""], "resources": ["events"], "verbs": ["create", "patch"] },
+                { "apiGroups": [""], "resources": ["secrets"], "verbs": ["get", "list", "watch", "patch"] },
+                { "apiGroups": ["coordination.k8s.io"], "resources": ["leases"], "verbs": ["get", "create", "update"] },
+            ])
+        );
+        assert!(objects[2]["rules"].as_array().unwrap().contains(&json!({
+            "apiGroups": [""],
+            "resources": ["secrets"],
+            "verbs": ["get", "list", "watch", "create", "patch", "delete"],
+        })));
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::{anyhow, Result};
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+/// A kubernetes label selector, e.g. `team=payments,tier in (web,api),!legacy`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+    raw: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Requirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    In(String, Vec<String>),
+    NotIn(String, Vec<String>),
+    Exists(String),
+    NotExists(String),
+}
+
+impl LabelSelector {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        for term in split_terms(s)? {
+            requirements.push(parse_term(&term)?);
+        }
+        if requirements.is_empty() {
+            return Err(anyhow!("empty label selector"));
+        }
+
+        Ok(LabelSelector {
+            requirements,
+            raw: s.trim().to_string(),
+        })
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.requirements.iter().all(|r| match r {
+            Requirement::Equals(k, v) => labels.get(k) == Some(v),
+            Requirement::NotEquals(k, v) => labels.get(k) != Some(v),
+            Requirement::In(k, vs) => labels.get(k).is_some_and(|v| vs.contains(v)),
+            Requirement::NotIn(k, vs) => labels.get(k).is_none_or(|v| !vs.contains(v)),
+            Requirement::Exists(k) => labels.contains_key(k),
+            Requirement::NotExists(k) => !labels.contains_key(k),
+        })
+    }
+
+    /// Same as [`matches`](Self::matches), for objects whose labels are optional.
+    pub fn matches_opt(&self, labels: &Option<BTreeMap<String, String>>) -> bool {
+        match labels {
+            Some(labels) => self.matches(labels),
+            None => self.matches(&BTreeMap::new()),
+        }
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        LabelSelector::parse(s)
+    }
+}
+
+impl fmt::Display for LabelSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+// split on the commas which are not inside a `(...)` value set
+fn split_terms(s: &str) -> Result<Vec<String>> {
+    let mut terms = Vec::new();
+    let mut depth = 0;
+    let mut cur = String::new();
+    for c in s.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err(anyhow!("unbalanced ')' in selector '{}'", s)),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                terms.push(cur.trim().to_string());
+                cur.clear();
+                continue;
+            }
+            _ => {}
+        }
+        cur.push(c);
+    }
+    if depth != 0 {
+        return Err(anyhow!("unbalanced '(' in selector '{}'", s));
+    }
+    if !cur.trim().is_empty() || !terms.is_empty() {
+        terms.push(cur.trim().to_string());
+    }
+    Ok(terms)
+}
+
+fn parse_term(term: &str) -> Result<Requirement> {
+    if term.is_empty() {
+        return Err(anyhow!("empty term in label selector"));
+    }
+
+    if let Some(key) = term.strip_prefix('!') {
+        return Ok(Requirement::NotExists(parse_key(key)?));
+    }
+    if let Some(i) = term.find("!=") {
+        return Ok(Requirement::NotEquals(
+            parse_key(&term[..i])?,
+            parse_value(&term[i + 2..])?,
+        ));
+    }
+    if let Some(i) = term.find("==") {
+        return Ok(Requirement::Equals(
+            parse_key(&term[..i])?,
+            parse_value(&term[i + 2..])?,
+        ));
+    }
+    if let Some(i) = term.find('=') {
+        return Ok(Requirement::Equals(
+            parse_key(&term[..i])?,
+            parse_value(&term[i + 1..])?,
+        ));
+    }
+    if let Some(i) = term.find('(') {
+        let head: Vec<&str> = term[..i].split_whitespace().collect();
+        let values = term[i + 1..]
+            .strip_suffix(')')
+            .ok_or_else(|| anyhow!("bad value set in term '{}'", term))?
+            .split(',')
+            .map(parse_value)
+            .collect::<Result<Vec<_>>>()?;
+        return match head.as_slice() {
+            [key, "in"] => Ok(Requirement::In(parse_key(key)?, values)),
+            [key, "notin"] => Ok(Requirement::NotIn(parse_key(key)?, values)),
+            _ => Err(anyhow!("bad set based term '{}'", term)),
+        };
+    }
+
+    Ok(Requirement::Exists(parse_key(term)?))
+}
+
+fn parse_key(key: &str) -> Result<String> {
+    let key = key.trim();
+    let name = match key.rsplit_once('/') {
+        Some((prefix, name)) if !prefix.is_empty() => name,
+        Some(_) => return Err(anyhow!("bad label key '{}'", key)),
+        None => key,
+    };
+    if name.is_empty() || name.len() > 63 || !is_label_chars(name) {
+        return Err(anyhow!("bad label key '{}'", key));
+    }
+    Ok(key.to_string())
+}
+
+fn parse_value(value: &str) -> Result<String> {
+    let value = value.trim();
+    if value.len() > 63 || !(value.is_empty() || is_label_chars(value)) {
+        return Err(anyhow!("bad label value '{}'", value));
+    }
+    Ok(value.to_string())
+}
+
+fn is_label_chars(s: &str) -> bool {
+    s.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && s.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && s.ends_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::LabelSelector;
+    use std::collections::BTreeMap;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn equality() {
+        let s = LabelSelector::parse("team=payments, env!=prod").unwrap();
+        assert!(s.matches(&labels(&[("team", "payments"), ("env", "dev")])));
+        assert!(s.matches(&labels(&[("team", "payments")])));
+        assert!(!s.matches(&labels(&[("team", "payments"), ("env", "prod")])));
+        assert!(!s.matches(&labels(&[("team", "search")])));
+    }
+
+    #[test]
+    fn set_based() {
+        let s = LabelSelector::parse("tier in (web, api),app.kubernetes.io/name,!legacy").unwrap();
+        assert!(s.matches(&labels(&[("tier", "api"), ("app.kubernetes.io/name", "x")])));
+        assert!(!s.matches(&labels(&[("tier", "db"), ("app.kubernetes.io/name", "x")])));
+        assert!(!s.matches(&labels(&[
+            ("tier", "web"),
+            ("app.kubernetes.io/name", "x"),
+            ("legacy", "true")
+        ])));
+
+        let s = LabelSelector::parse("tier notin (db)").unwrap();
+        assert!(s.matches(&labels(&[])));
+        assert!(!s.matches(&labels(&[("tier", "db")])));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(LabelSelector::parse("").is_err());
+        assert!(LabelSelector::parse("team=payments,").is_err());
+        assert!(LabelSelector::parse("=x").is_err());
+        assert!(LabelSelector::parse("tier in (web").is_err());
+        assert!(LabelSelector::parse("tier within (web)").is_err());
+        assert!(LabelSelector::parse("te am=x").is_err());
+    }
+}
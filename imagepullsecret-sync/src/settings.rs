@@ -0,0 +1,884 @@
+use crate::{
+    credentials::OPT_IN_SOURCES, naming::NameTemplate, plan::PlanArgs, rbac::RbacArgs,
+    selector::LabelSelector, validate::ValidateArgs,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::{fs, net::SocketAddr, path::PathBuf};
+use structopt::StructOpt;
+
+pub const DEFAULT_CONFIG_NAMESPACE: &str = "default";
+pub const DEFAULT_CONFIG_NAME: &str = "docker-registry";
+pub const DEFAULT_CONFIG_KEY: &str = "registry_secrets";
+pub const DEFAULT_SERVICE_ACCOUNT: &str = "default";
+pub const DEFAULT_SECRET_NAME_TEMPLATE: &str = "{{server}}";
+pub const DEFAULT_CREDENTIALS_POLL_INTERVAL: u64 = 60;
+pub const DEFAULT_TOKEN_REFRESH_BEFORE: u64 = 300;
+pub const DEFAULT_LOG_LEVEL: &str = "info,kube=debug";
+pub const DEFAULT_NAMESPACE_FIELD_SELECTOR: &str = "status.phase=Active";
+pub const DEFAULT_METRICS_ADDR: &str = "0.0.0.0:8080";
+pub const DEFAULT_LEASE_NAME: &str = "imagepullsecret-sync";
+pub const DEFAULT_LEASE_DURATION: u64 = 15;
+pub const DEFAULT_LEASE_RETRY_PERIOD: u64 = 2;
+pub const DEFAULT_WORKERS: usize = 4;
+pub const DEFAULT_QUEUE_QPS: u32 = 20;
+pub const DEFAULT_RESYNC_INTERVAL: u64 = 600;
+pub const DEFAULT_FIELD_MANAGER: &str = "imagepullsecret-sync";
+pub const DEFAULT_WEBHOOK_CERT: &str = "/tls/tls.crt";
+pub const DEFAULT_WEBHOOK_KEY: &str = "/tls/tls.key";
+
+/// Command line flags, every flag can also be set by its environment variable.
+/// Flags take precedence over the settings file, which takes precedence over
+/// the built-in defaults. Switches are on when given without a value, and can
+/// be turned off with `--switch=false`.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(
+    name = "imagepullsecret-sync",
+    about = "Sync docker registry secrets to namespaces and service accounts"
+)]
+pub struct Opts {
+    /// Optional YAML settings file
+    #[structopt(short = "f", long, env = "IPS_SETTINGS_FILE", parse(from_os_str))]
+    pub settings_file: Option<PathBuf>,
+
+    /// Namespace of the secret holding the registry configs [default: default]
+    #[structopt(long, env = "IPS_CONFIG_NAMESPACE")]
+    pub config_namespace: Option<String>,
+
+    /// Name of the secret holding the registry configs [default: docker-registry]
+    #[structopt(long, env = "IPS_CONFIG_NAME")]
+    pub config_name: Option<String>,
+
+    /// Data key of the registry configs in the config secret [default: registry_secrets]
+    #[structopt(long, env = "IPS_CONFIG_KEY")]
+    pub config_key: Option<String>,
+
+    /// Comma separated service accounts which get the imagePullSecrets [default: default]
+    #[structopt(long, env = "IPS_SERVICE_ACCOUNTS", use_delimiter = true)]
+    pub service_accounts: Vec<String>,
+
+    /// Label selector of additional service accounts which get the imagePullSecrets
+    #[structopt(long, env = "IPS_SERVICE_ACCOUNT_SELECTOR")]
+    pub service_account_selector: Option<String>,
+
+    /// Merge all registries of a namespace into one secret with this name
+    #[structopt(long, env = "IPS_MERGE_SECRET_NAME")]
+    pub merge_secret_name: Option<String>,
+
+    /// Name of the secret of each registry, with {{server}}, {{host}}, {{port}} and {{path}} [default: {{server}}]
+    #[structopt(long, env = "IPS_SECRET_NAME_TEMPLATE")]
+    pub secret_name_template: Option<String>,
+
+    /// Seconds between polls of external credential sources, 0 disables polling [default: 60]
+    #[structopt(long, env = "IPS_CREDENTIALS_POLL_INTERVAL")]
+    pub credentials_poll_interval: Option<u64>,
+
+    /// Seconds before expiry at which short-lived registry tokens are refreshed [default: 300]
+    #[structopt(long, env = "IPS_TOKEN_REFRESH_BEFORE")]
+    pub token_refresh_before: Option<u64>,
+
+    /// Comma separated credential sources which run in the worker to enable: file, env, http, exec
+    #[structopt(long, env = "IPS_CREDENTIAL_SOURCES", use_delimiter = true)]
+    pub credential_sources: Vec<String>,
+
+    /// Comma separated namespaces secret_ref credentials may read besides the config namespace
+    #[structopt(long, env = "IPS_CREDENTIAL_NAMESPACES", use_delimiter = true)]
+    pub credential_namespaces: Vec<String>,
+
+    /// Absolute directory below which the file credential source may read
+    #[structopt(long, env = "IPS_CREDENTIAL_DIR", parse(from_os_str))]
+    pub credential_dir: Option<PathBuf>,
+
+    /// Log in to each registry before distributing its credentials [default: false]
+    #[structopt(long, env = "IPS_VERIFY_CREDENTIALS", require_equals = true)]
+    pub verify_credentials: Option<Option<bool>>,
+
+    /// Log filter in env_logger syntax [default: info,kube=debug]
+    #[structopt(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+
+    /// Label selector used when watching namespaces
+    #[structopt(long, env = "IPS_NAMESPACE_LABEL_SELECTOR")]
+    pub namespace_label_selector: Option<String>,
+
+    /// Field selector used when watching namespaces [default: status.phase=Active]
+    #[structopt(long, env = "IPS_NAMESPACE_FIELD_SELECTOR")]
+    pub namespace_field_selector: Option<String>,
+
+    /// Comma separated namespaces to sync instead of watching all of them, needs no cluster-wide RBAC
+    #[structopt(long, env = "IPS_WATCH_NAMESPACES", use_delimiter = true)]
+    pub watch_namespaces: Vec<String>,
+
+    /// Address of the /metrics, /healthz and /readyz endpoints [default: 0.0.0.0:8080]
+    #[structopt(long, env = "IPS_METRICS_ADDR")]
+    pub metrics_addr: Option<String>,
+
+    /// Only reconcile while holding a Lease, for running multiple replicas [default: false]
+    #[structopt(long, env = "IPS_LEADER_ELECTION", require_equals = true)]
+    pub leader_election: Option<Option<bool>>,
+
+    /// Name of the leader election Lease [default: imagepullsecret-sync]
+    #[structopt(long, env = "IPS_LEASE_NAME")]
+    pub lease_name: Option<String>,
+
+    /// Namespace of the leader election Lease [default: the config namespace]
+    #[structopt(long, env = "IPS_LEASE_NAMESPACE")]
+    pub lease_namespace: Option<String>,
+
+    /// Seconds a Lease is valid without renewal [default: 15]
+    #[structopt(long, env = "IPS_LEASE_DURATION")]
+    pub lease_duration: Option<u64>,
+
+    /// Seconds between Lease acquire and renew attempts [default: 2]
+    #[structopt(long, env = "IPS_LEASE_RETRY_PERIOD")]
+    pub lease_retry_period: Option<u64>,
+
+    /// Namespaces reconciled concurrently [default: 4]
+    #[structopt(long, env = "IPS_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Namespace reconciles started per second at most, 0 is unlimited [default: 20]
+    #[structopt(long, env = "IPS_QUEUE_QPS")]
+    pub queue_qps: Option<u32>,
+
+    /// Seconds between full resyncs of all namespaces, 0 disables them [default: 600]
+    #[structopt(long, env = "IPS_RESYNC_INTERVAL")]
+    pub resync_interval: Option<u64>,
+
+    /// Field manager of the server-side apply writes [default: imagepullsecret-sync]
+    #[structopt(long, env = "IPS_FIELD_MANAGER")]
+    pub field_manager: Option<String>,
+
+    /// Also read the registries from RegistryCredential objects [default: false]
+    #[structopt(long, env = "IPS_REGISTRY_CREDENTIALS", require_equals = true)]
+    pub registry_credentials: Option<Option<bool>>,
+
+    /// Address of the HTTPS validating admission webhook, disabled when unset
+    #[structopt(long, env = "IPS_WEBHOOK_ADDR")]
+    pub webhook_addr: Option<String>,
+
+    /// PEM certificate chain of the webhook [default: /tls/tls.crt]
+    #[structopt(long, env = "IPS_WEBHOOK_CERT", parse(from_os_str))]
+    pub webhook_cert: Option<PathBuf>,
+
+    /// PEM private key of the webhook [default: /tls/tls.key]
+    #[structopt(long, env = "IPS_WEBHOOK_KEY", parse(from_os_str))]
+    pub webhook_key: Option<PathBuf>,
+
+    /// Also add the registry secrets to new Pods on the webhook [default: false]
+    #[structopt(long, env = "IPS_INJECT_PULL_SECRETS", require_equals = true)]
+    pub inject_pull_secrets: Option<Option<bool>>,
+
+    /// Comma separated kubeconfig contexts of more clusters to sync the registries into
+    #[structopt(long, env = "IPS_CLUSTERS", use_delimiter = true)]
+    pub clusters: Vec<String>,
+
+    /// Kubeconfig file with the --clusters contexts [default: $KUBECONFIG or ~/.kube/config]
+    #[structopt(long, env = "IPS_KUBECONFIG", parse(from_os_str))]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// Append a JSON line per secret and service account change to this file, - is stdout
+    #[structopt(long, env = "IPS_AUDIT_LOG")]
+    pub audit_log: Option<String>,
+
+    /// File with the key of the audit log digests, a random key per process when unset
+    #[structopt(long, env = "IPS_AUDIT_KEY_FILE", parse(from_os_str))]
+    pub audit_key_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+/// One-shot commands, without a command the sync worker runs.
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Print the changes a sync would make without making them
+    Plan(PlanArgs),
+    /// Print the least privileged RBAC objects of the settings
+    Rbac(RbacArgs),
+    /// Check registry configs files, without a cluster
+    Validate(ValidateArgs),
+}
+
+/// The content of the optional settings file, all fields are optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase", deny_unknown_fields)]
+pub struct FileSettings {
+    pub config_namespace: Option<String>,
+    pub config_name: Option<String>,
+    pub config_key: Option<String>,
+    pub service_accounts: Option<Vec<String>>,
+    pub service_account_selector: Option<String>,
+    pub merge_secret_name: Option<String>,
+    pub secret_name_template: Option<String>,
+    pub credentials_poll_interval: Option<u64>,
+    pub token_refresh_before: Option<u64>,
+    pub credential_sources: Option<Vec<String>>,
+    pub credential_namespaces: Option<Vec<String>>,
+    pub credential_dir: Option<PathBuf>,
+    pub verify_credentials: Option<bool>,
+    pub log_level: Option<String>,
+    pub namespace_label_selector: Option<String>,
+    pub namespace_field_selector: Option<String>,
+    pub watch_namespaces: Option<Vec<String>>,
+    pub metrics_addr: Option<String>,
+    pub leader_election: Option<bool>,
+    pub lease_name: Option<String>,
+    pub lease_namespace: Option<String>,
+    pub lease_duration: Option<u64>,
+    pub lease_retry_period: Option<u64>,
+    pub workers: Option<usize>,
+    pub queue_qps: Option<u32>,
+    pub resync_interval: Option<u64>,
+    pub field_manager: Option<String>,
+    pub registry_credentials: Option<bool>,
+    pub webhook_addr: Option<String>,
+    pub webhook_cert: Option<PathBuf>,
+    pub webhook_key: Option<PathBuf>,
+    pub inject_pull_secrets: Option<bool>,
+    pub clusters: Option<Vec<String>>,
+    pub kubeconfig: Option<PathBuf>,
+    pub audit_log: Option<String>,
+    pub audit_key_file: Option<PathBuf>,
+}
+
+impl FileSettings {
+    pub fn from_path(path: &PathBuf) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("read settings file {:?}", path))?;
+        serde_yaml::from_slice(&data).with_context(|| format!("parse settings file {:?}", path))
+    }
+}
+
+/// The resolved and validated runtime settings of the sync worker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub config_namespace: String,
+    pub config_name: String,
+    pub config_key: String,
+    pub service_accounts: Vec<String>,
+    pub service_account_selector: Option<String>,
+    pub merge_secret_name: Option<String>,
+    pub secret_name_template: String,
+    pub credentials_poll_interval: u64,
+    pub token_refresh_before: u64,
+    // the opt-in credential sources, see OPT_IN_SOURCES
+    pub credential_sources: Vec<String>,
+    // where secret_ref credentials may be read, besides the config namespace
+    pub credential_namespaces: Vec<String>,
+    pub credential_dir: Option<PathBuf>,
+    pub verify_credentials: bool,
+    pub log_level: String,
+    pub namespace_label_selector: Option<String>,
+    pub namespace_field_selector: String,
+    // the namespaces of the namespace-scoped mode, all namespaces when empty
+    pub watch_namespaces: Vec<String>,
+    pub metrics_addr: String,
+    pub leader_election: bool,
+    pub lease_name: String,
+    pub lease_namespace: String,
+    pub lease_duration: u64,
+    pub lease_retry_period: u64,
+    pub workers: usize,
+    pub queue_qps: u32,
+    pub resync_interval: u64,
+    pub field_manager: String,
+    pub registry_credentials: bool,
+    pub webhook_addr: Option<String>,
+    pub webhook_cert: PathBuf,
+    pub webhook_key: PathBuf,
+    pub inject_pull_secrets: bool,
+    pub clusters: Vec<String>,
+    pub kubeconfig: Option<PathBuf>,
+    pub audit_log: Option<String>,
+    pub audit_key_file: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::merge(Opts::default(), FileSettings::default())
+    }
+}
+
+impl Settings {
+    /// Resolve the settings from flags, env and the optional settings file.
+    pub fn load(opts: Opts) -> Result<Self> {
+        let file = match &opts.settings_file {
+            Some(path) => FileSettings::from_path(path)?,
+            None => FileSettings::default(),
+        };
+
+        let settings = Settings::merge(opts, file);
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub fn merge(opts: Opts, file: FileSettings) -> Self {
+        let service_account_selector = opts
+            .service_account_selector
+            .or(file.service_account_selector)
+            .filter(|s| !s.trim().is_empty());
+
+        // the default service account is only implied when no selector is given
+        let service_accounts = if !opts.service_accounts.is_empty() {
+            opts.service_accounts
+        } else if let Some(sas) = file.service_accounts {
+            sas
+        } else if service_account_selector.is_some() {
+            vec![]
+        } else {
+            vec![DEFAULT_SERVICE_ACCOUNT.to_string()]
+        };
+
+        let config_namespace = opts
+            .config_namespace
+            .or(file.config_namespace)
+            .unwrap_or_else(|| DEFAULT_CONFIG_NAMESPACE.to_string());
+
+        Settings {
+            lease_namespace: opts
+                .lease_namespace
+                .or(file.lease_namespace)
+                .unwrap_or_else(|| config_namespace.clone()),
+            config_namespace,
+            config_name: opts
+                .config_name
+                .or(file.config_name)
+                .unwrap_or_else(|| DEFAULT_CONFIG_NAME.to_string()),
+            config_key: opts
+                .config_key
+                .or(file.config_key)
+                .unwrap_or_else(|| DEFAULT_CONFIG_KEY.to_string()),
+            service_accounts: service_accounts
+                .into_iter()
+                .map(|sa| sa.trim().to_string())
+                .collect(),
+            service_account_selector,
+            merge_secret_name: opts
+                .merge_secret_name
+                .or(file.merge_secret_name)
+                .filter(|s| !s.trim().is_empty()),
+            secret_name_template: opts
+                .secret_name_template
+                .or(file.secret_name_template)
+                .filter(|s| !s.trim().is_empty())
+                .unwrap_or_else(|| DEFAULT_SECRET_NAME_TEMPLATE.to_string()),
+            credentials_poll_interval: opts
+                .credentials_poll_interval
+                .or(file.credentials_poll_interval)
+                .unwrap_or(DEFAULT_CREDENTIALS_POLL_INTERVAL),
+            token_refresh_before: opts
+                .token_refresh_before
+                .or(file.token_refresh_before)
+                .unwrap_or(DEFAULT_TOKEN_REFRESH_BEFORE),
+            credential_sources: if !opts.credential_sources.is_empty() {
+                opts.credential_sources
+            } else {
+                file.credential_sources.unwrap_or_default()
+            }
+            .into_iter()
+            .map(|s| s.trim().to_string())
+            .collect(),
+            credential_namespaces: if !opts.credential_namespaces.is_empty() {
+                opts.credential_namespaces
+            } else {
+                file.credential_namespaces.unwrap_or_default()
+            }
+            .into_iter()
+            .map(|ns| ns.trim().to_string())
+            .collect(),
+            credential_dir: opts.credential_dir.or(file.credential_dir),
+            verify_credentials: switch(opts.verify_credentials)
+                .or(file.verify_credentials)
+                .unwrap_or(false),
+            log_level: opts
+                .log_level
+                .or(file.log_level)
+                .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string()),
+            namespace_label_selector: opts
+                .namespace_label_selector
+                .or(file.namespace_label_selector)
+                .filter(|s| !s.trim().is_empty()),
+            namespace_field_selector: opts
+                .namespace_field_selector
+                .or(file.namespace_field_selector)
+                .unwrap_or_else(|| DEFAULT_NAMESPACE_FIELD_SELECTOR.to_string()),
+            watch_namespaces: if !opts.watch_namespaces.is_empty() {
+                opts.watch_namespaces
+            } else {
+                file.watch_namespaces.unwrap_or_default()
+            }
+            .into_iter()
+            .map(|ns| ns.trim().to_string())
+            .collect(),
+            metrics_addr: opts
+                .metrics_addr
+                .or(file.metrics_addr)
+                .unwrap_or_else(|| DEFAULT_METRICS_ADDR.to_string()),
+            leader_election: switch(opts.leader_election)
+                .or(file.leader_election)
+                .unwrap_or(false),
+            lease_name: opts
+                .lease_name
+                .or(file.lease_name)
+                .unwrap_or_else(|| DEFAULT_LEASE_NAME.to_string()),
+            lease_duration: opts
+                .lease_duration
+                .or(file.lease_duration)
+                .unwrap_or(DEFAULT_LEASE_DURATION),
+            lease_retry_period: opts
+                .lease_retry_period
+                .or(file.lease_retry_period)
+                .unwrap_or(DEFAULT_LEASE_RETRY_PERIOD),
+            workers: opts.workers.or(file.workers).unwrap_or(DEFAULT_WORKERS),
+            queue_qps: opts
+                .queue_qps
+                .or(file.queue_qps)
+                .unwrap_or(DEFAULT_QUEUE_QPS),
+            resync_interval: opts
+                .resync_interval
+                .or(file.resync_interval)
+                .unwrap_or(DEFAULT_RESYNC_INTERVAL),
+            field_manager: opts
+                .field_manager
+                .or(file.field_manager)
+                .unwrap_or_else(|| DEFAULT_FIELD_MANAGER.to_string()),
+            registry_credentials: switch(opts.registry_credentials)
+                .or(file.registry_credentials)
+                .unwrap_or(false),
+            webhook_addr: opts
+                .webhook_addr
+                .or(file.webhook_addr)
+                .filter(|s| !s.trim().is_empty()),
+            webhook_cert: opts
+                .webhook_cert
+                .or(file.webhook_cert)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_WEBHOOK_CERT)),
+            webhook_key: opts
+                .webhook_key
+                .or(file.webhook_key)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_WEBHOOK_KEY)),
+            inject_pull_secrets: switch(opts.inject_pull_secrets)
+                .or(file.inject_pull_secrets)
+                .unwrap_or(false),
+            clusters: if !opts.clusters.is_empty() {
+                opts.clusters
+            } else {
+                file.clusters.unwrap_or_default()
+            }
+            .into_iter()
+            .map(|c| c.trim().to_string())
+            .collect(),
+            kubeconfig: opts.kubeconfig.or(file.kubeconfig),
+            audit_log: opts
+                .audit_log
+                .or(file.audit_log)
+                .filter(|s| !s.trim().is_empty()),
+            audit_key_file: opts.audit_key_file.or(file.audit_key_file),
+        }
+    }
+
+    /// Only the --watch-namespaces are synced, with namespaced permissions.
+    pub fn namespace_scoped(&self) -> bool {
+        !self.watch_namespaces.is_empty()
+    }
+
+    /// Whether secret_ref credentials may be read from the namespace.
+    pub fn credential_namespace_allowed(&self, namespace: &str) -> bool {
+        namespace == self.config_namespace
+            || self.credential_namespaces.iter().any(|ns| ns == namespace)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if !is_dns1123_label(&self.config_namespace) {
+            return Err(anyhow!(
+                "invalid config namespace '{}': must be a DNS-1123 label",
+                self.config_namespace
+            ));
+        }
+        if !is_dns1123_subdomain(&self.config_name) {
+            return Err(anyhow!(
+                "invalid config name '{}': must be a DNS-1123 subdomain",
+                self.config_name
+            ));
+        }
+        if !is_config_key(&self.config_key) {
+            return Err(anyhow!(
+                "invalid config key '{}': must consist of alphanumeric characters, '-', '_' or '.'",
+                self.config_key
+            ));
+        }
+        if self.service_accounts.is_empty() && self.service_account_selector.is_none() {
+            return Err(anyhow!(
+                "at least one service account or a service account selector is required"
+            ));
+        }
+        for sa in self.service_accounts.iter() {
+            if !is_dns1123_subdomain(sa) {
+                return Err(anyhow!(
+                    "invalid service account '{}': must be a DNS-1123 subdomain",
+                    sa
+                ));
+            }
+        }
+        if let Some(name) = &self.merge_secret_name {
+            if !is_dns1123_subdomain(name) {
+                return Err(anyhow!(
+                    "invalid merge secret name '{}': must be a DNS-1123 subdomain",
+                    name
+                ));
+            }
+        }
+        NameTemplate::parse(&self.secret_name_template)?;
+        for source in self.credential_sources.iter() {
+            if !OPT_IN_SOURCES.contains(&source.as_str()) {
+                return Err(anyhow!(
+                    "invalid credential source '{}': must be one of {}",
+                    source,
+                    OPT_IN_SOURCES.join(", ")
+                ));
+            }
+        }
+        for ns in self.credential_namespaces.iter() {
+            if !is_dns1123_label(ns) {
+                return Err(anyhow!(
+                    "invalid credential namespace '{}': must be a DNS-1123 label",
+                    ns
+                ));
+            }
+        }
+        match &self.credential_dir {
+            Some(dir) if !dir.is_absolute() => {
+                return Err(anyhow!(
+                    "invalid credential dir {:?}: must be an absolute path",
+                    dir
+                ));
+            }
+            None if self.credential_sources.iter().any(|s| s == "file") => {
+                return Err(anyhow!(
+                    "the file credential source needs a --credential-dir"
+                ));
+            }
+            _ => {}
+        }
+        if self.log_level.trim().is_empty() {
+            return Err(anyhow!("log level must not be empty"));
+        }
+        if let Some(selector) = &self.service_account_selector {
+            LabelSelector::parse(selector)
+                .with_context(|| format!("invalid service account selector '{}'", selector))?;
+        }
+        if let Some(selector) = &self.namespace_label_selector {
+            LabelSelector::parse(selector)
+                .with_context(|| format!("invalid namespace label selector '{}'", selector))?;
+        }
+        validate_field_selector(&self.namespace_field_selector)?;
+        for (i, ns) in self.watch_namespaces.iter().enumerate() {
+            if !is_dns1123_label(ns) {
+                return Err(anyhow!(
+                    "invalid watch namespace '{}': must be a DNS-1123 label",
+                    ns
+                ));
+            }
+            if self.watch_namespaces[..i].contains(ns) {
+                return Err(anyhow!("duplicate watch namespace '{}'", ns));
+            }
+        }
+        // both need to read cluster scoped objects
+        if self.namespace_scoped() && self.namespace_label_selector.is_some() {
+            return Err(anyhow!(
+                "a namespace label selector can't be used with watch namespaces"
+            ));
+        }
+        if self.namespace_scoped() && self.registry_credentials {
+            return Err(anyhow!(
+                "RegistryCredential objects can't be used with watch namespaces"
+            ));
+        }
+        self.metrics_addr
+            .parse::<SocketAddr>()
+            .with_context(|| format!("invalid metrics address '{}'", self.metrics_addr))?;
+        if let Some(addr) = &self.webhook_addr {
+            addr.parse::<SocketAddr>()
+                .with_context(|| format!("invalid webhook address '{}'", addr))?;
+        } else if self.inject_pull_secrets {
+            return Err(anyhow!("injecting pull secrets requires a webhook address"));
+        }
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            if cluster.is_empty() {
+                return Err(anyhow!("cluster contexts must not be empty"));
+            }
+            if self.clusters[..i].contains(cluster) {
+                return Err(anyhow!("duplicate cluster context '{}'", cluster));
+            }
+        }
+        if self.workers == 0 {
+            return Err(anyhow!("at least one worker is required"));
+        }
+        if self.field_manager.trim().is_empty() || self.field_manager.len() > 128 {
+            return Err(anyhow!(
+                "invalid field manager '{}': must be 1 to 128 characters",
+                self.field_manager
+            ));
+        }
+        if self.leader_election {
+            if !is_dns1123_label(&self.lease_namespace) {
+                return Err(anyhow!(
+                    "invalid lease namespace '{}': must be a DNS-1123 label",
+                    self.lease_namespace
+                ));
+            }
+            if !is_dns1123_subdomain(&self.lease_name) {
+                return Err(anyhow!(
+                    "invalid lease name '{}': must be a DNS-1123 subdomain",
+                    self.lease_name
+                ));
+            }
+            if self.lease_retry_period == 0 || self.lease_retry_period * 2 > self.lease_duration {
+                return Err(anyhow!(
+                    "lease retry period {}s must be positive and at most half the lease duration {}s",
+                    self.lease_retry_period,
+                    self.lease_duration
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_field_selector(selector: &str) -> Result<()> {
+    for term in selector.split(',') {
+        let term = term.trim();
+        if term.is_empty() || term.starts_with('=') || term.starts_with("!=") {
+            return Err(anyhow!(
+                "invalid namespace field selector '{}': bad term '{}'",
+                selector,
+                term
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn is_config_key(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 253
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pub fn is_dns1123_label(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 63
+        && s.chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+}
+
+// a switch given without a value is on
+fn switch(flag: Option<Option<bool>>) -> Option<bool> {
+    flag.map(|value| value.unwrap_or(true))
+}
+
+pub fn is_dns1123_subdomain(s: &str) -> bool {
+    !s.is_empty()
+        && s.len() <= 253
+        && s.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !part.starts_with('-')
+                && !part.ends_with('-')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileSettings, Opts, Settings};
+    use structopt::StructOpt;
+
+    #[test]
+    fn defaults() {
+        let s = Settings::default();
+        assert_eq!(s.config_namespace, "default");
+        assert_eq!(s.config_name, "docker-registry");
+        assert_eq!(s.config_key, "registry_secrets");
+        assert_eq!(s.service_accounts, vec!["default".to_string()]);
+        assert_eq!(s.namespace_field_selector, "status.phase=Active");
+        assert!(s.validate().is_ok());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let opts = Opts {
+            config_name: Some("flag-name".to_string()),
+            service_accounts: vec!["app".to_string(), " ci-runner".to_string()],
+            ..Opts::default()
+        };
+        let file: FileSettings = serde_yaml::from_str(
+            "configNamespace: infra\nconfigName: file-name\nserviceAccounts: [builder]\n",
+        )
+        .unwrap();
+
+        let s = Settings::merge(opts, file);
+        assert_eq!(s.config_namespace, "infra");
+        assert_eq!(s.config_name, "flag-name");
+        assert_eq!(s.service_accounts, vec!["app", "ci-runner"]);
+    }
+
+    #[test]
+    fn lease_namespace_defaults_to_config_namespace() {
+        let opts = Opts {
+            config_namespace: Some("infra".to_string()),
+            leader_election: Some(Some(true)),
+            ..Opts::default()
+        };
+
+        let s = Settings::merge(opts, FileSettings::default());
+        assert_eq!(s.lease_namespace, "infra");
+        assert!(s.validate().is_ok());
+
+        let s = Settings {
+            lease_retry_period: 10,
+            ..s
+        };
+        assert!(s.validate().is_err());
+    }
+
+    #[test]
+    fn selector_without_names() {
+        let opts = Opts {
+            service_account_selector: Some("imagepullsecret-sync=enabled".to_string()),
+            ..Opts::default()
+        };
+
+        let s = Settings::merge(opts, FileSettings::default());
+        assert!(s.service_accounts.is_empty());
+        assert!(s.validate().is_ok());
+    }
+
+    #[test]
+    fn switches() {
+        let opts = Opts::from_iter_safe(&[
+            "imagepullsecret-sync",
+            "--verify-credentials",
+            "--leader-election=false",
+            "--registry-credentials=true",
+            "plan",
+        ])
+        .unwrap();
+        let file: FileSettings =
+            serde_yaml::from_str("leaderElection: true\ninjectPullSecrets: true\n").unwrap();
+
+        let s = Settings::merge(opts, file);
+        assert!(s.verify_credentials);
+        assert!(!s.leader_election);
+        assert!(s.registry_credentials);
+        assert!(s.inject_pull_secrets);
+
+        assert!(Opts::from_iter_safe(&["imagepullsecret-sync", "--leader-election=yes"]).is_err());
+    }
+
+    #[test]
+    fn unknown_file_field() {
+        let r: Result<FileSettings, _> = serde_yaml::from_str("cfgName: x\n");
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn validate_errors() {
+        let s = Settings {
+            config_namespace: "Kube_System".to_string(),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            service_accounts: vec![],
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            config_key: "registry secrets".to_string(),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            namespace_label_selector: Some("team=payments,=x".to_string()),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            credential_sources: vec!["secret_ref".to_string()],
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            credential_sources: vec!["file".to_string()],
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            service_account_selector: Some("tier in (web".to_string()),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            metrics_addr: "localhost".to_string(),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            field_manager: "x".repeat(129),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            webhook_addr: Some("8443".to_string()),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            secret_name_template: "regcred-{{hostname}}".to_string(),
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            watch_namespaces: vec!["team-a".to_string()],
+            registry_credentials: true,
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            watch_namespaces: vec!["Team_A".to_string()],
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            clusters: vec!["prod".to_string(), "prod".to_string()],
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+
+        let s = Settings {
+            inject_pull_secrets: true,
+            ..Settings::default()
+        };
+        assert!(s.validate().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+/// The coverage of one registry, written to the status annotation of the
+/// config secret.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryStatus {
+    // namespaces the registry secret is in sync in
+    pub synced: usize,
+    pub failed: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failed_namespaces: Vec<String>,
+    // why the registry is synced nowhere, e.g. its credentials failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+/// Add the per registry coverage of the --clusters context `cluster` to
+/// `total`, its failed namespaces are reported as "<cluster>/<namespace>".
+pub fn add_cluster(
+    total: &mut BTreeMap<String, RegistryStatus>,
+    cluster: &str,
+    summary: &BTreeMap<String, RegistryStatus>,
+) {
+    for (server, status) in summary.iter() {
+        let t = total.entry(server.clone()).or_default();
+        t.synced += status.synced;
+        t.failed += status.failed;
+        t.failed_namespaces.extend(
+            status
+                .failed_namespaces
+                .iter()
+                .map(|ns| format!("{}/{}", cluster, ns)),
+        );
+        if t.skipped.is_none() {
+            t.skipped = status.skipped.clone();
+        }
+        if status.last_error.is_some() {
+            t.last_error = status.last_error.clone();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    synced: BTreeSet<String>,
+    // namespace -> error
+    failed: BTreeMap<String, String>,
+    skipped: Option<String>,
+    last_error: Option<String>,
+}
+
+/// Tracks per registry in which namespaces its secret is synced or failed.
+#[derive(Default)]
+pub struct Coverage {
+    registries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage::default()
+    }
+
+    /// Track the configured `servers`, `skipped` are (server, reason) of the
+    /// registries which are synced nowhere.
+    pub fn set_configs(&self, servers: &[String], skipped: &[(String, String)]) {
+        let mut registries = self.registries.lock().unwrap();
+        registries.retain(|server, _| {
+            servers.contains(server) || skipped.iter().any(|(s, _)| s == server)
+        });
+        for server in servers.iter() {
+            registries.entry(server.clone()).or_default().skipped = None;
+        }
+        for (server, reason) in skipped.iter() {
+            registries.entry(server.clone()).or_default().skipped = Some(reason.clone());
+        }
+    }
+
+    /// Record the outcome of syncing `ns`: the registries it should have,
+    /// with the error of the failed ones. `ns` is dropped from all others.
+    pub fn record(&self, ns: &str, outcomes: &BTreeMap<String, Option<String>>) {
+        let mut registries = self.registries.lock().unwrap();
+        for (server, entry) in registries.iter_mut() {
+            match outcomes.get(server) {
+                Some(None) => {
+                    entry.failed.remove(ns);
+                    entry.synced.insert(ns.to_string());
+                }
+                Some(Some(e)) => {
+                    entry.synced.remove(ns);
+                    entry.failed.insert(ns.to_string(), e.clone());
+                    entry.last_error = Some(e.clone());
+                }
+                None => {
+                    entry.synced.remove(ns);
+                    entry.failed.remove(ns);
+                }
+            }
+        }
+    }
+
+    pub fn remove_ns(&self, ns: &str) {
+        self.record(ns, &BTreeMap::new());
+    }
+
+    pub fn summary(&self) -> BTreeMap<String, RegistryStatus> {
+        let registries = self.registries.lock().unwrap();
+        registries
+            .iter()
+            .map(|(server, e)| {
+                let status = RegistryStatus {
+                    synced: e.synced.len(),
+                    failed: e.failed.len(),
+                    failed_namespaces: e.failed.keys().cloned().collect(),
+                    skipped: e.skipped.clone(),
+                    last_error: e.last_error.clone(),
+                };
+                (server.clone(), status)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{add_cluster, Coverage};
+    use std::collections::BTreeMap;
+
+    fn outcomes(list: &[(&str, Option<&str>)]) -> BTreeMap<String, Option<String>> {
+        list.iter()
+            .map(|(s, e)| (s.to_string(), e.map(String::from)))
+            .collect()
+    }
+
+    #[test]
+    fn coverage() {
+        let c = Coverage::new();
+        c.set_configs(
+            &["a.io".to_string(), "b.io".to_string()],
+            &[("c.io".to_string(), "vault sealed".to_string())],
+        );
+        c.record("team-a", &outcomes(&[("a.io", None), ("b.io", None)]));
+        c.record("team-b", &outcomes(&[("a.io", Some("forbidden"))]));
+
+        let s = c.summary();
+        assert_eq!((s["a.io"].synced, s["a.io"].failed), (1, 1));
+        assert_eq!(s["a.io"].failed_namespaces, vec!["team-b"]);
+        assert_eq!(s["a.io"].last_error.as_deref(), Some("forbidden"));
+        assert_eq!(s["c.io"].skipped.as_deref(), Some("vault sealed"));
+
+        // fixed, b.io no longer matches team-a
+        c.record("team-b", &outcomes(&[("a.io", None)]));
+        c.record("team-a", &outcomes(&[("a.io", None)]));
+        let s = c.summary();
+        assert_eq!((s["a.io"].synced, s["a.io"].failed), (2, 0));
+        assert_eq!(s["b.io"].synced, 0);
+
+        c.remove_ns("team-a");
+        c.set_configs(&["a.io".to_string()], &[]);
+        let s = c.summary();
+        assert_eq!(s.keys().collect::<Vec<_>>(), vec!["a.io"]);
+        assert_eq!(s["a.io"].synced, 1);
+    }
+
+    #[test]
+    fn clusters() {
+        let local = Coverage::new();
+        local.set_configs(&["a.io".to_string()], &[]);
+        local.record("team-a", &outcomes(&[("a.io", None)]));
+        let edge = Coverage::new();
+        edge.set_configs(&["a.io".to_string(), "b.io".to_string()], &[]);
+        edge.record("team-a", &outcomes(&[("a.io", Some("forbidden"))]));
+        edge.record("team-b", &outcomes(&[("a.io", None), ("b.io", None)]));
+
+        let mut total = local.summary();
+        add_cluster(&mut total, "edge", &edge.summary());
+        assert_eq!((total["a.io"].synced, total["a.io"].failed), (2, 1));
+        assert_eq!(total["a.io"].failed_namespaces, vec!["edge/team-a"]);
+        assert_eq!(total["a.io"].last_error.as_deref(), Some("forbidden"));
+        assert_eq!(total["b.io"].synced, 1);
+    }
+}
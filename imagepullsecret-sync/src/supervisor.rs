@@ -0,0 +1,220 @@
+use crate::metrics::Metrics;
+use anyhow::{anyhow, Result};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Exponential backoff between restarts, doubling up to `max`.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// The delay before the next restart.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// Wait the longest delay from now on.
+    pub fn max_out(&mut self) {
+        self.current = self.max;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(INITIAL_BACKOFF, MAX_BACKOFF)
+    }
+}
+
+/// Errors no restart can fix, e.g. missing RBAC permissions, end the process
+/// instead of retrying forever.
+pub fn is_fatal(e: &anyhow::Error) -> bool {
+    let kube_err = match e.downcast_ref::<kube_runtime::watcher::Error>() {
+        Some(kube_runtime::watcher::Error::InitialListFailed { source, .. })
+        | Some(kube_runtime::watcher::Error::WatchStartFailed { source, .. }) => Some(source),
+        Some(_) => None,
+        None => e.downcast_ref::<kube::Error>(),
+    };
+    matches!(kube_err, Some(kube::Error::Api(e)) if e.code == 401 || e.code == 403)
+}
+
+/// Runs a long lived task, e.g. a watcher, and restarts it with backoff when
+/// it fails or ends, its state is reported by the health endpoints.
+pub struct Supervisor {
+    metrics: Arc<Metrics>,
+    backoff: Backoff,
+    cluster: Option<String>,
+}
+
+impl Supervisor {
+    pub fn new(metrics: Arc<Metrics>, backoff: Backoff) -> Self {
+        Supervisor {
+            metrics,
+            backoff,
+            cluster: None,
+        }
+    }
+
+    /// Supervise the tasks of the --clusters context `cluster`, their fatal
+    /// errors are retried too so one cluster can't stop the others.
+    pub fn for_cluster(self, cluster: &str) -> Self {
+        Supervisor {
+            cluster: Some(cluster.to_string()),
+            ..self
+        }
+    }
+
+    /// Run `task` until it fails fatally, which is returned.
+    pub async fn run<F, Fut>(&self, name: &'static str, task: F) -> Result<()>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let label = match &self.cluster {
+            Some(cluster) => format!("{}/{}", cluster, name),
+            None => name.to_string(),
+        };
+        let mut backoff = self.backoff.clone();
+        loop {
+            let started = Instant::now();
+            let result = {
+                let _alive = self.metrics.health.alive(name);
+                task().await
+            };
+
+            let err = match result {
+                Ok(()) => anyhow!("stream ended"),
+                Err(e) if is_fatal(&e) && self.cluster.is_none() => {
+                    error!("{} failed fatally: {:#}", name, e);
+                    return Err(e.context(format!("{} failed", name)));
+                }
+                Err(e) if is_fatal(&e) => {
+                    // retry at the longest delay until the cluster's RBAC is fixed
+                    error!("{} failed: {:#}", label, e);
+                    backoff.max_out();
+                    e
+                }
+                Err(e) => e,
+            };
+
+            // a task which ran for a while failed on its own, not because of
+            // the previous failure
+            if started.elapsed() > self.backoff.max {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            warn!("{} failed: {:#}, restart in {:?}", label, err, delay);
+            self.metrics.health.restarted(name, format!("{:#}", err));
+            self.metrics.watch_restarted(name);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_fatal, Backoff, Supervisor};
+    use crate::metrics::{Metrics, WATCH_NS};
+    use anyhow::anyhow;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    fn api_error(code: u16) -> kube::Error {
+        kube::Error::Api(kube::error::ErrorResponse {
+            status: "Failure".to_string(),
+            message: "denied".to_string(),
+            reason: "Forbidden".to_string(),
+            code,
+        })
+    }
+
+    #[test]
+    fn backoff() {
+        let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let delays: Vec<u64> = (0..5).map(|_| b.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        b.reset();
+        assert_eq!(b.next_delay().as_secs(), 1);
+        b.max_out();
+        assert_eq!(b.next_delay().as_secs(), 5);
+    }
+
+    #[test]
+    fn fatal() {
+        assert!(is_fatal(&api_error(403).into()));
+        assert!(!is_fatal(&api_error(500).into()));
+        assert!(!is_fatal(&anyhow!("connection reset")));
+    }
+
+    #[tokio::test]
+    async fn restarts_until_fatal() {
+        let metrics = Arc::new(Metrics::new());
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        let supervisor = Supervisor::new(metrics.clone(), backoff);
+        let runs = AtomicUsize::new(0);
+
+        let result = supervisor
+            .run(WATCH_NS, || async {
+                match runs.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(anyhow!("connection reset")),
+                    1 => Ok(()),
+                    _ => Err(api_error(403).into()),
+                }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        let state = metrics.health.states()[WATCH_NS].clone();
+        assert_eq!(state.restarts, 2);
+        assert!(!state.alive);
+    }
+
+    #[tokio::test]
+    async fn cluster_tasks_are_not_fatal() {
+        let metrics = Arc::new(Metrics::for_cluster("edge"));
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        let supervisor = Supervisor::new(metrics.clone(), backoff).for_cluster("edge");
+        let runs = AtomicUsize::new(0);
+
+        let result = tokio::time::timeout(
+            Duration::from_millis(200),
+            supervisor.run(WATCH_NS, || async {
+                runs.fetch_add(1, Ordering::SeqCst);
+                Err(api_error(403).into())
+            }),
+        )
+        .await;
+
+        assert!(result.is_err(), "still running");
+        assert!(runs.load(Ordering::SeqCst) > 1);
+    }
+}
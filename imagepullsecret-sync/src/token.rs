@@ -0,0 +1,283 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use tokio::process::Command;
+
+pub const DEFAULT_TOKEN_TTL: u64 = 3600;
+const EXEC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A short-lived registry credential.
+#[derive(Clone, PartialEq)]
+pub struct Token {
+    pub username: String,
+    pub password: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Token")
+            .field("username", &self.username)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// Issues short-lived registry tokens, e.g. ECR or GCR access tokens.
+#[async_trait]
+pub trait TokenProvider: Send + Sync {
+    async fn fetch(&self) -> Result<Token>;
+}
+
+/// Runs a command and reads the token from its stdout. The output is either
+/// a json object `{"username": "..", "password": "..", "expires_at": "<rfc3339>"}`
+/// or the plain password, e.g. of `aws ecr get-login-password`.
+#[derive(Debug, Clone)]
+pub struct ExecProvider {
+    pub command: String,
+    pub args: Vec<String>,
+    // used when the output has no username
+    pub username: Option<String>,
+    // used when the output has no expiry
+    pub ttl: Duration,
+}
+
+#[derive(Deserialize)]
+struct ExecOutput {
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(alias = "token")]
+    password: String,
+    #[serde(default, alias = "expiresAt")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
+impl TokenProvider for ExecProvider {
+    async fn fetch(&self) -> Result<Token> {
+        let output = tokio::time::timeout(
+            EXEC_TIMEOUT,
+            Command::new(&self.command)
+                .args(&self.args)
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| anyhow!("exec '{}' timed out", self.command))?
+        .with_context(|| format!("exec '{}'", self.command))?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "exec '{}' failed with {}: {}",
+                self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let stdout = String::from_utf8(output.stdout)
+            .with_context(|| format!("exec '{}' output", self.command))?;
+        self.parse(stdout.trim(), Utc::now())
+    }
+}
+
+impl ExecProvider {
+    fn parse(&self, stdout: &str, now: DateTime<Utc>) -> Result<Token> {
+        let out = if stdout.starts_with('{') {
+            serde_json::from_str(stdout)
+                .with_context(|| format!("decode output of '{}'", self.command))?
+        } else {
+            ExecOutput {
+                username: None,
+                password: stdout.to_string(),
+                expires_at: None,
+            }
+        };
+
+        if out.password.is_empty() {
+            return Err(anyhow!("exec '{}' printed an empty token", self.command));
+        }
+        let username = out
+            .username
+            .or_else(|| self.username.clone())
+            .ok_or_else(|| anyhow!("exec '{}' printed no username", self.command))?;
+
+        Ok(Token {
+            username,
+            password: out.password,
+            expires_at: out.expires_at.unwrap_or(now + self.ttl),
+        })
+    }
+}
+
+/// Caches tokens until `refresh_before` their expiry, so namespace events
+/// don't issue new tokens and the refresh scheduler knows when to run. The
+/// lead is capped at half the lifetime of a token, so a token shorter lived
+/// than `refresh_before` is used for a while too.
+pub struct TokenCache {
+    refresh_before: Duration,
+    // the tokens with the time they are due for refresh
+    tokens: Mutex<HashMap<String, (Token, DateTime<Utc>)>>,
+    retry: AtomicBool,
+}
+
+impl TokenCache {
+    pub fn new(refresh_before: Duration) -> Self {
+        TokenCache {
+            refresh_before,
+            tokens: Mutex::new(HashMap::new()),
+            retry: AtomicBool::new(false),
+        }
+    }
+
+    /// The cached token of `key`, fetched again from `provider` when it is
+    /// missing or due for refresh.
+    pub async fn token(&self, key: &str, provider: &dyn TokenProvider) -> Result<Token> {
+        let now = Utc::now();
+        if let Some((t, refresh_at)) = self.tokens.lock().unwrap().get(key) {
+            if *refresh_at > now {
+                return Ok(t.clone());
+            }
+        }
+
+        match provider.fetch().await {
+            Ok(t) => {
+                let refresh_at = self.refresh_at(&t, Utc::now());
+                debug!(
+                    "fetched token for '{}', expires at {}, refresh at {}",
+                    key, t.expires_at, refresh_at
+                );
+                self.tokens
+                    .lock()
+                    .unwrap()
+                    .insert(key.to_string(), (t.clone(), refresh_at));
+                Ok(t)
+            }
+            Err(e) => {
+                self.retry.store(true, Ordering::SeqCst);
+                Err(e)
+            }
+        }
+    }
+
+    // refresh `refresh_before` the expiry, but not before half the lifetime
+    // left at `fetched`
+    fn refresh_at(&self, t: &Token, fetched: DateTime<Utc>) -> DateTime<Utc> {
+        let lifetime = (t.expires_at - fetched).max(Duration::zero());
+        t.expires_at - self.refresh_before.min(lifetime / 2)
+    }
+
+    /// When the next cached token is due for refresh.
+    pub fn next_refresh(&self) -> Option<DateTime<Utc>> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(_, refresh_at)| *refresh_at)
+            .min()
+    }
+
+    /// Drop the tokens due for refresh at `now`, returns whether a refresh
+    /// is needed because tokens were dropped or a fetch failed before.
+    pub fn expire_due(&self, now: DateTime<Utc>) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let before = tokens.len();
+        tokens.retain(|_, (_, refresh_at)| *refresh_at > now);
+
+        let retry = self.retry.swap(false, Ordering::SeqCst);
+        tokens.len() != before || retry
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ExecProvider, Token, TokenCache, TokenProvider};
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use chrono::{Duration, TimeZone, Utc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn exec(script: &str) -> ExecProvider {
+        ExecProvider {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            username: Some("AWS".to_string()),
+            ttl: Duration::seconds(600),
+        }
+    }
+
+    #[tokio::test]
+    async fn exec_plain_and_json() {
+        let t = exec("echo s3cret").fetch().await.unwrap();
+        assert_eq!(
+            (t.username.as_str(), t.password.as_str()),
+            ("AWS", "s3cret")
+        );
+        assert!(t.expires_at > Utc::now() + Duration::seconds(590));
+
+        let t = exec(r#"echo '{"username":"oauth2accesstoken","token":"abc","expires_at":"2030-01-01T00:00:00Z"}'"#)
+            .fetch()
+            .await
+            .unwrap();
+        assert_eq!(t.username, "oauth2accesstoken");
+        assert_eq!(t.password, "abc");
+        assert_eq!(t.expires_at, Utc.ymd(2030, 1, 1).and_hms(0, 0, 0));
+
+        assert!(exec("echo oops >&2; exit 3").fetch().await.is_err());
+        assert!(exec("true").fetch().await.is_err());
+    }
+
+    struct Counting(AtomicUsize, Duration);
+
+    #[async_trait]
+    impl TokenProvider for Counting {
+        async fn fetch(&self) -> Result<Token> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Token {
+                username: "u".to_string(),
+                password: format!("token-{}", n),
+                expires_at: Utc::now() + self.1,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_refresh() {
+        let cache = TokenCache::new(Duration::seconds(60));
+        let long = Counting(AtomicUsize::new(0), Duration::hours(1));
+
+        assert_eq!(cache.token("a", &long).await.unwrap().password, "token-0");
+        assert_eq!(cache.token("a", &long).await.unwrap().password, "token-0");
+        let next = cache.next_refresh().unwrap();
+        assert!(next > Utc::now() + Duration::minutes(58));
+        assert!(!cache.expire_due(Utc::now()));
+
+        // shorter lived than the refresh lead, refreshed after half its lifetime
+        let short = Counting(AtomicUsize::new(0), Duration::seconds(30));
+        cache.token("b", &short).await.unwrap();
+        assert_eq!(cache.token("b", &short).await.unwrap().password, "token-0");
+        let half = cache.next_refresh().unwrap();
+        assert!(half > Utc::now() + Duration::seconds(10));
+        assert!(half < Utc::now() + Duration::seconds(16));
+        assert!(!cache.expire_due(Utc::now()));
+        assert!(cache.expire_due(Utc::now() + Duration::seconds(16)));
+        assert_eq!(cache.next_refresh(), Some(next));
+
+        // already expired when fetched
+        let expired = Counting(AtomicUsize::new(0), Duration::seconds(-5));
+        cache.token("c", &expired).await.unwrap();
+        assert_eq!(
+            cache.token("c", &expired).await.unwrap().password,
+            "token-1"
+        );
+        assert!(cache.next_refresh().unwrap() < Utc::now());
+    }
+}
@@ -0,0 +1,347 @@
+use crate::{config::Config, settings::Settings};
+use std::{collections::BTreeMap, fmt, fs, path::PathBuf};
+use structopt::StructOpt;
+use thiserror::Error;
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+/// A mistake in the registry configs, with its 1-based position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+/// Every mistake found in the registry configs.
+#[derive(Debug, Clone, PartialEq, Error)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        f.write_str(&errors.join("; "))
+    }
+}
+
+/// Parse and validate the registry configs YAML: unknown fields, duplicate
+/// servers, missing credentials, invalid namespace patterns and what the
+/// settings don't allow, e.g. token ttls within --token-refresh-before, are
+/// errors.
+pub fn parse_configs(data: &[u8], settings: &Settings) -> Result<Vec<Config>, ConfigErrors> {
+    let configs: Vec<Config> = serde_yaml::from_slice(data).map_err(|e| {
+        let (line, column) = e.location().map_or((0, 0), |l| (l.line(), l.column()));
+        // the position is reported separately
+        let suffix = format!(" at line {} column {}", line, column);
+        let message = e.to_string().replace(&suffix, "");
+        ConfigErrors(vec![ConfigError {
+            line,
+            column,
+            message,
+        }])
+    })?;
+
+    // yaml-rust already accepted what serde_yaml parsed
+    let mut positions = Positions::default();
+    let text = String::from_utf8_lossy(data);
+    let _ = Parser::new(text.chars()).load(&mut positions, false);
+
+    let mut errors = Vec::new();
+    let mut servers: BTreeMap<String, usize> = BTreeMap::new();
+    for (i, cfg) in configs.iter().enumerate() {
+        let at = |field: &str| positions.at(i, field);
+
+        for (field, message) in cfg.validate(settings) {
+            let (line, column) = at(field);
+            errors.push(ConfigError {
+                line,
+                column,
+                // e.g. regex errors span several lines
+                message: message.split_whitespace().collect::<Vec<_>>().join(" "),
+            });
+        }
+
+        let server = cfg.server.trim().trim_end_matches('/').to_lowercase();
+        if server.is_empty() {
+            continue;
+        }
+        let (line, column) = at("server");
+        match servers.get(&server) {
+            Some(first) => errors.push(ConfigError {
+                line,
+                column,
+                message: format!(
+                    "duplicate server '{}', first defined at line {}",
+                    cfg.server, first
+                ),
+            }),
+            None => {
+                servers.insert(server, line);
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(configs)
+    } else {
+        Err(ConfigErrors(errors))
+    }
+}
+
+// the positions of the configs and their fields, from the yaml events
+#[derive(Default)]
+struct Positions {
+    items: Vec<(Marker, BTreeMap<String, Marker>)>,
+    depth: usize,
+    expect_key: bool,
+}
+
+impl Positions {
+    // (line, column) of `field` of config `i`, else of the config itself
+    fn at(&self, i: usize, field: &str) -> (usize, usize) {
+        match self.items.get(i) {
+            Some((start, fields)) => {
+                let mark = fields.get(field).unwrap_or(start);
+                (mark.line(), mark.col() + 1)
+            }
+            None => (0, 0),
+        }
+    }
+}
+
+impl MarkedEventReceiver for Positions {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::MappingStart(_) | Event::SequenceStart(_) => {
+                if self.depth == 1 {
+                    self.items.push((mark, BTreeMap::new()));
+                    self.expect_key = true;
+                }
+                self.depth += 1;
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.depth -= 1;
+                if self.depth == 2 {
+                    self.expect_key = true;
+                }
+            }
+            Event::Scalar(value, ..) if self.depth == 2 => {
+                if self.expect_key {
+                    if let Some((_, fields)) = self.items.last_mut() {
+                        fields.insert(value, mark);
+                    }
+                }
+                self.expect_key = !self.expect_key;
+            }
+            Event::Alias(_) if self.depth == 2 => self.expect_key = !self.expect_key,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ValidateArgs {
+    /// Registry configs YAML files, like the registry_secrets key of the config secret
+    #[structopt(required = true, parse(from_os_str))]
+    pub files: Vec<PathBuf>,
+}
+
+/// Validate the files under the settings and print the errors compiler
+/// style to stdout, returns the process exit code.
+pub fn run(args: &ValidateArgs, settings: &Settings) -> i32 {
+    let mut code = 0;
+    for path in args.files.iter() {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("{}: {}", path.display(), e);
+                code = 1;
+                continue;
+            }
+        };
+        match parse_configs(&data, settings) {
+            Ok(configs) => println!("{}: {} registries ok", path.display(), configs.len()),
+            Err(ConfigErrors(errors)) => {
+                for e in errors {
+                    println!("{}:{}:{}: {}", path.display(), e.line, e.column, e.message);
+                }
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_configs, ConfigError};
+    use crate::settings::Settings;
+
+    fn errors(yaml: &str) -> Vec<String> {
+        errors_with(yaml, &Settings::default())
+    }
+
+    fn errors_with(yaml: &str, settings: &Settings) -> Vec<String> {
+        parse_configs(yaml.as_bytes(), settings)
+            .unwrap_err()
+            .0
+            .iter()
+            .map(ConfigError::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid() {
+        let configs = parse_configs(
+            include_bytes!("../registry_secrets.yaml"),
+            &Settings::default(),
+        )
+        .unwrap();
+        assert_eq!(configs.len(), 3);
+    }
+
+    #[test]
+    fn unknown_field() {
+        let yaml = "- server: a.io\n  username: u\n  password: p\n  namespace: [default]\n";
+        let errs = errors(yaml);
+        assert_eq!(errs.len(), 1);
+        assert!(errs[0].starts_with("line 4, column 3: "), "{}", errs[0]);
+        assert!(errs[0].contains("unknown field `namespace`"), "{}", errs[0]);
+    }
+
+    #[test]
+    fn semantic_errors() {
+        let yaml = r#"
+- server: a.io
+  username: u
+  password: p
+  namespaces: ["*"]
+
+- server: b.io
+  username: u
+  namespaces:
+    - "/(/"
+
+- server: A.io/
+  credentials:
+    secret_ref:
+      namespace: default
+      name: ""
+  namespace_selector: "team in (a"
+"#;
+        assert_eq!(
+            errors(yaml),
+            vec![
+                "line 7, column 3: username and password or credentials are required",
+                "line 9, column 3: invalid namespace regex '/(/': regex parse error: ( ^ error: unclosed group",
+                "line 13, column 3: credentials name must not be empty",
+                "line 17, column 3: invalid namespace_selector 'team in (a': unbalanced '(' in selector 'team in (a'",
+                "line 12, column 3: duplicate server 'A.io/', first defined at line 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn token_ttl() {
+        let yaml = r#"
+- server: ecr.io
+  credentials:
+    exec:
+      command: aws
+      ttl: 300
+  namespaces: ["*"]
+"#;
+        let settings = Settings {
+            credential_sources: vec!["exec".to_string()],
+            ..Settings::default()
+        };
+        assert_eq!(
+            errors_with(yaml, &settings),
+            vec!["line 3, column 3: credentials ttl 300s must be longer than the token refresh lead of 300s"]
+        );
+        let settings = Settings {
+            token_refresh_before: 60,
+            ..settings
+        };
+        assert!(parse_configs(yaml.as_bytes(), &settings).is_ok());
+    }
+
+    #[test]
+    fn opt_in_sources() {
+        let yaml = r#"
+- server: ecr.io
+  credentials:
+    exec:
+      command: aws
+  namespaces: ["*"]
+"#;
+        assert_eq!(
+            errors(yaml),
+            vec!["line 3, column 3: credentials source exec is disabled, it can be enabled with --credential-sources"]
+        );
+    }
+
+    #[test]
+    fn credential_namespaces() {
+        let yaml = r#"
+- server: a.io
+  credentials:
+    secret_ref: { namespace: kube-system, name: admin-token }
+  namespaces: ["*"]
+- server: b.io
+  credentials:
+    secret_ref: { namespace: infra, name: b }
+  namespaces: ["*"]
+"#;
+        let settings = Settings {
+            credential_namespaces: vec!["infra".to_string()],
+            ..Settings::default()
+        };
+        assert_eq!(
+            errors_with(yaml, &settings),
+            vec!["line 3, column 3: credentials namespace 'kube-system' is not allowed, only the config namespace default or the --credential-namespaces are"]
+        );
+    }
+
+    #[test]
+    fn credential_dir() {
+        let yaml = r#"
+- server: a.io
+  credentials:
+    file:
+      username_path: /creds/a/username
+      password_path: /var/run/secrets/kubernetes.io/serviceaccount/token
+  namespaces: ["*"]
+- server: b.io
+  credentials:
+    file:
+      username_path: /creds/b/username
+      password_path: /creds/../proc/self/environ
+  namespaces: ["*"]
+"#;
+        let settings = Settings {
+            credential_sources: vec!["file".to_string()],
+            credential_dir: Some("/creds".into()),
+            ..Settings::default()
+        };
+        assert_eq!(
+            errors_with(yaml, &settings),
+            vec![
+                "line 3, column 3: credentials path '/var/run/secrets/kubernetes.io/serviceaccount/token' must be below the --credential-dir /creds",
+                "line 9, column 3: credentials path '/creds/../proc/self/environ' must be below the --credential-dir /creds",
+            ]
+        );
+    }
+}
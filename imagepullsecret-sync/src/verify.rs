@@ -0,0 +1,258 @@
+use crate::config::Config;
+use reqwest::{header::WWW_AUTHENTICATE, StatusCode};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+use thiserror::Error;
+
+// per request, a registry slower than this counts as unavailable
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum VerifyError {
+    /// The registry refused the credentials, they must not be rolled out.
+    #[error("registry '{server}' rejected the credentials: {message}")]
+    Rejected { server: String, message: String },
+
+    /// The credentials could not be checked.
+    #[error("registry '{server}' unavailable: {message}")]
+    Unavailable { server: String, message: String },
+}
+
+/// Checks registry credentials with the Docker Registry v2 handshake: an
+/// anonymous `GET /v2/` answers with the challenge, which is completed with
+/// Basic auth or a bearer token from the token service.
+pub struct Verifier {
+    http: reqwest::Client,
+    // the fingerprint of the last verified credentials of each server, they
+    // are not checked again until they change
+    verified: Mutex<HashMap<String, u64>>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Verifier {
+            http: reqwest::Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .expect("build http client"),
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Verify the resolved credentials of `cfg`.
+    pub async fn verify(&self, cfg: &Config) -> Result<(), VerifyError> {
+        let fingerprint = crate::credentials::fingerprint(std::slice::from_ref(cfg));
+        if self.verified.lock().unwrap().get(&cfg.server) == Some(&fingerprint) {
+            return Ok(());
+        }
+        Handshake {
+            http: &self.http,
+            server: &cfg.server,
+            base: base_url(&cfg.server),
+        }
+        .run(&cfg.username, &cfg.password)
+        .await?;
+        self.verified
+            .lock()
+            .unwrap()
+            .insert(cfg.server.clone(), fingerprint);
+        Ok(())
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Verifier::new()
+    }
+}
+
+struct Handshake<'a> {
+    http: &'a reqwest::Client,
+    server: &'a str,
+    base: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(default)]
+    access_token: Option<String>,
+}
+
+impl Handshake<'_> {
+    fn rejected(&self, message: impl Into<String>) -> VerifyError {
+        VerifyError::Rejected {
+            server: self.server.to_string(),
+            message: message.into(),
+        }
+    }
+
+    fn unavailable(&self, message: impl Into<String>) -> VerifyError {
+        VerifyError::Unavailable {
+            server: self.server.to_string(),
+            message: message.into(),
+        }
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response, VerifyError> {
+        req.send()
+            .await
+            .map_err(|e| self.unavailable(e.to_string()))
+    }
+
+    // the outcome of an authenticated request to `url`
+    fn check(&self, url: &str, status: StatusCode) -> Result<(), VerifyError> {
+        match status {
+            s if s.is_success() => Ok(()),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                Err(self.rejected(format!("GET {} returned {}", url, status)))
+            }
+            s => Err(self.unavailable(format!("GET {} returned {}", url, s))),
+        }
+    }
+
+    async fn run(&self, username: &str, password: &str) -> Result<(), VerifyError> {
+        let url = format!("{}/v2/", self.base);
+        let resp = self.send(self.http.get(&url)).await?;
+        match resp.status() {
+            // an open registry accepts any credentials, like `docker login`
+            s if s.is_success() => return Ok(()),
+            StatusCode::UNAUTHORIZED => {}
+            s => return Err(self.unavailable(format!("GET {} returned {}", url, s))),
+        }
+        let header = resp
+            .headers()
+            .get(WWW_AUTHENTICATE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let (scheme, params) = challenge(header)
+            .ok_or_else(|| self.unavailable(format!("GET {} returned no challenge", url)))?;
+
+        match scheme.as_str() {
+            "basic" => {
+                let req = self.http.get(&url).basic_auth(username, Some(password));
+                let resp = self.send(req).await?;
+                self.check(&url, resp.status())
+            }
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .ok_or_else(|| self.unavailable("bearer challenge without realm"))?;
+                let query: Vec<(&str, &String)> = ["service", "scope"]
+                    .iter()
+                    .filter_map(|k| params.get(*k).map(|v| (*k, v)))
+                    .collect();
+                let req = self
+                    .http
+                    .get(realm)
+                    .query(&query)
+                    .basic_auth(username, Some(password));
+                let resp = self.send(req).await?;
+                self.check(realm, resp.status())?;
+                let body: TokenResponse = resp
+                    .json()
+                    .await
+                    .map_err(|e| self.unavailable(format!("decode token: {}", e)))?;
+                let token = body
+                    .token
+                    .or(body.access_token)
+                    .ok_or_else(|| self.unavailable("token response without token"))?;
+
+                let resp = self.send(self.http.get(&url).bearer_auth(token)).await?;
+                self.check(&url, resp.status())
+            }
+            other => Err(self.unavailable(format!("unsupported auth scheme '{}'", other))),
+        }
+    }
+}
+
+/// The registry API endpoint of a config server, https unless the server
+/// says http. Docker Hub servers are served by registry-1.docker.io.
+pub fn base_url(server: &str) -> String {
+    let s = server.trim();
+    let (scheme, rest) = match s.strip_prefix("http://") {
+        Some(rest) => ("http", rest),
+        None => ("https", s.strip_prefix("https://").unwrap_or(s)),
+    };
+    let host = rest.split('/').next().unwrap_or_default().to_lowercase();
+    match host.as_str() {
+        "docker.io" | "index.docker.io" => "https://registry-1.docker.io".to_string(),
+        _ => format!("{}://{}", scheme, host),
+    }
+}
+
+/// The lower case scheme and the parameters of a `WWW-Authenticate` header,
+/// e.g. `Bearer realm="https://auth.example.com/token",service="registry"`.
+pub fn challenge(header: &str) -> Option<(String, BTreeMap<String, String>)> {
+    let header = header.trim();
+    let (scheme, rest) = header.split_once(' ').unwrap_or((header, ""));
+    if scheme.is_empty() {
+        return None;
+    }
+    let mut params = BTreeMap::new();
+    let mut rest = rest.trim();
+    while let Some((key, value)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let value = value.trim_start();
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => value.split_at(value.find(',').unwrap_or(value.len())),
+        };
+        params.insert(key, value.to_string());
+        rest = tail.trim_start().trim_start_matches(',');
+    }
+    Some((scheme.to_lowercase(), params))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{base_url, challenge};
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(
+            base_url("registry.example.com"),
+            "https://registry.example.com"
+        );
+        assert_eq!(
+            base_url("http://127.0.0.1:5000/team/"),
+            "http://127.0.0.1:5000"
+        );
+        assert_eq!(
+            base_url("https://index.docker.io/v1/"),
+            "https://registry-1.docker.io"
+        );
+        assert_eq!(base_url("docker.io"), "https://registry-1.docker.io");
+    }
+
+    #[test]
+    fn challenges() {
+        let (scheme, params) = challenge(
+            r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:a/b:pull,push""#,
+        )
+        .unwrap();
+        assert_eq!(scheme, "bearer");
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:a/b:pull,push");
+
+        let (scheme, params) = challenge(r#"Basic realm="Registry Realm""#).unwrap();
+        assert_eq!(scheme, "basic");
+        assert_eq!(params["realm"], "Registry Realm");
+
+        let (_, params) = challenge("Bearer realm=http://auth/token, service=quay.io").unwrap();
+        assert_eq!(params["realm"], "http://auth/token");
+        assert_eq!(params["service"], "quay.io");
+
+        assert!(challenge("").is_none());
+        assert!(challenge(r#"Bearer realm="unterminated"#).is_none());
+    }
+}
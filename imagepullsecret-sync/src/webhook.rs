@@ -0,0 +1,462 @@
+use crate::{crd::RegistryCredential, inject::Injector, settings::Settings, validate};
+use anyhow::{Context, Result};
+use hyper::{
+    body::HttpBody, header::CONTENT_LENGTH, server::conn::Http, service::service_fn, Body, Method,
+    Request, Response, StatusCode,
+};
+use openssl::{pkcs12::Pkcs12, pkey::PKey, x509::X509};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    convert::Infallible,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio::net::TcpListener;
+use tokio_native_tls::TlsAcceptor;
+
+pub const VALIDATE_PATH: &str = "/validate";
+pub const MUTATE_PATH: &str = "/mutate";
+
+// the api server sends at most 3MiB objects
+const MAX_BODY: u64 = 4 << 20;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionReview {
+    api_version: String,
+    request: AdmissionRequest,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AdmissionRequest {
+    uid: String,
+    kind: GroupVersionKind,
+    #[serde(default)]
+    namespace: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    // null on DELETE
+    #[serde(default)]
+    object: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct GroupVersionKind {
+    #[serde(default)]
+    group: String,
+    kind: String,
+}
+
+/// The TLS acceptor of the PEM certificate chain and private key files, e.g.
+/// the `tls.crt` and `tls.key` of a mounted `kubernetes.io/tls` secret. It is
+/// rebuilt when either file changes, so a renewed certificate is served
+/// without a restart.
+pub struct ReloadingAcceptor {
+    cert: PathBuf,
+    key: PathBuf,
+    // the modification times the acceptor was built from
+    current: Mutex<(Modified, Arc<TlsAcceptor>)>,
+}
+
+// of the cert and the key, None when either can't be read
+type Modified = Option<(SystemTime, SystemTime)>;
+
+impl ReloadingAcceptor {
+    pub fn new(cert: &Path, key: &Path) -> Result<Self> {
+        let modified = modified(cert, key);
+        let acceptor = tls_acceptor(cert, key)?;
+        Ok(ReloadingAcceptor {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            current: Mutex::new((modified, Arc::new(acceptor))),
+        })
+    }
+
+    // the acceptor of the current files, a broken update keeps the previous
+    // one until the files change again, e.g. once the key follows the cert
+    fn acceptor(&self) -> Arc<TlsAcceptor> {
+        let modified = modified(&self.cert, &self.key);
+        let mut current = self.current.lock().unwrap();
+        if modified != current.0 {
+            current.0 = modified;
+            match tls_acceptor(&self.cert, &self.key) {
+                Ok(acceptor) => {
+                    info!("reloaded the webhook certificate {:?}", self.cert);
+                    current.1 = Arc::new(acceptor);
+                }
+                Err(e) => warn!("reload the webhook certificate err: {:#}", e),
+            }
+        }
+        current.1.clone()
+    }
+}
+
+fn modified(cert: &Path, key: &Path) -> Modified {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    Some((modified(cert)?, modified(key)?))
+}
+
+fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let chain = fs::read(cert).with_context(|| format!("read webhook cert {:?}", cert))?;
+    let key = fs::read(key).with_context(|| format!("read webhook key {:?}", key))?;
+
+    // native-tls only takes pkcs12, like the client identity of kube
+    let mut certs = X509::stack_from_pem(&chain).context("parse webhook cert")?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificate in webhook cert {:?}", cert));
+    }
+    let leaf = certs.remove(0);
+    let mut ca = openssl::stack::Stack::new()?;
+    for c in certs {
+        ca.push(c)?;
+    }
+    let pkey = PKey::private_key_from_pem(&key).context("parse webhook key")?;
+    let p12 = Pkcs12::builder()
+        .name("imagepullsecret-sync")
+        .pkey(&pkey)
+        .cert(&leaf)
+        .ca(ca)
+        .build2("")?;
+
+    let identity = native_tls::Identity::from_pkcs12(&p12.to_der()?, "")?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+/// The admission webhook: it validates the configs on `/validate` and, with
+/// an injector, adds the registry secrets to new Pods on `/mutate`.
+pub struct Webhook {
+    settings: Settings,
+    injector: Option<Injector>,
+}
+
+impl Webhook {
+    pub fn new(settings: Settings) -> Self {
+        Webhook {
+            settings,
+            injector: None,
+        }
+    }
+
+    pub fn with_injector(self, injector: Injector) -> Self {
+        Webhook {
+            injector: Some(injector),
+            ..self
+        }
+    }
+
+    // the AdmissionResponse to `req`, a Pod is never rejected: when its
+    // registries can't be determined it is admitted unchanged
+    async fn mutate(&self, injector: &Injector, req: &AdmissionRequest) -> Value {
+        let allowed = json!({ "uid": req.uid, "allowed": true });
+        if req.object.is_null() || (req.kind.group.as_str(), req.kind.kind.as_str()) != ("", "Pod")
+        {
+            return allowed;
+        }
+        let ns = req.namespace.as_deref().unwrap_or_default();
+        let patch = match serde_json::from_value(req.object.clone()) {
+            Ok(pod) => injector.patch(ns, &pod).await,
+            Err(e) => Err(e.into()),
+        };
+        match patch {
+            Ok(Some(patch)) => {
+                debug!("inject into pod in '{}': {}", ns, patch);
+                json!({
+                    "uid": req.uid,
+                    "allowed": true,
+                    "patchType": "JSONPatch",
+                    "patch": base64::encode(patch.to_string()),
+                })
+            }
+            Ok(None) => allowed,
+            Err(e) => {
+                warn!("inject into pod in '{}' err: {:#}", ns, e);
+                allowed
+            }
+        }
+    }
+
+    fn validate(&self, req: &AdmissionRequest) -> Value {
+        match admit(&self.settings, req) {
+            Ok(()) => json!({ "uid": req.uid, "allowed": true }),
+            Err(message) => {
+                info!(
+                    "rejected {} '{}': {}",
+                    req.kind.kind,
+                    req.name.as_deref().unwrap_or_default(),
+                    message
+                );
+                json!({
+                    "uid": req.uid,
+                    "allowed": false,
+                    "status": { "code": 422, "reason": "Invalid", "message": message },
+                })
+            }
+        }
+    }
+}
+
+/// Serve the admission `webhook` on `listener` with TLS.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: ReloadingAcceptor,
+    webhook: Webhook,
+) -> Result<()> {
+    info!(
+        "serving the admission webhook on {} ...",
+        listener.local_addr()?
+    );
+    let webhook = Arc::new(webhook);
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let (acceptor, webhook) = (acceptor.acceptor(), webhook.clone());
+        tokio::spawn(async move {
+            let tls = match acceptor.accept(tcp).await {
+                Ok(tls) => tls,
+                Err(e) => {
+                    debug!("webhook tls handshake with {} err: {}", peer, e);
+                    return;
+                }
+            };
+            let svc = service_fn(move |req| {
+                let webhook = webhook.clone();
+                async move { Ok::<_, Infallible>(handle(&webhook, req).await) }
+            });
+            if let Err(e) = Http::new().serve_connection(tls, svc).await {
+                debug!("webhook connection with {} err: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(webhook: &Webhook, req: Request<Body>) -> Response<Body> {
+    let injector = match req.uri().path() {
+        VALIDATE_PATH => None,
+        MUTATE_PATH if webhook.injector.is_some() => webhook.injector.as_ref(),
+        _ => return respond(StatusCode::NOT_FOUND, "not found\n"),
+    };
+    if req.method() != Method::POST {
+        return respond(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n");
+    }
+    let too_large = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|len| len > MAX_BODY);
+    if too_large {
+        return respond(StatusCode::PAYLOAD_TOO_LARGE, "payload too large\n");
+    }
+    let body = match read_body(req.into_body()).await {
+        Ok(Some(body)) => body,
+        Ok(None) => return respond(StatusCode::PAYLOAD_TOO_LARGE, "payload too large\n"),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("read body: {}\n", e)),
+    };
+    let review: AdmissionReview = match serde_json::from_slice(&body) {
+        Ok(review) => review,
+        Err(e) => {
+            let message = format!("invalid AdmissionReview: {}\n", e);
+            return respond(StatusCode::BAD_REQUEST, message);
+        }
+    };
+
+    let response = match injector {
+        Some(injector) => webhook.mutate(injector, &review.request).await,
+        None => webhook.validate(&review.request),
+    };
+    // answered in the version it was sent in, v1 or v1beta1
+    let body = json!({
+        "apiVersion": review.api_version,
+        "kind": "AdmissionReview",
+        "response": response,
+    });
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// the body, None when it is longer than MAX_BODY. A chunked body has no
+// Content-Length, so the limit is checked while reading.
+async fn read_body(mut body: Body) -> Result<Option<Vec<u8>>, hyper::Error> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (data.len() + chunk.len()) as u64 > MAX_BODY {
+            return Ok(None);
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(Some(data))
+}
+
+// Ok when the object may be admitted, else why not
+fn admit(settings: &Settings, req: &AdmissionRequest) -> Result<(), String> {
+    if req.object.is_null() {
+        return Ok(());
+    }
+    match (req.kind.group.as_str(), req.kind.kind.as_str()) {
+        ("", "Secret") => {
+            let is_config = req.namespace.as_deref() == Some(&settings.config_namespace)
+                && req.name.as_deref() == Some(&settings.config_name);
+            if is_config {
+                admit_config_secret(settings, &req.object)
+            } else {
+                Ok(())
+            }
+        }
+        (crate::crd::GROUP, crate::crd::KIND) => admit_registry_credential(settings, &req.object),
+        _ => Ok(()),
+    }
+}
+
+fn admit_config_secret(settings: &Settings, secret: &Value) -> Result<(), String> {
+    let key = &settings.config_key;
+    // the api server merges stringData into data before the review, it only
+    // shows up in hand written reviews and wins over data like in the api
+    let data = match (&secret["stringData"][key], &secret["data"][key]) {
+        (Value::String(s), _) => s.as_bytes().to_vec(),
+        (_, Value::String(s)) => {
+            base64::decode(s).map_err(|e| format!("data key '{}' is not base64: {}", key, e))?
+        }
+        _ => return Err(format!("the config secret has no key '{}'", key)),
+    };
+    validate::parse_configs(&data, settings)
+        .map(|_| ())
+        .map_err(|e| format!("invalid registry configs in key '{}': {}", key, e))
+}
+
+fn admit_registry_credential(settings: &Settings, object: &Value) -> Result<(), String> {
+    let item: RegistryCredential =
+        serde_json::from_value(object.clone()).map_err(|e| format!("invalid spec: {}", e))?;
+    let problems = item.validate(settings);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("invalid spec: {}", problems.join("; ")))
+    }
+}
+
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{admit, AdmissionReview};
+    use crate::settings::Settings;
+    use serde_json::json;
+
+    fn review(kind: serde_json::Value, name: &str, object: serde_json::Value) -> AdmissionReview {
+        serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": kind,
+                "namespace": "default",
+                "name": name,
+                "operation": "UPDATE",
+                "object": object,
+            },
+        }))
+        .unwrap()
+    }
+
+    fn secret(name: &str, configs: &str) -> AdmissionReview {
+        review(
+            json!({ "group": "", "version": "v1", "kind": "Secret" }),
+            name,
+            json!({ "data": { "registry_secrets": base64::encode(configs) } }),
+        )
+    }
+
+    #[test]
+    fn config_secret() {
+        let s = Settings::default();
+        let valid = "- server: a.io\n  username: u\n  password: p\n  namespaces: ['*']\n";
+        assert_eq!(admit(&s, &secret("docker-registry", valid).request), Ok(()));
+
+        let invalid = "- server: a.io\n  username: u\n  namespaces: ['*']\n";
+        assert_eq!(
+            admit(&s, &secret("docker-registry", invalid).request),
+            Err(
+                "invalid registry configs in key 'registry_secrets': line 1, column 3: \
+                 username and password or credentials are required"
+                    .to_string()
+            )
+        );
+        // other secrets are none of our business
+        assert_eq!(admit(&s, &secret("other", invalid).request), Ok(()));
+
+        // exec is off unless the operator enables it
+        let exec =
+            "- server: a.io\n  credentials:\n    exec:\n      command: sh\n  namespaces: ['*']\n";
+        let err = admit(&s, &secret("docker-registry", exec).request).unwrap_err();
+        assert!(
+            err.contains("credentials source exec is disabled"),
+            "{}",
+            err
+        );
+        let s = Settings {
+            credential_sources: vec!["exec".to_string()],
+            ..s
+        };
+        assert_eq!(admit(&s, &secret("docker-registry", exec).request), Ok(()));
+
+        let string_data = review(
+            json!({ "group": "", "version": "v1", "kind": "Secret" }),
+            "docker-registry",
+            json!({ "stringData": { "registry_secrets": "- server: a.io\n  nope: 1\n" } }),
+        );
+        let err = admit(&s, &string_data.request).unwrap_err();
+        assert!(err.contains("line 2, column 3: "), "{}", err);
+    }
+
+    #[test]
+    fn registry_credential() {
+        let kind = json!({ "group": "imagepullsecret-sync.io", "version": "v1alpha1", "kind": "RegistryCredential" });
+        let item = |spec| json!({ "metadata": { "name": "a" }, "spec": spec });
+        let s = Settings {
+            credential_namespaces: vec!["infra".to_string()],
+            ..Settings::default()
+        };
+
+        let ok = item(json!({
+            "server": "a.io",
+            "credentials": { "secret_ref": { "namespace": "infra", "name": "a" } },
+            "namespaces": ["team-*"],
+        }));
+        assert_eq!(admit(&s, &review(kind.clone(), "a", ok).request), Ok(()));
+
+        let bad = item(json!({
+            "server": "a.io",
+            "credentials": { "secret_ref": { "namespace": "infra", "name": "a" } },
+            "namespaces": ["/(/"],
+        }));
+        let err = admit(&s, &review(kind.clone(), "a", bad).request).unwrap_err();
+        assert!(
+            err.starts_with("invalid spec: invalid namespace regex '/(/'"),
+            "{}",
+            err
+        );
+
+        let exec = item(json!({
+            "server": "a.io",
+            "credentials": { "exec": { "command": "sh", "args": ["-c", "cat /etc/shadow"] } },
+            "namespaces": ["*"],
+        }));
+        assert_eq!(
+            admit(&s, &review(kind.clone(), "a", exec).request),
+            Err("invalid spec: credentials must be a secret_ref".to_string())
+        );
+
+        let unknown = item(json!({ "server": "a.io", "password": "p" }));
+        assert!(admit(&s, &review(kind, "a", unknown).request).is_err());
+    }
+}
@@ -1,77 +1,1087 @@
-use anyhow::{anyhow, Result};
-use futures::{StreamExt, TryStreamExt};
-use imagepullsecret_sync::config::{Config, RegistryAuth};
-use k8s_openapi::api::core::v1::{LocalObjectReference, Namespace, Secret, ServiceAccount};
+use crate::{
+    audit::{self, AuditEntry, AuditLog, Digests},
+    config::{Config, NamespaceFilter, RegistryAuth},
+    crd::{self, RegistryCredential, Rejected},
+    credentials::{self, CredentialSource},
+    error::{SyncError, SyncResult},
+    events::{self, EventType, Recorder},
+    leader,
+    metrics::{Metrics, WATCH_CONFIG, WATCH_NS, WATCH_REGISTRY_CREDENTIALS},
+    naming::NameTemplate,
+    plan::{Action, Change},
+    queue::WorkQueue,
+    selector::LabelSelector,
+    settings::Settings,
+    status::{self, Coverage, RegistryStatus},
+    token::TokenCache,
+    validate,
+    verify::{Verifier, VerifyError},
+};
+use anyhow::{anyhow, Context, Result};
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use k8s_openapi::{
+    api::core::v1::{LocalObjectReference, Namespace, Secret, ServiceAccount},
+    ByteString,
+};
 use kube::{
-    api::{ListParams, Meta, PatchParams, PostParams},
+    api::{DeleteParams, ListParams, Meta, PatchParams},
     Api, Client,
 };
 use kube_runtime::watcher;
+use serde::de::DeserializeOwned;
 use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// every secret created by the worker carries this label and the server
+// annotation, only labeled secrets are ever garbage collected.
+const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+const MANAGED_BY: &str = "imagepullsecret-sync";
+const SERVER_ANNOTATION: &str = "imagepullsecret-sync/server";
+const DOCKER_CONFIG_KEY: &str = ".dockerconfigjson";
+// the per registry coverage on the config secret
+const STATUS_ANNOTATION: &str = "imagepullsecret-sync/status";
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+// attempts to apply imagePullSecrets again after a concurrent change
+const SA_APPLY_RETRIES: u32 = 3;
+// the least time between two token refreshes, a token expiring right away
+// must not make the scheduler spin
+const MIN_TOKEN_REFRESH: Duration = Duration::from_secs(5);
+
+// what ensure_registry_secret did to the secret
+#[derive(Debug, Clone, PartialEq)]
+enum SecretChange {
+    Unchanged,
+    Created,
+    // the drifted fields
+    Patched(Vec<&'static str>),
+}
+
+/// The outcome of syncing one namespace.
+#[derive(Debug, Default)]
+pub struct NamespaceReport {
+    pub namespace: String,
+    // secrets which are in sync
+    pub synced: Vec<String>,
+    // secrets which failed to sync
+    pub failed: Vec<String>,
+    // orphaned secrets deleted by the garbage collection
+    pub deleted: Vec<String>,
+    // every change made, or planned in dry-run mode
+    pub changes: Vec<Change>,
+    // the registries the namespace should have, with the error of the
+    // failed ones
+    pub registries: BTreeMap<String, Option<String>>,
+    // the .dockerconfigjson digests of the changed secrets, by name
+    pub digests: BTreeMap<String, Digests>,
+    pub errors: Vec<SyncError>,
+}
+
+impl NamespaceReport {
+    fn new(namespace: String) -> Self {
+        NamespaceReport {
+            namespace,
+            ..NamespaceReport::default()
+        }
+    }
+
+    // the outcome of the comma separated `servers` of one secret
+    fn record(&mut self, servers: &str, error: Option<String>) {
+        for server in servers.split(',') {
+            self.registries.insert(server.to_string(), error.clone());
+        }
+    }
+}
+
+// (server, reason) of a registry synced nowhere
+type Skipped = (String, String);
+
+type WatchStream<K> = BoxStream<'static, Result<watcher::Event<K>, watcher::Error>>;
+
+// a dockerconfigjson secret to sync
+struct SecretSpec<'a> {
+    name: &'a str,
+    auth: &'a RegistryAuth,
+    // the comma separated servers of `auth`
+    servers: &'a str,
+    // the skipped servers whose entries in the current secret are kept
+    keep: &'a [String],
+}
+
+// the .dockerconfigjson of a secret
+fn docker_config(secret: &Secret) -> Option<&ByteString> {
+    secret.data.as_ref()?.get(DOCKER_CONFIG_KEY)
+}
+
+// the configs as compared with the ones in use, they hold the credentials
+// and only stay in memory
+fn serialize_configs(configs: &[Config]) -> Vec<u8> {
+    serde_json::to_vec(configs).unwrap_or_default()
+}
+
+// a watch namespace, its labels can't be read without cluster-wide RBAC
+fn scoped_ns(name: &str) -> Namespace {
+    let mut ns = Namespace::default();
+    ns.metadata.name = Some(name.to_string());
+    ns
+}
+
+// the resolved configs shared by the queued reconciles
+struct Desired {
+    configs: Vec<(Config, NamespaceFilter)>,
+    skipped: Vec<String>,
+    revision: Option<String>,
+}
+
+// the resourceVersions the configs were read at, for the audit log
+#[derive(Debug, Clone, Default)]
+struct ConfigRevision {
+    secret: Option<String>,
+    registry_credentials: Option<String>,
+}
+
+impl ConfigRevision {
+    // e.g. "secret=1234,registrycredentials=1250"
+    fn render(&self) -> Option<String> {
+        let parts: Vec<String> = [
+            ("secret", &self.secret),
+            ("registrycredentials", &self.registry_credentials),
+        ]
+        .iter()
+        .filter_map(|(kind, rv)| rv.as_ref().map(|rv| format!("{}={}", kind, rv)))
+        .collect();
+        (!parts.is_empty()).then(|| parts.join(","))
+    }
+}
 
 #[derive(Clone)]
-pub struct SyncWorker<'a> {
-    cfg_ns: &'a str,
-    cfg_name: &'a str,
-    cfg_data_key: &'a str,
-    sa_name: &'a str,
+pub struct SyncWorker {
+    settings: Settings,
+    sa_selector: Option<LabelSelector>,
+    names: NameTemplate,
+    tokens: Arc<TokenCache>,
+    // checks the credentials before they are rolled out
+    verifier: Option<Arc<Verifier>>,
+    metrics: Arc<Metrics>,
+    // plan changes without making them
+    dry_run: bool,
+    queue: Arc<WorkQueue>,
+    desired: Arc<Mutex<Option<Arc<Desired>>>>,
+    coverage: Arc<Coverage>,
+    // the RegistryCredentials which are not synced, by name
+    rejected: Arc<Mutex<BTreeMap<String, Rejected>>>,
+    recorder: Arc<Recorder>,
+    // the cluster synced into
     client: Client,
+    // the cluster with the config secret, the RegistryCredentials and the
+    // credential secrets, the same as `client` but for the --clusters
+    source: Client,
+    // the kubeconfig context of a --clusters worker
+    cluster: Option<String>,
+    // the workers of the --clusters, the configs are fanned out to them
+    remotes: Vec<SyncWorker>,
+    audit: Option<Arc<AuditLog>>,
+    // why the queued namespaces were queued, for the audit log
+    triggers: Arc<Mutex<BTreeMap<String, BTreeSet<String>>>>,
+    revision: Arc<Mutex<ConfigRevision>>,
+    // the serialized configs in use, the watchers compare the configs
+    // listed after a restart with them
+    applied: Arc<Mutex<Option<Vec<u8>>>>,
 }
 
-impl<'a> SyncWorker<'a> {
-    pub fn new(client: Client, cfg_ns: &'a str, cfg_name: &'a str) -> Self {
+impl SyncWorker {
+    pub fn new(client: Client, settings: Settings) -> Result<Self> {
+        let sa_selector = match &settings.service_account_selector {
+            Some(s) => Some(LabelSelector::parse(s)?),
+            None => None,
+        };
+
+        let names = NameTemplate::parse(&settings.secret_name_template)?;
+        let refresh_before = chrono::Duration::seconds(settings.token_refresh_before as i64);
+
+        Ok(SyncWorker {
+            recorder: Arc::new(Recorder::new(client.clone(), &leader::default_identity())),
+            source: client.clone(),
+            client,
+            cluster: None,
+            remotes: Vec::new(),
+            audit: None,
+            triggers: Arc::new(Mutex::new(BTreeMap::new())),
+            revision: Arc::new(Mutex::new(ConfigRevision::default())),
+            applied: Arc::new(Mutex::new(None)),
+            sa_selector,
+            names,
+            tokens: Arc::new(TokenCache::new(refresh_before)),
+            verifier: settings
+                .verify_credentials
+                .then(|| Arc::new(Verifier::new())),
+            metrics: Arc::new(Metrics::new()),
+            dry_run: false,
+            queue: WorkQueue::new(settings.queue_qps),
+            desired: Arc::new(Mutex::new(None)),
+            coverage: Arc::new(Coverage::new()),
+            rejected: Arc::new(Mutex::new(BTreeMap::new())),
+            settings,
+        })
+    }
+
+    // the name of the secret of the registry `server`, or of the merged one
+    fn secret_name(&self, server: &str) -> String {
+        match &self.settings.merge_secret_name {
+            Some(name) => name.clone(),
+            None => self.names.render(server),
+        }
+    }
+
+    /// The configs with the name of their secret, without the ones whose name
+    /// is taken by an earlier config.
+    pub fn secret_names(&self, configs: Vec<Config>) -> Vec<(Config, String)> {
+        let configs = match self.settings.merge_secret_name {
+            Some(_) => configs,
+            None => self.names.dedup(configs).0,
+        };
+        configs
+            .into_iter()
+            .map(|cfg| {
+                let name = self.secret_name(&cfg.server);
+                (cfg, name)
+            })
+            .collect()
+    }
+
+    /// A worker syncing the configs of this one into the cluster of `client`,
+    /// the kubeconfig context `cluster`. It shares the credential caches and
+    /// has its own queue, coverage and metrics.
+    pub fn remote(&self, cluster: &str, client: Client) -> Self {
         SyncWorker {
+            metrics: Arc::new(Metrics::for_cluster(cluster)),
+            queue: WorkQueue::new(self.settings.queue_qps),
+            desired: Arc::new(Mutex::new(None)),
+            coverage: Arc::new(Coverage::new()),
+            rejected: Arc::new(Mutex::new(BTreeMap::new())),
+            recorder: Arc::new(Recorder::new(client.clone(), &leader::default_identity())),
             client,
-            cfg_ns,
-            cfg_name,
-            cfg_data_key: "registry_secrets", // todo
-            sa_name: "default",               // todo
+            cluster: Some(cluster.to_string()),
+            remotes: Vec::new(),
+            triggers: Arc::new(Mutex::new(BTreeMap::new())),
+            applied: Arc::new(Mutex::new(None)),
+            ..self.clone()
         }
     }
 
-    async fn ensure(&self, all_ns: Vec<String>, configs: Vec<Config>) {
+    /// A worker which fans the configs out to the `remotes` and reports their
+    /// coverage along with its own.
+    pub fn with_remotes(self, remotes: Vec<SyncWorker>) -> Self {
+        for remote in remotes.iter() {
+            if let Some(cluster) = &remote.cluster {
+                self.metrics.add_cluster(cluster, remote.metrics());
+            }
+        }
+        SyncWorker { remotes, ..self }
+    }
+
+    pub fn remotes(&self) -> &[SyncWorker] {
+        &self.remotes
+    }
+
+    /// The kubeconfig context of a --clusters worker.
+    pub fn cluster(&self) -> Option<&str> {
+        self.cluster.as_deref()
+    }
+
+    // `ns` in the logs, prefixed with the cluster of a --clusters worker
+    fn ns_label(&self, ns: &str) -> String {
+        match &self.cluster {
+            Some(cluster) => format!("{}/{}", cluster, ns),
+            None => ns.to_string(),
+        }
+    }
+
+    /// A worker which writes every change it makes to the `audit` log, so do
+    /// the --clusters workers created from it afterwards.
+    pub fn with_audit(self, audit: Arc<AuditLog>) -> Self {
+        SyncWorker {
+            audit: Some(audit),
+            ..self
+        }
+    }
+
+    /// A worker which only records the changes it would make in the reports,
+    /// like its remotes.
+    pub fn with_dry_run(self, dry_run: bool) -> Self {
+        let remotes = self
+            .remotes
+            .into_iter()
+            .map(|remote| remote.with_dry_run(dry_run))
+            .collect();
+        SyncWorker {
+            dry_run,
+            remotes,
+            ..self
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn queue(&self) -> Arc<WorkQueue> {
+        self.queue.clone()
+    }
+
+    fn ns_list_params(&self) -> ListParams {
+        let lp = ListParams::default().fields(&self.settings.namespace_field_selector);
+        match &self.settings.namespace_label_selector {
+            Some(selector) => lp.labels(selector),
+            None => lp,
+        }
+    }
+
+    fn is_target_sa(&self, sa: &ServiceAccount) -> bool {
+        let name = sa.name();
+        let desired = self.desired.lock().unwrap().clone();
+        self.settings.service_accounts.contains(&name)
+            || self
+                .sa_selector
+                .as_ref()
+                .is_some_and(|s| s.matches_opt(&sa.metadata.labels))
+            || desired.is_some_and(|d| {
+                d.configs
+                    .iter()
+                    .any(|(cfg, _)| cfg.service_accounts.contains(&name))
+            })
+    }
+
+    // the explicit service accounts plus the ones matching the selector in ns
+    async fn target_sas(&self, ns: &str) -> SyncResult<Vec<String>> {
+        let mut sas = self.settings.service_accounts.clone();
+
+        if let Some(selector) = &self.settings.service_account_selector {
+            let sa_api = Api::<ServiceAccount>::namespaced(self.client.clone(), ns);
+            let lp = ListParams::default().labels(selector);
+            let list = sa_api
+                .list(&lp)
+                .await
+                .map_err(|e| SyncError::from_kube(e, "list", "serviceaccount", ns, selector))?;
+            for sa in list {
+                let name = sa.name();
+                if !sas.contains(&name) {
+                    sas.push(name);
+                }
+            }
+        }
+
+        Ok(sas)
+    }
+
+    // the service accounts referencing the secret of `cfgs`, a config's own
+    // service accounts replace the global `sas`
+    fn secret_sas(cfgs: &[&Config], sas: &[String]) -> Vec<String> {
+        let mut out: Vec<String> = Vec::new();
+        for cfg in cfgs.iter() {
+            let own = if cfg.service_accounts.is_empty() {
+                sas
+            } else {
+                &cfg.service_accounts
+            };
+            for sa in own.iter() {
+                if !out.contains(sa) {
+                    out.push(sa.clone());
+                }
+            }
+        }
+        out
+    }
+
+    // pair every config with its compiled namespace filter, configs with
+    // invalid namespace patterns are skipped and their servers returned, so
+    // their secrets are protected from garbage collection. So are selectors
+    // in namespace-scoped mode, the labels they'd match are never read.
+    fn with_filters(&self, configs: Vec<Config>) -> (Vec<(Config, NamespaceFilter)>, Vec<Skipped>) {
+        let mut filtered = Vec::new();
+        let mut skipped = Vec::new();
+        for cfg in configs.into_iter() {
+            if self.settings.namespace_scoped() && cfg.namespace_selector.is_some() {
+                let reason = "namespace_selector can't be used with --watch-namespaces";
+                error!("skip registry '{}': {}", cfg.server, reason);
+                skipped.push((cfg.server, reason.to_string()));
+                continue;
+            }
+            match cfg.namespace_filter() {
+                Ok(filter) => filtered.push((cfg, filter)),
+                Err(e) => {
+                    error!("skip registry '{}': {:#}", cfg.server, e);
+                    skipped.push((cfg.server, format!("{:#}", e)));
+                }
+            }
+        }
+        (filtered, skipped)
+    }
+
+    // a dry run neither runs commands nor sends requests to resolve the
+    // credentials, nor logs in to the registries. The configs needing that
+    // are skipped, they keep their current secrets in the plan.
+    fn dry_run_configs(&self, configs: Vec<Config>) -> (Vec<Config>, Vec<Skipped>) {
+        if !self.dry_run {
+            return (configs, vec![]);
+        }
+        let (configs, unresolved): (Vec<Config>, Vec<Config>) =
+            configs.into_iter().partition(|cfg| {
+                cfg.credentials
+                    .as_ref()
+                    .is_none_or(CredentialSource::is_read_only)
+            });
+        let skipped = unresolved
+            .into_iter()
+            .map(|cfg| {
+                let kind = cfg.credentials.as_ref().map_or("", CredentialSource::kind);
+                let reason = format!("{} credentials are not resolved in a dry run", kind);
+                (cfg.server, reason)
+            })
+            .collect();
+        (configs, skipped)
+    }
+
+    // drop the configs whose credentials the registry rejects, they keep
+    // their current secrets. A registry which can't be reached doesn't
+    // block the rollout.
+    async fn verify(&self, configs: Vec<Config>) -> (Vec<Config>, Vec<Skipped>) {
+        let verifier = match &self.verifier {
+            Some(verifier) => verifier,
+            None => return (configs, vec![]),
+        };
+        let outcomes =
+            futures::future::join_all(configs.iter().map(|cfg| verifier.verify(cfg))).await;
+        let mut verified = Vec::new();
+        let mut rejected = Vec::new();
+        for (cfg, outcome) in configs.into_iter().zip(outcomes) {
+            match outcome {
+                Ok(()) => verified.push(cfg),
+                Err(e @ VerifyError::Rejected { .. }) => {
+                    error!("skip registry '{}': {}", cfg.server, e);
+                    rejected.push((cfg.server, e.to_string()));
+                }
+                Err(e) => {
+                    warn!("verify credentials err, roll them out anyway: {}", e);
+                    verified.push(cfg);
+                }
+            }
+        }
+        (verified, rejected)
+    }
+
+    // resolve the credentials and compile the namespace filters, configs
+    // failing either are skipped and keep their current secrets, the
+    // returned servers. So are configs whose secret name is taken by an
+    // earlier config, which keeps its secret.
+    async fn prepare(&self, configs: Vec<Config>) -> (Vec<(Config, NamespaceFilter)>, Vec<String>) {
+        let (configs, collided) = match self.settings.merge_secret_name {
+            Some(_) => (configs, vec![]),
+            None => self.names.dedup(configs),
+        };
+        let (configs, unresolved) = self.dry_run_configs(configs);
+        let (resolved, failed) =
+            credentials::resolve_all(&self.source, &self.tokens, configs).await;
+        let (verified, rejected) = match self.dry_run {
+            true => (resolved, vec![]),
+            false => self.verify(resolved).await,
+        };
+        let (filtered, mut skipped) = self.with_filters(verified);
+        skipped.extend(rejected);
+        skipped.extend(unresolved);
+        for (server, reason) in collided {
+            error!("skip registry '{}': {}", server, reason);
+            skipped.push((server, reason));
+        }
+        for (cfg, e) in failed {
+            error!(
+                "skip registry '{}', resolve credentials err: {:#}",
+                cfg.server, e
+            );
+            skipped.push((cfg.server, format!("resolve credentials: {:#}", e)));
+        }
+        if !self.dry_run {
+            let servers: Vec<String> = filtered.iter().map(|(cfg, _)| cfg.server.clone()).collect();
+            self.coverage.set_configs(&servers, &skipped);
+        }
+        let skipped = skipped.into_iter().map(|(server, _)| server).collect();
+        (filtered, skipped)
+    }
+
+    /// Sync the configs into every namespace, the returned reports hold the
+    /// outcome and the errors of each namespace.
+    pub async fn ensure(
+        &self,
+        all_ns: Vec<Namespace>,
+        configs: Vec<Config>,
+    ) -> Vec<NamespaceReport> {
+        let (configs, skipped) = self.prepare(configs).await;
+        let revision = self.revision.lock().unwrap().render();
+        let mut reports = Vec::new();
         for ns in all_ns.iter() {
-            for cfg in configs.iter() {
-                match self.ensure_registry_secret(ns, cfg).await {
-                    Ok(skip) => {
-                        if !skip {
-                            if let Err(e) = self.ensure_patch_sa(ns, &cfg.server).await {
-                                warn!("patch '{}/{}' to default err: {}", ns, cfg.server, e);
-                            }
-                        }
+            let start = Instant::now();
+            let report = self.sync_ns(ns, &configs, &skipped).await;
+            self.metrics
+                .observe_reconcile(start.elapsed().as_secs_f64());
+            self.audit(&report, "sync", revision.as_deref());
+            self.publish(&report).await;
+            reports.push(report);
+        }
+        if skipped.is_empty() && reports.iter().all(|r| r.errors.is_empty()) {
+            self.metrics.synced_all();
+        }
+        reports
+    }
+
+    async fn sync_ns(
+        &self,
+        ns: &Namespace,
+        configs: &[(Config, NamespaceFilter)],
+        skipped: &[String],
+    ) -> NamespaceReport {
+        let mut report = NamespaceReport::new(ns.name());
+
+        let sas = match self.target_sas(&report.namespace).await {
+            Ok(sas) => sas,
+            Err(e) => {
+                // every registry of the namespace failed
+                for (cfg, filter) in configs.iter() {
+                    if filter.matches(&report.namespace, &ns.metadata.labels) {
+                        report
+                            .registries
+                            .insert(cfg.server.clone(), Some(e.to_string()));
                     }
-                    Err(e) => {
-                        info!("ensure '{}/{}' registry_secret err: {}", ns, cfg.server, e);
+                }
+                report.errors.push(e);
+                return report;
+            }
+        };
+
+        self.ensure_ns(ns, &sas, configs, skipped, &mut report)
+            .await;
+
+        let mut desired: HashSet<String> = report.synced.iter().cloned().collect();
+        desired.extend(report.failed.iter().cloned());
+        desired.extend(skipped.iter().map(|server| self.names.render(server)));
+        if let (Some(merged), false) = (&self.settings.merge_secret_name, skipped.is_empty()) {
+            desired.insert(merged.clone());
+        }
+        self.gc_ns(&desired, &mut report).await;
+
+        report
+    }
+
+    async fn ensure_ns(
+        &self,
+        ns: &Namespace,
+        sas: &[String],
+        configs: &[(Config, NamespaceFilter)],
+        skipped: &[String],
+        report: &mut NamespaceReport,
+    ) {
+        let name = ns.name();
+        let matched: Vec<&Config> = configs
+            .iter()
+            .filter(|(cfg, filter)| {
+                let ok = filter.matches(&name, &ns.metadata.labels);
+                if !ok {
+                    debug!("secret '{}' don't need sync to ns '{}'", cfg.server, name);
+                }
+                ok
+            })
+            .map(|(cfg, _)| cfg)
+            .collect();
+
+        match &self.settings.merge_secret_name {
+            Some(secret_name) => {
+                if matched.is_empty() {
+                    return;
+                }
+                let auth = RegistryAuth::from_configs(matched.iter().copied());
+                let servers: Vec<&str> = matched.iter().map(|cfg| cfg.server.as_str()).collect();
+                let sas = Self::secret_sas(&matched, sas);
+                // the skipped registries keep their current entries
+                let synced = SecretSpec {
+                    name: secret_name,
+                    auth: &auth,
+                    servers: &servers.join(","),
+                    keep: skipped,
+                };
+                self.sync_secret(&name, &synced, &sas, report).await;
+            }
+            None => {
+                for cfg in matched {
+                    let auth = RegistryAuth::from_configs(Some(cfg));
+                    let sas = Self::secret_sas(&[cfg], sas);
+                    let secret_name = self.names.render(&cfg.server);
+                    let synced = SecretSpec {
+                        name: &secret_name,
+                        auth: &auth,
+                        servers: &cfg.server,
+                        keep: &[],
+                    };
+                    self.sync_secret(&name, &synced, &sas, report).await;
+                }
+            }
+        }
+    }
+
+    // ensure one dockerconfigjson secret and its references from the target
+    // service accounts
+    async fn sync_secret(
+        &self,
+        ns: &str,
+        spec: &SecretSpec<'_>,
+        sas: &[String],
+        report: &mut NamespaceReport,
+    ) {
+        let (secret_name, servers) = (spec.name, spec.servers);
+        let change = |action, detail: Option<String>| Change {
+            action,
+            kind: "secret",
+            namespace: ns.to_string(),
+            name: secret_name.to_string(),
+            detail,
+        };
+        match self.ensure_registry_secret(ns, spec).await {
+            Ok((SecretChange::Created, digests)) => {
+                self.metrics.secret(ns, "created");
+                report.changes.push(change(Action::Create, None));
+                report.digests.insert(secret_name.to_string(), digests);
+            }
+            Ok((SecretChange::Patched(fields), digests)) => {
+                self.metrics.secret(ns, "patched");
+                report
+                    .changes
+                    .push(change(Action::Patch, Some(fields.join(", "))));
+                report.digests.insert(secret_name.to_string(), digests);
+            }
+            Ok((SecretChange::Unchanged, _)) => {}
+            Err(e) => {
+                self.metrics.secret(ns, "failed");
+                report.failed.push(secret_name.to_string());
+                report.record(servers, Some(e.to_string()));
+                report.errors.push(e);
+                return;
+            }
+        }
+        report.synced.push(secret_name.to_string());
+
+        let mut sa_error = None;
+        for sa_name in sas.iter() {
+            match self.ensure_patch_sa(ns, sa_name, secret_name).await {
+                Ok(change) => report.changes.extend(change),
+                Err(e) => {
+                    sa_error.get_or_insert_with(|| e.to_string());
+                    report.errors.push(e);
+                }
+            }
+        }
+        report.record(servers, sa_error);
+    }
+
+    // log the outcome of syncing one namespace, record its coverage and
+    // publish it as events on the namespace
+    async fn publish(&self, report: &NamespaceReport) {
+        for e in report.errors.iter() {
+            error!("sync ns '{}' err: {}", self.ns_label(&report.namespace), e);
+        }
+        if self.dry_run {
+            return;
+        }
+        self.coverage.record(&report.namespace, &report.registries);
+
+        let ns = &report.namespace;
+        let object = events::object_ref("Namespace", None, ns);
+        if !report.changes.is_empty() {
+            let changes: Vec<String> = report
+                .changes
+                .iter()
+                .map(|c| format!("{} {} {}", c.action, c.kind, c.name))
+                .collect();
+            let message = format!("synced registry secrets: {}", changes.join(", "));
+            self.recorder
+                .publish(
+                    ns,
+                    &object,
+                    EventType::Normal,
+                    events::REASON_SYNCED,
+                    &message,
+                )
+                .await;
+        }
+        for e in report.errors.iter() {
+            self.recorder
+                .publish(
+                    ns,
+                    &object,
+                    EventType::Warning,
+                    events::REASON_FAILED,
+                    &e.to_string(),
+                )
+                .await;
+        }
+    }
+
+    // delete the managed secrets which are no longer desired in ns and drop
+    // the references to them from the target service accounts. A secret of
+    // a registry which is synced under another name now, e.g. after the
+    // name template changed, is migrated: its references are renamed in
+    // place. The references go first, a secret whose references failed to
+    // be dropped is kept so the next reconcile finds it again.
+    async fn gc_ns(&self, desired: &HashSet<String>, report: &mut NamespaceReport) {
+        let ns = report.namespace.clone();
+        let secret_api = Api::<Secret>::namespaced(self.client.clone(), &ns);
+        let selector = format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY);
+        let lp = ListParams::default().labels(&selector);
+
+        let secrets = match secret_api.list(&lp).await {
+            Ok(secrets) => secrets,
+            Err(e) => {
+                let e = SyncError::from_kube(e, "list", "secret", &ns, &selector);
+                report.errors.push(e);
+                return;
+            }
+        };
+
+        let mut stale = Vec::new();
+        let mut renamed = BTreeMap::new();
+        for s in secrets {
+            let name = s.name();
+            if desired.contains(&name) {
+                continue;
+            }
+            let digests = Digests {
+                before: docker_config(&s).map(|data| audit::digest(&data.0)),
+                after: None,
+            };
+            let renamed_to = s
+                .metadata
+                .annotations
+                .as_ref()
+                .and_then(|a| a.get(SERVER_ANNOTATION))
+                .map(|servers| self.secret_name(servers))
+                .filter(|new| *new != name && report.synced.contains(new));
+            if let Some(new) = renamed_to {
+                renamed.insert(name.clone(), new);
+            }
+            stale.push((name, digests));
+        }
+        if stale.is_empty() {
+            return;
+        }
+
+        // references are dropped from every service account holding one, not
+        // only the current targets: a service account which stopped being a
+        // target, e.g. by losing the selector label, keeps its references
+        let names: Vec<String> = stale.iter().map(|(name, _)| name.clone()).collect();
+        let sa_api = Api::<ServiceAccount>::namespaced(self.client.clone(), &ns);
+        let holders: Vec<String> = match sa_api.list(&ListParams::default()).await {
+            Ok(list) => list
+                .into_iter()
+                .filter(|sa| {
+                    sa.image_pull_secrets
+                        .iter()
+                        .flatten()
+                        .any(|r| r.name.as_ref().is_some_and(|name| names.contains(name)))
+                })
+                .map(|sa| sa.name())
+                .collect(),
+            Err(e) => {
+                let e = SyncError::from_kube(e, "list", "serviceaccount", &ns, "*");
+                report.errors.push(e);
+                return;
+            }
+        };
+        let mut referenced = false;
+        for sa_name in holders.iter() {
+            match self.remove_sa_refs(&ns, sa_name, &names, &renamed).await {
+                Ok(change) => report.changes.extend(change),
+                Err(e) => {
+                    referenced = true;
+                    report.errors.push(e);
+                }
+            }
+        }
+        if referenced {
+            return;
+        }
+
+        for (name, digests) in stale {
+            report.changes.push(Change {
+                action: Action::Delete,
+                kind: "secret",
+                namespace: ns.clone(),
+                name: name.clone(),
+                detail: renamed.get(&name).map(|new| format!("renamed to {}", new)),
+            });
+            if self.dry_run {
+                report.deleted.push(name);
+                continue;
+            }
+            match renamed.get(&name) {
+                Some(new) => info!("migrate secret '{}/{}' to '{}'", ns, name, new),
+                None => info!("delete orphaned secret '{}/{}'", ns, name),
+            }
+            match secret_api.delete(&name, &DeleteParams::default()).await {
+                Ok(_) => {
+                    report.digests.insert(name.clone(), digests);
+                    report.deleted.push(name);
+                }
+                Err(e) => match SyncError::from_kube(e, "delete", "secret", &ns, &name) {
+                    e if e.is_not_found() => report.deleted.push(name),
+                    e => report.errors.push(e),
+                },
+            }
+        }
+    }
+
+    // resolve configs once for the queued reconciles
+    async fn set_configs(&self, configs: Vec<Config>) -> Arc<Desired> {
+        *self.applied.lock().unwrap() = Some(serialize_configs(&configs));
+        let revision = self.revision.lock().unwrap().render();
+        let (configs, skipped) = self.prepare(configs).await;
+        let desired = Arc::new(Desired {
+            configs,
+            skipped,
+            revision,
+        });
+        *self.desired.lock().unwrap() = Some(desired.clone());
+        desired
+    }
+
+    async fn desired(&self) -> Result<Arc<Desired>> {
+        let cached = self.desired.lock().unwrap().clone();
+        match cached {
+            Some(desired) => Ok(desired),
+            None => Ok(self.set_configs(self.read_config().await?).await),
+        }
+    }
+
+    /// Use `configs` from now on and queue every namespace, `trigger` says
+    /// why in the audit log.
+    pub async fn update_configs(&self, configs: Vec<Config>, trigger: &str) -> Result<()> {
+        // concurrently, an unreachable cluster must not hold up the others
+        let remotes: Vec<_> = self
+            .remotes
+            .iter()
+            .map(|remote| {
+                let configs = configs.clone();
+                async move {
+                    if let Err(e) = remote.update_local(configs, trigger).await {
+                        error!(
+                            "cluster '{}': get all ns err: {}",
+                            remote.cluster.as_deref().unwrap_or_default(),
+                            e
+                        );
                     }
                 }
+            })
+            .collect();
+        let (local, _) = futures::join!(
+            self.update_local(configs, trigger),
+            futures::future::join_all(remotes)
+        );
+        local
+    }
+
+    /// Use the current configs when they differ from the ones in use, e.g.
+    /// when the config secret changed while its watch was down. Returns
+    /// whether they differed, before the initial sync they never do.
+    pub async fn update_changed_configs(&self, trigger: &str) -> Result<bool> {
+        let applied = self.applied.lock().unwrap().clone();
+        let applied = match applied {
+            Some(applied) => applied,
+            None => return Ok(false),
+        };
+        let configs = self.read_config().await?;
+        if serialize_configs(&configs) == applied {
+            return Ok(false);
+        }
+        info!("configs changed while not watched, resync all ns");
+        self.update_configs(configs, trigger).await?;
+        Ok(true)
+    }
+
+    async fn update_local(&self, configs: Vec<Config>, trigger: &str) -> Result<()> {
+        self.set_configs(configs).await;
+        for ns in self.get_all_ns().await? {
+            self.enqueue(&ns.name(), trigger);
+        }
+        Ok(())
+    }
+
+    /// The per registry coverage of this worker and its --clusters.
+    pub fn summary(&self) -> BTreeMap<String, RegistryStatus> {
+        let mut summary = self.coverage.summary();
+        for remote in self.remotes.iter() {
+            let cluster = remote.cluster.as_deref().unwrap_or_default();
+            status::add_cluster(&mut summary, cluster, &remote.coverage.summary());
+        }
+        summary
+    }
+
+    /// Queue a sync of namespace `ns`, `trigger` says why in the audit log.
+    pub fn enqueue(&self, ns: &str, trigger: &str) {
+        self.triggers
+            .lock()
+            .unwrap()
+            .entry(ns.to_string())
+            .or_default()
+            .insert(trigger.to_string());
+        self.queue.add(ns);
+    }
+
+    // the triggers `ns` was queued for since its last successful sync
+    fn pending_triggers(&self, ns: &str) -> BTreeSet<String> {
+        let triggers = self.triggers.lock().unwrap();
+        triggers.get(ns).cloned().unwrap_or_default()
+    }
+
+    // forget the `done` triggers, the ones added meanwhile queued `ns` again
+    fn clear_triggers(&self, ns: &str, done: &BTreeSet<String>) {
+        let mut triggers = self.triggers.lock().unwrap();
+        if let Some(pending) = triggers.get_mut(ns) {
+            pending.retain(|t| !done.contains(t));
+            if pending.is_empty() {
+                triggers.remove(ns);
             }
         }
     }
 
+    // write the changes of `report` to the audit log, failed deletes are not
+    // changes
+    fn audit(&self, report: &NamespaceReport, trigger: &str, revision: Option<&str>) {
+        let audit = match (&self.audit, self.dry_run) {
+            (Some(audit), false) => audit,
+            _ => return,
+        };
+        for change in report.changes.iter() {
+            let digests = match change.kind {
+                "secret" => report.digests.get(&change.name).cloned(),
+                _ => None,
+            };
+            if change.action == Action::Delete && digests.is_none() {
+                continue;
+            }
+            let digests = digests.unwrap_or_default();
+            audit.record(&AuditEntry {
+                time: chrono::Utc::now(),
+                change: change.clone(),
+                cluster: self.cluster.clone(),
+                trigger: trigger.to_string(),
+                config_revision: revision.map(String::from),
+                before: digests.before,
+                after: digests.after,
+            });
+        }
+    }
+
+    /// Sync one namespace with the current configs.
+    pub async fn reconcile(&self, name: &str) -> Result<NamespaceReport> {
+        let triggers = self.pending_triggers(name);
+        let ns = match self.get_ns(name).await? {
+            Some(ns) => ns,
+            // deleted meanwhile, nothing to do
+            None => {
+                self.coverage.remove_ns(name);
+                self.clear_triggers(name, &triggers);
+                return Ok(NamespaceReport::new(name.to_string()));
+            }
+        };
+        let desired = self.desired().await?;
+
+        let start = Instant::now();
+        let report = self.sync_ns(&ns, &desired.configs, &desired.skipped).await;
+        self.metrics
+            .observe_reconcile(start.elapsed().as_secs_f64());
+        // a failed namespace keeps its triggers for the retry
+        let trigger = if triggers.is_empty() {
+            "reconcile".to_string()
+        } else {
+            triggers.iter().cloned().collect::<Vec<_>>().join(", ")
+        };
+        self.audit(&report, &trigger, desired.revision.as_deref());
+        if report.errors.is_empty() {
+            self.clear_triggers(name, &triggers);
+        }
+        self.publish(&report).await;
+        Ok(report)
+    }
+
+    // the target namespace `name`, None when it doesn't exist or isn't one of
+    // the watch namespaces
+    async fn get_ns(&self, name: &str) -> Result<Option<Namespace>> {
+        if self.settings.namespace_scoped() {
+            let listed = self.settings.watch_namespaces.iter().any(|ns| ns == name);
+            return Ok(listed.then(|| scoped_ns(name)));
+        }
+        match Api::<Namespace>::all(self.client.clone()).get(name).await {
+            Ok(ns) => Ok(Some(ns)),
+            Err(e) => match SyncError::from_kube(e, "get", "namespace", "", name) {
+                e if e.is_not_found() => Ok(None),
+                e => Err(e.into()),
+            },
+        }
+    }
+
+    /// Reconcile the queued namespaces with `workers` concurrent tasks, failed
+    /// namespaces are retried with backoff.
+    pub async fn process_queue(&self) -> Result<()> {
+        info!(
+            "processing the work queue with {} workers ...",
+            self.settings.workers
+        );
+        let workers = (0..self.settings.workers).map(|_| async move {
+            loop {
+                let ns = self.queue.next().await;
+                let ok = match self.reconcile(&ns).await {
+                    Ok(report) => report.errors.is_empty(),
+                    Err(e) => {
+                        error!("reconcile ns '{}' err: {:#}", self.ns_label(&ns), e);
+                        false
+                    }
+                };
+                self.queue.done(&ns, ok);
+                if ok && self.queue.is_idle() {
+                    self.metrics.synced_all();
+                }
+            }
+        });
+        futures::future::join_all(workers).await;
+        Ok(())
+    }
+
     pub async fn watch_ns(&self) -> Result<()> {
+        let mut listed = false;
+        if self.settings.namespace_scoped() {
+            // the namespaces don't change, sync them once like a list
+            info!(
+                "syncing ns {} ...",
+                self.settings.watch_namespaces.join(", ")
+            );
+            self.relisted(WATCH_NS, &mut listed);
+            self.sync_listed(&self.settings.watch_namespaces, "namespaces listed")
+                .await;
+            futures::future::pending::<()>().await;
+        }
+
         info!("watching all active ns ...");
         let ns_api = Api::<Namespace>::all(self.client.clone());
-
-        let lp = ListParams::default().fields("status.phase=Active");
-
-        let mut w = watcher(ns_api, lp).boxed();
+        let mut w = watcher(ns_api, self.ns_list_params()).boxed();
         while let Some(event) = w.try_next().await? {
             match event {
-                watcher::Event::Applied(ns) => match self.read_config().await {
-                    Ok(configs) => {
-                        let all_ns = Vec::from(vec![ns.name()]);
-                        self.ensure(all_ns, configs).await;
-                    }
-                    Err(e) => {
-                        error!("applied ns {}, but read_config err: {}", ns.name(), e);
-                    }
-                },
+                watcher::Event::Applied(ns) => {
+                    let trigger = format!("namespace '{}' changed", ns.name());
+                    self.enqueue(&ns.name(), &trigger)
+                }
                 watcher::Event::Restarted(nss) => {
-                    // if read config err stop watch
-                    let configs = self.read_config().await?;
-                    let all_ns = nss.iter().map(|ns| ns.name()).collect();
-
-                    self.ensure(all_ns, configs).await;
+                    self.relisted(WATCH_NS, &mut listed);
+                    let names: Vec<String> = nss.iter().map(|ns| ns.name()).collect();
+                    self.sync_listed(&names, "namespaces listed").await;
                 }
                 _ => {}
             }
@@ -79,144 +1089,804 @@ impl<'a> SyncWorker<'a> {
         Ok(())
     }
 
+    // read the configs and queue the listed namespaces
+    async fn sync_listed(&self, names: &[String], trigger: &str) {
+        // without a config there is nothing to sync, watch_cfg_secret
+        // syncs all ns once it shows up
+        match self.read_config().await {
+            Ok(configs) => {
+                self.set_configs(configs).await;
+                for ns in names.iter() {
+                    self.enqueue(ns, trigger);
+                }
+            }
+            Err(e) => error!("restarted ns watch, but read_config err: {}", e),
+        }
+    }
+
+    // the watch stream of `K` in all namespaces, or merged from one stream
+    // per watch namespace in namespace-scoped mode
+    fn watch_targets<K>(&self, lp: ListParams) -> WatchStream<K>
+    where
+        K: Meta + Clone + DeserializeOwned + Send + 'static,
+    {
+        if !self.settings.namespace_scoped() {
+            return watcher(Api::<K>::all(self.client.clone()), lp).boxed();
+        }
+        let streams =
+            self.settings.watch_namespaces.iter().map(|ns| {
+                watcher(Api::<K>::namespaced(self.client.clone(), ns), lp.clone()).boxed()
+            });
+        futures::stream::select_all(streams).boxed()
+    }
+
+    // the first list of a watch stream makes it ready, later ones are restarts
+    fn relisted(&self, watcher: &'static str, listed: &mut bool) {
+        if *listed {
+            self.metrics.watch_restarted(watcher);
+        } else {
+            self.metrics.health.synced(watcher);
+            *listed = true;
+        }
+    }
+
+    pub async fn watch_sa(&self) -> Result<()> {
+        info!("watching service accounts ...");
+        let mut w = self.watch_targets::<ServiceAccount>(ListParams::default());
+        while let Some(event) = w.try_next().await? {
+            // existing service accounts are handled by watch_ns on restart,
+            // any change, e.g. stripped imagePullSecrets, requeues the namespace
+            if let watcher::Event::Applied(sa) = event {
+                if !self.is_target_sa(&sa) {
+                    continue;
+                }
+                if let Some(ns) = sa.namespace() {
+                    let trigger = format!("serviceaccount '{}/{}' changed", ns, sa.name());
+                    self.enqueue(&ns, &trigger);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Watch the managed secrets, an edited or deleted secret requeues its
+    /// namespace so the drift is corrected.
+    pub async fn watch_secrets(&self) -> Result<()> {
+        info!("watching managed secrets ...");
+        let lp = ListParams::default().labels(&format!("{}={}", MANAGED_BY_LABEL, MANAGED_BY));
+        let mut w = self.watch_targets::<Secret>(lp);
+        while let Some(event) = w.try_next().await? {
+            // existing secrets are handled by watch_ns on restart, our own
+            // changes requeue too but the reconcile is a no-op then
+            let (secret, what) = match event {
+                watcher::Event::Applied(s) => (s, "changed"),
+                watcher::Event::Deleted(s) => (s, "deleted"),
+                watcher::Event::Restarted(_) => continue,
+            };
+            if let Some(ns) = secret.namespace() {
+                let trigger = format!("secret '{}/{}' {}", ns, secret.name(), what);
+                self.enqueue(&ns, &trigger);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the config and requeue every namespace periodically, a safety
+    /// net for drift the watches miss.
+    pub async fn resync(&self) -> Result<()> {
+        let interval = self.settings.resync_interval;
+        if interval == 0 {
+            info!("periodic resync disabled");
+            return Ok(());
+        }
+        info!("resync all ns every {}s ...", interval);
+
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // the first tick is immediate, the initial sync is done by watch_ns
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            debug!("periodic resync of all ns");
+            match self.read_config().await {
+                Ok(configs) => {
+                    if let Err(e) = self.update_configs(configs, "resync").await {
+                        error!("get all ns err: {}", e);
+                    }
+                }
+                Err(e) => error!("resync, but read_config err: {}", e),
+            }
+        }
+    }
+
     pub async fn watch_cfg_secret(&self) -> Result<()> {
-        info!("watching secret '{}/{}' ...", self.cfg_ns, self.cfg_name);
-        let secret_api = Api::<Secret>::namespaced(self.client.clone(), self.cfg_ns);
+        let (cfg_ns, cfg_name) = (&self.settings.config_namespace, &self.settings.config_name);
+        info!("watching secret '{}/{}' ...", cfg_ns, cfg_name);
+        let secret_api = Api::<Secret>::namespaced(self.source.clone(), cfg_ns);
 
-        let lp = ListParams::default().fields(&format!("metadata.name={}", self.cfg_name));
+        let lp = ListParams::default().fields(&format!("metadata.name={}", cfg_name));
 
+        let mut listed = false;
+        let mut data = None;
         let mut w = watcher(secret_api, lp).boxed();
         while let Some(event) = w.try_next().await? {
-            match event {
-                watcher::Event::Applied(s) => match self.read_data(s).await {
-                    Ok(configs) => match self.get_all_ns().await {
-                        Ok(all_ns) => self.ensure(all_ns, configs).await,
-                        Err(e) => error!("get all ns err: {}", e),
-                    },
+            if let watcher::Event::Restarted(secrets) = event {
+                // the initial sync is done by watch_ns, a relist applies
+                // what changed while the watch was down
+                self.relisted(WATCH_CONFIG, &mut listed);
+                data = secrets.into_iter().next().map(|s| s.data);
+                if let Err(e) = self.update_changed_configs("config secret changed").await {
+                    error!("relisted {} cfg, but update configs err: {:#}", cfg_name, e);
+                }
+            } else if let watcher::Event::Applied(s) = event {
+                // e.g. the status annotation was written
+                if data.as_ref() == Some(&s.data) {
+                    continue;
+                }
+                data = Some(s.data.clone());
+                match self.read_data(s).await {
+                    Ok(configs) => {
+                        if let Err(e) = self.update_configs(configs, "config secret changed").await
+                        {
+                            error!("get all ns err: {}", e);
+                        }
+                    }
+                    // the last valid configs stay in use
                     Err(e) => {
-                        error!("applied {} cfg, but read_data err: {}", self.cfg_name, e);
+                        error!("applied {} cfg, but read_data err: {:#}", cfg_name, e);
+                        let object = events::object_ref("Secret", Some(cfg_ns), cfg_name);
+                        self.recorder
+                            .publish(
+                                cfg_ns,
+                                &object,
+                                EventType::Warning,
+                                events::REASON_INVALID,
+                                &format!("{:#}", e),
+                            )
+                            .await;
                     }
-                },
-                _ => {}
+                }
             }
         }
         Ok(())
     }
 
-    async fn get_all_ns(&self) -> Result<Vec<String>> {
-        let ns_api = Api::<Namespace>::all(self.client.clone());
-        let lp = ListParams::default().fields("status.phase=Active");
+    /// Watch the RegistryCredentials, a changed spec or a deleted one resyncs
+    /// every namespace. Status writes don't change the generation.
+    pub async fn watch_registry_credentials(&self) -> Result<()> {
+        if !self.settings.registry_credentials {
+            info!("{} objects disabled", crd::KIND);
+            return Ok(());
+        }
+        info!("watching {} objects ...", crd::KIND);
+        let api = Api::<RegistryCredential>::all(self.source.clone());
 
-        let all_ns = ns_api.list(&lp).await?;
+        let mut listed = false;
+        let mut generations = BTreeMap::new();
+        let mut w = watcher(api, ListParams::default()).boxed();
+        while let Some(event) = w.try_next().await? {
+            let changed = match event {
+                watcher::Event::Restarted(items) => {
+                    // the initial sync is done by watch_ns, a relist applies
+                    // what changed while the watch was down
+                    self.relisted(WATCH_REGISTRY_CREDENTIALS, &mut listed);
+                    generations = items
+                        .iter()
+                        .map(|r| (r.name(), r.metadata.generation))
+                        .collect();
+                    let trigger = format!("{} changed", crd::KIND);
+                    if let Err(e) = self.update_changed_configs(&trigger).await {
+                        error!("relisted {}, but update configs err: {:#}", crd::KIND, e);
+                    }
+                    false
+                }
+                watcher::Event::Applied(r) => {
+                    generations.insert(r.name(), r.metadata.generation)
+                        != Some(r.metadata.generation)
+                }
+                watcher::Event::Deleted(r) => generations.remove(&r.name()).is_some(),
+            };
+            if !changed {
+                continue;
+            }
+            match self.read_config().await {
+                Ok(configs) => {
+                    if let Err(e) = self
+                        .update_configs(configs, &format!("{} changed", crd::KIND))
+                        .await
+                    {
+                        error!("get all ns err: {}", e);
+                    }
+                }
+                Err(e) => error!("{} changed, but read_config err: {:#}", crd::KIND, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Poll the external credential sources and resync every namespace when
+    /// a resolved credential changes.
+    pub async fn watch_credentials(&self) -> Result<()> {
+        let interval = self.settings.credentials_poll_interval;
+        if interval == 0 {
+            info!("credential polling disabled");
+            return Ok(());
+        }
+        info!("polling credential sources every {}s ...", interval);
 
-        Ok(all_ns.items.iter().map(|item| item.name()).collect())
+        let mut last = None;
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            let configs = match self.read_config().await {
+                Ok(configs) => configs,
+                Err(e) => {
+                    error!("poll credentials, but read_config err: {}", e);
+                    continue;
+                }
+            };
+            // inline credentials change with the config secret, which is watched,
+            // and tokens are refreshed by watch_tokens
+            let external = |cfg: &Config| cfg.credentials.as_ref().is_some_and(|c| c.is_external());
+            if !configs.iter().any(external) {
+                last = None;
+                continue;
+            }
+
+            let (resolved, failed) =
+                credentials::resolve_all(&self.source, &self.tokens, configs.clone()).await;
+            for (cfg, e) in failed.iter() {
+                warn!("resolve credentials of '{}' err: {:#}", cfg.server, e);
+            }
+            let current = credentials::fingerprint(&resolved);
+            if last.is_some() && last != Some(current) {
+                info!("credentials changed, resync all ns");
+                if let Err(e) = self.update_configs(configs, "credentials changed").await {
+                    error!("get all ns err: {}", e);
+                    continue;
+                }
+            }
+            last = Some(current);
+        }
     }
 
-    async fn ensure_registry_secret(&self, ns: &str, cfg: &Config) -> Result<bool> {
-        if !cfg.namespaces.contains(&format!("*")) && !cfg.namespaces.contains(&format!("{}", ns)) {
-            info!("secret '{}' don't need sync to ns '{}'", cfg.server, ns);
-            return Ok(true);
+    /// Refresh the short-lived registry tokens before they expire and patch
+    /// every target secret with the new ones.
+    pub async fn watch_tokens(&self) -> Result<()> {
+        info!("scheduling registry token refresh ...");
+        let idle = chrono::Duration::seconds(60);
+        loop {
+            let now = chrono::Utc::now();
+            let next = self
+                .tokens
+                .next_refresh()
+                .map_or(now + idle, |t| t.min(now + idle));
+            let wait = (next - now).to_std().unwrap_or_default();
+            tokio::time::sleep(wait.max(MIN_TOKEN_REFRESH)).await;
+
+            if !self.tokens.expire_due(chrono::Utc::now()) {
+                continue;
+            }
+            info!("registry tokens due for refresh, resync all ns");
+            let configs = match self.read_config().await {
+                Ok(configs) => configs,
+                Err(e) => {
+                    error!("refresh tokens, but read_config err: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = self.update_configs(configs, "token refresh").await {
+                error!("get all ns err: {}", e);
+            }
         }
+    }
 
-        let auth = RegistryAuth::new(
-            cfg.username.clone(),
-            cfg.password.clone(),
-            cfg.server.clone(),
+    /// Write the per registry coverage to the status annotation of the config
+    /// secret and publish changes of it as events on the config secret, and
+    /// to the status of the RegistryCredentials.
+    pub async fn report_status(&self) -> Result<()> {
+        let (cfg_ns, cfg_name) = (&self.settings.config_namespace, &self.settings.config_name);
+        info!(
+            "reporting status on secret '{}/{}' every {:?} ...",
+            cfg_ns, cfg_name, STATUS_INTERVAL
         );
+        let secret_api = Api::<Secret>::namespaced(self.source.clone(), cfg_ns);
+        let object = events::object_ref("Secret", Some(cfg_ns), cfg_name);
 
-        let key = ".dockerconfigjson";
-
-        let secret_api = Api::<Secret>::namespaced(self.client.clone(), self.cfg_ns);
-        match secret_api.get(&cfg.server).await {
-            Ok(s) => {
-                if let Some(map) = s.data {
-                    if let Some(data) = map.get(key) {
-                        if base64::encode(&data.0) != auth.base64_encode() {
-                            let js = json!({ "data": {key: auth.base64_encode() } });
-                            let p = serde_json::to_vec(&js)?;
-                            let pp = PatchParams::default();
-                            secret_api.patch(&cfg.server, &pp, p).await?;
-                        }
-                    } else {
-                        warn!("not found {} in map", key);
+        let mut last = BTreeMap::new();
+        let mut ticker = tokio::time::interval(STATUS_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let summary = self.summary();
+            if self.settings.registry_credentials {
+                if let Err(e) = self.write_registry_credential_status(&summary).await {
+                    warn!("write {} status err: {}", crd::KIND, e);
+                }
+            }
+            if summary == last {
+                continue;
+            }
+            // the config secret is optional with RegistryCredentials, and
+            // applying the annotation would create it
+            if self.settings.registry_credentials {
+                match secret_api.get(cfg_name).await {
+                    Ok(_) => {}
+                    Err(kube::Error::Api(e)) if e.code == 404 => {
+                        last = summary;
+                        continue;
                     }
-                } else {
-                    warn!("secret's data field is None");
-                }
-            }
-            Err(kube::Error::Api(e)) => {
-                if e.code == 404 {
-                    let s: Secret = serde_json::from_value(json!({
-                            "apiVersion": "v1",
-                            "data": {
-                                ".dockerconfigjson": auth.base64_encode(),
-                            },
-                            "kind": "Secret",
-                            "metadata": {
-                                "name": cfg.server,
-                                "namespace": ns,
-                            },
-                            "type": "kubernetes.io/dockerconfigjson"
-                        }
-                    ))?;
+                    Err(e) => {
+                        warn!("get config secret err: {}", e);
+                        continue;
+                    }
+                }
+            }
 
-                    let pp = PostParams::default();
+            let mut status = json!({
+                "updatedAt": chrono::Utc::now(),
+                "registries": summary,
+            });
+            if !self.remotes.is_empty() {
+                let mut clusters = BTreeMap::new();
+                for remote in self.remotes.iter() {
+                    let cluster = remote.cluster.clone().unwrap_or_default();
+                    clusters.insert(cluster, remote.coverage.summary());
+                }
+                status["local"] = json!(self.coverage.summary());
+                status["clusters"] = json!(clusters);
+            }
+            let obj = json!({
+                "apiVersion": "v1",
+                "kind": "Secret",
+                "metadata": {
+                    "name": cfg_name,
+                    "namespace": cfg_ns,
+                    "annotations": { STATUS_ANNOTATION: status.to_string() },
+                },
+            });
+            if let Err(e) = self
+                .apply(&secret_api, "secret", cfg_ns, cfg_name, &obj, true)
+                .await
+            {
+                warn!("write status annotation err: {}", e);
+                continue;
+            }
 
-                    secret_api.create(&pp, &s).await?;
+            for (server, status) in summary.iter() {
+                if last.get(server) == Some(status) {
+                    continue;
                 }
+                let (type_, reason, message) = if let Some(reason) = &status.skipped {
+                    (
+                        EventType::Warning,
+                        events::REASON_SKIPPED,
+                        format!("registry '{}' skipped: {}", server, reason),
+                    )
+                } else if status.failed > 0 {
+                    (
+                        EventType::Warning,
+                        events::REASON_FAILED,
+                        format!(
+                            "registry '{}' failed in {} of {} namespaces ({}): {}",
+                            server,
+                            status.failed,
+                            status.failed + status.synced,
+                            status.failed_namespaces.join(", "),
+                            status.last_error.as_deref().unwrap_or_default()
+                        ),
+                    )
+                } else {
+                    (
+                        EventType::Normal,
+                        events::REASON_SYNCED,
+                        format!(
+                            "registry '{}' synced to {} namespaces",
+                            server, status.synced
+                        ),
+                    )
+                };
+                self.recorder
+                    .publish(cfg_ns, &object, type_, reason, &message)
+                    .await;
             }
-            Err(e) => return Err(anyhow!("query {} err: {}", cfg.server, e)),
+            last = summary;
         }
+    }
 
-        Ok(false)
+    // apply the status of every RegistryCredential which changed
+    async fn write_registry_credential_status(
+        &self,
+        summary: &BTreeMap<String, RegistryStatus>,
+    ) -> Result<()> {
+        let api = Api::<RegistryCredential>::all(self.source.clone());
+        let items = api.list(&ListParams::default()).await?;
+        let rejected = self.rejected.lock().unwrap().clone();
+        let now = chrono::Utc::now();
+        for item in items.iter() {
+            let name = item.name();
+            let status = match crd::status(
+                item,
+                summary.get(&item.spec.server),
+                rejected.get(&name),
+                now,
+            ) {
+                Some(status) if Some(&status) != item.status.as_ref() => status,
+                _ => continue,
+            };
+            let obj = json!({
+                "apiVersion": <RegistryCredential as k8s_openapi::Resource>::API_VERSION,
+                "kind": crd::KIND,
+                "metadata": { "name": name },
+                "status": status,
+            });
+            let pp = PatchParams {
+                force: true,
+                ..PatchParams::apply(&self.settings.field_manager)
+            };
+            if let Err(e) = api
+                .patch_status(&name, &pp, serde_json::to_vec(&obj)?)
+                .await
+            {
+                warn!("apply {} '{}' status err: {}", crd::KIND, name, e);
+            }
+        }
+        Ok(())
     }
 
-    async fn ensure_patch_sa(&self, ns: &str, secret_name: &str) -> Result<()> {
-        let sa_api = Api::<ServiceAccount>::namespaced(self.client.clone(), ns);
+    pub async fn get_all_ns(&self) -> Result<Vec<Namespace>> {
+        if self.settings.namespace_scoped() {
+            let all_ns = self
+                .settings
+                .watch_namespaces
+                .iter()
+                .map(|ns| scoped_ns(ns));
+            return Ok(all_ns.collect());
+        }
+        let ns_api = Api::<Namespace>::all(self.client.clone());
 
-        let mut found = false;
-        let mut new_secrets: Vec<LocalObjectReference> = Vec::new();
+        let all_ns = ns_api.list(&self.ns_list_params()).await?;
 
-        match sa_api.get(self.sa_name).await {
-            Ok(sa) => {
-                if let Some(ipss) = sa.image_pull_secrets {
-                    for item in ipss {
-                        if item.name == Some(String::from(secret_name)) {
-                            found = true
-                        }
-                        new_secrets.push(item);
-                    }
+        Ok(all_ns.items)
+    }
+
+    async fn ensure_registry_secret(
+        &self,
+        ns: &str,
+        spec: &SecretSpec<'_>,
+    ) -> SyncResult<(SecretChange, Digests)> {
+        let name = spec.name;
+        let secret_api = Api::<Secret>::namespaced(self.client.clone(), ns);
+        let existing = match secret_api.get(name).await {
+            Ok(s) => Some(s),
+            Err(e) => match SyncError::from_kube(e, "get", "secret", ns, name) {
+                e if e.is_not_found() => None,
+                e => return Err(e),
+            },
+        };
+
+        let mut auth = spec.auth.clone();
+        let mut servers = spec.servers.to_string();
+        let current = existing
+            .as_ref()
+            .and_then(docker_config)
+            .filter(|_| !spec.keep.is_empty())
+            .and_then(|data| serde_json::from_slice::<RegistryAuth>(&data.0).ok());
+        if let Some(current) = current {
+            for server in auth.keep(&current, spec.keep) {
+                servers.push(',');
+                servers.push_str(&server);
+            }
+        }
+        let servers = servers.as_str();
+        let want = auth.base64_encode();
+        let mut digests = Digests {
+            before: None,
+            after: base64::decode(&want).ok().map(|data| audit::digest(&data)),
+        };
+
+        match existing {
+            Some(s) => {
+                let current = docker_config(&s).map(|data| base64::encode(&data.0));
+                digests.before = docker_config(&s).map(|data| audit::digest(&data.0));
+                // a stripped label or annotation is drift too
+                let labeled = s
+                    .metadata
+                    .labels
+                    .as_ref()
+                    .and_then(|l| l.get(MANAGED_BY_LABEL))
+                    .is_some_and(|v| v == MANAGED_BY);
+                let annotated = s
+                    .metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.get(SERVER_ANNOTATION))
+                    .is_some_and(|v| v == servers);
+                let drifted: Vec<&'static str> = [
+                    (current.as_ref() == Some(&want), "data"),
+                    (labeled, "labels"),
+                    (annotated, "annotations"),
+                ]
+                .iter()
+                .filter(|(ok, _)| !ok)
+                .map(|(_, field)| *field)
+                .collect();
+                if drifted.is_empty() {
+                    return Ok((SecretChange::Unchanged, digests));
+                }
+                if self.dry_run {
+                    return Ok((SecretChange::Patched(drifted), digests));
                 }
+
+                info!("apply secret '{}/{}'", ns, name);
+                self.apply_secret(&secret_api, ns, name, &want, servers)
+                    .await?;
+                Ok((SecretChange::Patched(drifted), digests))
+            }
+            None => {
+                if self.dry_run {
+                    return Ok((SecretChange::Created, digests));
+                }
+                info!("create secret '{}/{}'", ns, name);
+                self.apply_secret(&secret_api, ns, name, &want, servers)
+                    .await?;
+                Ok((SecretChange::Created, digests))
             }
-            Err(e) => return Err(anyhow!("get {}/default sa err: {}", ns, e)),
         }
+    }
 
-        if !found {
-            let p = serde_json::to_vec(&json!({ "imagePullSecrets": new_secrets }))?;
-            let pp = PatchParams::default();
-            sa_api.patch(self.sa_name, &pp, p).await?;
+    // apply the whole secret, we are its only rightful owner so fields
+    // changed by anyone else are taken over
+    async fn apply_secret(
+        &self,
+        secret_api: &Api<Secret>,
+        ns: &str,
+        name: &str,
+        data: &str,
+        servers: &str,
+    ) -> SyncResult<()> {
+        let obj = json!({
+            "apiVersion": "v1",
+            "kind": "Secret",
+            "metadata": {
+                "name": name,
+                "namespace": ns,
+                "labels": { MANAGED_BY_LABEL: MANAGED_BY },
+                "annotations": { SERVER_ANNOTATION: servers },
+            },
+            "type": "kubernetes.io/dockerconfigjson",
+            "data": { DOCKER_CONFIG_KEY: data },
+        });
+        match self
+            .apply(secret_api, "secret", ns, name, &obj, false)
+            .await
+        {
+            Err(e) if e.is_conflict() => {
+                warn!(
+                    "secret '{}/{}' was changed by another field manager, take it over: {}",
+                    ns, name, e
+                );
+                self.apply(secret_api, "secret", ns, name, &obj, true).await
+            }
+            result => result,
         }
+    }
 
+    // server-side apply `obj` as our field manager, `force` takes over the
+    // fields owned by other managers instead of failing with a conflict
+    async fn apply<K>(
+        &self,
+        api: &Api<K>,
+        kind: &'static str,
+        ns: &str,
+        name: &str,
+        obj: &serde_json::Value,
+        force: bool,
+    ) -> SyncResult<()>
+    where
+        K: Clone + DeserializeOwned + Meta,
+    {
+        let p = serde_json::to_vec(obj).map_err(|source| SyncError::Encode {
+            kind,
+            ns: ns.to_string(),
+            name: name.to_string(),
+            source,
+        })?;
+        let pp = PatchParams {
+            force,
+            ..PatchParams::apply(&self.settings.field_manager)
+        };
+        api.patch(name, &pp, p)
+            .await
+            .map_err(|e| SyncError::from_kube(e, "apply", kind, ns, name))?;
         Ok(())
     }
 
-    async fn read_config(&self) -> Result<Vec<Config>> {
-        let secret_api = Api::<Secret>::namespaced(self.client.clone(), self.cfg_ns);
-        let secret = secret_api.get(self.cfg_name).await?;
+    async fn ensure_patch_sa(
+        &self,
+        ns: &str,
+        sa_name: &str,
+        secret_name: &str,
+    ) -> SyncResult<Option<Change>> {
+        self.update_sa_secrets(ns, sa_name, false, |mut secrets| {
+            if secrets
+                .iter()
+                .any(|item| item.name.as_deref() == Some(secret_name))
+            {
+                return None;
+            }
+            secrets.push(LocalObjectReference {
+                name: Some(String::from(secret_name)),
+            });
+            Some((secrets, format!("imagePullSecrets +{}", secret_name)))
+        })
+        .await
+    }
+
+    // drop the references to the `stale` secrets, the `renamed` ones are
+    // replaced by their new name at the same position
+    async fn remove_sa_refs(
+        &self,
+        ns: &str,
+        sa_name: &str,
+        stale: &[String],
+        renamed: &BTreeMap<String, String>,
+    ) -> SyncResult<Option<Change>> {
+        self.update_sa_secrets(ns, sa_name, true, |secrets| {
+            let mut kept: Vec<LocalObjectReference> = Vec::new();
+            let mut details = Vec::new();
+            for item in secrets.into_iter() {
+                let name = match &item.name {
+                    Some(name) if stale.contains(name) => name,
+                    _ => {
+                        kept.push(item);
+                        continue;
+                    }
+                };
+                match renamed.get(name) {
+                    Some(new) => {
+                        details.push(format!("{}->{}", name, new));
+                        kept.push(LocalObjectReference {
+                            name: Some(new.clone()),
+                        });
+                    }
+                    None => details.push(format!("-{}", name)),
+                }
+            }
+            if details.is_empty() {
+                return None;
+            }
+            // the sync appended the new name already, the first one stays
+            let mut seen = HashSet::new();
+            kept.retain(|item| match &item.name {
+                Some(name) if renamed.values().any(|new| new == name) => seen.insert(name.clone()),
+                _ => true,
+            });
+            Some((kept, format!("imagePullSecrets {}", details.join(" "))))
+        })
+        .await
+    }
+
+    // apply the imagePullSecrets `update` derives from the current ones.
+    // imagePullSecrets is an atomic list without a merge key, so the whole
+    // list is applied guarded by the resourceVersion and computed again from
+    // a fresh read when the service account changed in between.
+    async fn update_sa_secrets<F>(
+        &self,
+        ns: &str,
+        sa_name: &str,
+        missing_ok: bool,
+        update: F,
+    ) -> SyncResult<Option<Change>>
+    where
+        F: Fn(Vec<LocalObjectReference>) -> Option<(Vec<LocalObjectReference>, String)>,
+    {
+        let sa_api = Api::<ServiceAccount>::namespaced(self.client.clone(), ns);
+        let mut conflicts = 0;
+        loop {
+            let sa = match sa_api.get(sa_name).await {
+                Ok(sa) => sa,
+                Err(e) => match SyncError::from_kube(e, "get", "serviceaccount", ns, sa_name) {
+                    e if e.is_not_found() && missing_ok => return Ok(None),
+                    e => return Err(e),
+                },
+            };
+            let (secrets, detail) = match update(sa.image_pull_secrets.unwrap_or_default()) {
+                Some(update) => update,
+                None => return Ok(None),
+            };
+            let change = Change {
+                action: Action::Patch,
+                kind: "serviceaccount",
+                namespace: ns.to_string(),
+                name: sa_name.to_string(),
+                detail: Some(detail),
+            };
+            if self.dry_run {
+                return Ok(Some(change));
+            }
+
+            info!(
+                "apply sa '{}/{}': {}",
+                ns,
+                sa_name,
+                change.detail.as_ref().unwrap()
+            );
+            let obj = json!({
+                "apiVersion": "v1",
+                "kind": "ServiceAccount",
+                "metadata": {
+                    "name": sa_name,
+                    "namespace": ns,
+                    "resourceVersion": sa.metadata.resource_version,
+                },
+                "imagePullSecrets": secrets,
+            });
+            match self
+                .apply(&sa_api, "serviceaccount", ns, sa_name, &obj, true)
+                .await
+            {
+                Ok(()) => return Ok(Some(change)),
+                Err(e) if e.is_conflict() && conflicts < SA_APPLY_RETRIES => {
+                    conflicts += 1;
+                    debug!("sa '{}/{}' changed concurrently, retry", ns, sa_name);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// The configs of the config secret followed by the RegistryCredentials
+    /// when they are enabled, the config secret is optional then.
+    pub async fn read_config(&self) -> Result<Vec<Config>> {
+        let secret_api =
+            Api::<Secret>::namespaced(self.source.clone(), &self.settings.config_namespace);
+        let legacy = match secret_api.get(&self.settings.config_name).await {
+            Ok(secret) => self.read_data(secret).await?,
+            Err(kube::Error::Api(e)) if e.code == 404 && self.settings.registry_credentials => {
+                self.revision.lock().unwrap().secret = None;
+                vec![]
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !self.settings.registry_credentials {
+            return Ok(legacy);
+        }
 
-        self.read_data(secret).await
+        let api = Api::<RegistryCredential>::all(self.source.clone());
+        let items = api
+            .list(&ListParams::default())
+            .await
+            .context("list registrycredentials")?;
+        self.revision.lock().unwrap().registry_credentials =
+            items.metadata.resource_version.clone();
+        let (configs, rejected) = crd::merge(legacy, items.items, &self.settings);
+        for (name, r) in rejected.iter() {
+            warn!("skip {} '{}': {}", crd::KIND, name, r.message);
+        }
+        if !self.dry_run {
+            *self.rejected.lock().unwrap() = rejected;
+        }
+        Ok(configs)
     }
 
     async fn read_data(&self, secret: Secret) -> Result<Vec<Config>> {
+        self.revision.lock().unwrap().secret = secret.metadata.resource_version.clone();
+        let (ns, name, key) = (
+            &self.settings.config_namespace,
+            &self.settings.config_name,
+            &self.settings.config_key,
+        );
         match secret.data {
-            Some(map) => match map.get(self.cfg_data_key) {
-                Some(byte_str) => {
-                    let configs: Vec<Config> = serde_yaml::from_slice(&byte_str.0)?;
-                    return Ok(configs);
-                }
-                None => Err(anyhow!("read secret data field {} err", self.cfg_data_key)),
+            Some(map) => match map.get(key) {
+                Some(byte_str) => validate::parse_configs(&byte_str.0, &self.settings)
+                    .with_context(|| {
+                        format!(
+                            "invalid registry configs in secret '{}/{}' key '{}'",
+                            ns, name, key
+                        )
+                    }),
+                None => Err(anyhow!("secret '{}/{}' has no key '{}'", ns, name, key)),
             },
-            None => Err(anyhow!("read secret data err")),
+            None => Err(anyhow!("secret '{}/{}' has no data", ns, name)),
         }
     }
 }
@@ -0,0 +1,137 @@
+mod support;
+
+use imagepullsecret_sync::{
+    audit::{self, AuditLog},
+    settings::Settings,
+    worker::SyncWorker,
+};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use support::{namespace, secret, service_account, MockApiServer};
+
+fn config_secret(password: &str) -> Value {
+    let configs = format!(
+        "- server: registry.example.com\n  username: user\n  password: {}\n  namespaces: [team-a]\n",
+        password
+    );
+    json!({ "registry_secrets": base64::encode(configs) })
+}
+
+fn entries(path: &std::path::Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+// the digest of the .dockerconfigjson of a secret in the api
+fn digest(api: &MockApiServer, ns: &str, name: &str) -> String {
+    let s = api.get("secrets", ns, name).unwrap();
+    let data = base64::decode(s["data"][".dockerconfigjson"].as_str().unwrap()).unwrap();
+    audit::digest(&data)
+}
+
+#[tokio::test]
+async fn every_change_is_audited() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &["old.io"]));
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        config_secret("first-pass"),
+    ));
+    let mut orphan = secret(
+        "team-a",
+        "old.io",
+        json!({ "app.kubernetes.io/managed-by": "imagepullsecret-sync" }),
+        json!({ ".dockerconfigjson": base64::encode("{\"auths\":{}}") }),
+    );
+    orphan["metadata"]["annotations"] = json!({ "imagepullsecret-sync/server": "old.io" });
+    api.insert(orphan);
+
+    let path = std::env::temp_dir().join(format!("ips-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let log = AuditLog::open(path.to_str().unwrap()).unwrap();
+    let worker = SyncWorker::new(api.client(), Settings::default())
+        .unwrap()
+        .with_audit(Arc::new(log));
+
+    let configs = worker.read_config().await.unwrap();
+    worker
+        .update_configs(configs, "config secret changed")
+        .await
+        .unwrap();
+    let report = worker.reconcile("team-a").await.unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+    let created = digest(&api, "team-a", "registry.example.com");
+    let revision = api.get("secrets", "default", "docker-registry").unwrap()["metadata"]
+        ["resourceVersion"]
+        .as_str()
+        .map(|rv| format!("secret={}", rv));
+    let logged = entries(&path);
+    let summary: Vec<(&str, &str, &str)> = logged
+        .iter()
+        .map(|e| {
+            (
+                e["action"].as_str().unwrap(),
+                e["kind"].as_str().unwrap(),
+                e["name"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("create", "secret", "registry.example.com"),
+            ("patch", "serviceaccount", "default"),
+            ("patch", "serviceaccount", "default"),
+            ("delete", "secret", "old.io"),
+        ]
+    );
+    for e in logged.iter() {
+        assert_eq!(e["trigger"], "config secret changed");
+        assert_eq!(e["namespace"], "team-a");
+        assert_eq!(e["configRevision"].as_str().map(String::from), revision);
+    }
+    assert_eq!(logged[0]["after"], created.as_str());
+    assert!(logged[0].get("before").is_none());
+    assert_eq!(
+        logged[3]["before"],
+        audit::digest(b"{\"auths\":{}}").as_str()
+    );
+    assert!(logged[3].get("after").is_none());
+    assert!(logged[1].get("after").is_none());
+
+    // a rotated password patches the secret, from the old digest to the new
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        config_secret("second-pass"),
+    ));
+    let configs = worker.read_config().await.unwrap();
+    worker.update_configs(configs, "resync").await.unwrap();
+    worker.reconcile("team-a").await.unwrap();
+    let logged = entries(&path);
+    assert_eq!(logged.len(), 5);
+    assert_eq!(logged[4]["action"], "patch");
+    assert_eq!(logged[4]["detail"], "data");
+    assert_eq!(logged[4]["trigger"], "resync");
+    assert_eq!(logged[4]["before"], created.as_str());
+    assert_eq!(
+        logged[4]["after"],
+        digest(&api, "team-a", "registry.example.com").as_str()
+    );
+
+    // nothing changed, nothing logged
+    worker.reconcile("team-a").await.unwrap();
+    assert_eq!(entries(&path).len(), 5);
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(!text.contains("pass"));
+    let _ = std::fs::remove_file(&path);
+}
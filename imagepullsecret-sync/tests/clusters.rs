@@ -0,0 +1,123 @@
+mod support;
+
+use imagepullsecret_sync::{settings::Settings, worker::SyncWorker};
+use serde_json::{json, Value};
+use support::{namespace, secret, service_account, MockApiServer};
+
+fn pull_secrets(api: &MockApiServer, ns: &str, sa: &str) -> Vec<String> {
+    let sa = api.get("serviceaccounts", ns, sa).unwrap();
+    sa["imagePullSecrets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn cluster(namespaces: &[&str]) -> MockApiServer {
+    let api = MockApiServer::start().await;
+    for n in namespaces {
+        api.insert(namespace(n, json!({})));
+        api.insert(service_account(n, "default", &[]));
+    }
+    api
+}
+
+#[tokio::test]
+async fn configs_are_synced_into_every_cluster() {
+    // the management cluster has the config secret and the credentials
+    let source = cluster(&["default", "infra"]).await;
+    let configs = r#"
+- server: registry.example.com
+  credentials:
+    secret_ref:
+      namespace: infra
+      name: registry-creds
+  namespaces: ["team-*"]
+"#;
+    source.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(configs) }),
+    ));
+    source.insert(secret(
+        "infra",
+        "registry-creds",
+        json!({}),
+        json!({ "username": base64::encode("user"), "password": base64::encode("pass") }),
+    ));
+    let edge = cluster(&["team-a", "team-b"]).await;
+    edge.fail(
+        "PATCH",
+        "/api/v1/namespaces/team-b/secrets/registry.example.com",
+        403,
+    );
+    // one cluster being down must not stop the others
+    let down = cluster(&["team-a"]).await;
+    down.fail("GET", "/api/v1/namespaces", 500);
+
+    let settings = Settings {
+        credential_namespaces: vec!["infra".to_string()],
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(source.client(), settings).unwrap();
+    let remotes = vec![
+        worker.remote("edge", edge.client()),
+        worker.remote("down", down.client()),
+    ];
+    let worker = worker.with_remotes(remotes);
+
+    let configs = worker.read_config().await.unwrap();
+    worker.update_configs(configs, "test").await.unwrap();
+    let edge_worker = &worker.remotes()[0];
+    assert_eq!(edge_worker.queue().len(), 2);
+    assert_eq!(worker.remotes()[1].queue().len(), 0);
+
+    edge_worker.reconcile("team-a").await.unwrap();
+    let report = edge_worker.reconcile("team-b").await.unwrap();
+    assert_eq!(report.failed, vec!["registry.example.com"]);
+
+    let synced = edge
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(synced["type"], "kubernetes.io/dockerconfigjson");
+    assert_eq!(
+        pull_secrets(&edge, "team-a", "default"),
+        vec!["registry.example.com"]
+    );
+    assert!(source
+        .get("secrets", "team-a", "registry.example.com")
+        .is_none());
+
+    let summary = worker.summary();
+    let status = &summary["registry.example.com"];
+    assert_eq!((status.synced, status.failed), (1, 1));
+    assert_eq!(status.failed_namespaces, vec!["edge/team-b"]);
+
+    let _ = tokio::time::timeout(
+        std::time::Duration::from_millis(300),
+        worker.report_status(),
+    )
+    .await;
+    let cfg = source.get("secrets", "default", "docker-registry").unwrap();
+    let annotation = cfg["metadata"]["annotations"]["imagepullsecret-sync/status"]
+        .as_str()
+        .unwrap();
+    let status: Value = serde_json::from_str(annotation).unwrap();
+    assert_eq!(status["registries"]["registry.example.com"]["synced"], 1);
+    assert_eq!(
+        status["clusters"]["edge"]["registry.example.com"]["failedNamespaces"],
+        json!(["team-b"])
+    );
+    assert_eq!(
+        status["clusters"]["down"]["registry.example.com"]["synced"],
+        0
+    );
+    assert_eq!(status["local"]["registry.example.com"]["synced"], 0);
+
+    let text = worker.metrics().encode();
+    assert!(text.contains(
+        r#"imagepullsecret_sync_secrets_total{action="created",namespace="team-a",cluster="edge"} 1"#
+    ));
+}
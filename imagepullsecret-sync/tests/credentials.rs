@@ -0,0 +1,168 @@
+mod support;
+
+use imagepullsecret_sync::{
+    config::{Config, RegistryAuth},
+    credentials::CredentialSource,
+    settings::Settings,
+    worker::SyncWorker,
+};
+use k8s_openapi::api::core::v1::Namespace;
+use serde_json::json;
+use support::{namespace, secret, service_account, MockApiServer};
+
+fn ns(name: &str) -> Namespace {
+    serde_json::from_value(namespace(name, json!({}))).unwrap()
+}
+
+async fn setup() -> (MockApiServer, SyncWorker) {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let worker = SyncWorker::new(api.client(), Settings::default()).unwrap();
+    (api, worker)
+}
+
+fn want(server: &str, username: &str, password: &str) -> String {
+    RegistryAuth::new(
+        username.to_string(),
+        password.to_string(),
+        server.to_string(),
+    )
+    .base64_encode()
+}
+
+#[tokio::test]
+async fn secret_ref_in_other_namespace() {
+    let (api, worker) = setup().await;
+    api.insert(secret(
+        "infra",
+        "registry-creds",
+        json!({}),
+        json!({ "user": base64::encode("robot"), "token": base64::encode("s3cret") }),
+    ));
+    let cfg = Config {
+        server: "registry.example.com".to_string(),
+        namespaces: vec!["*".to_string()],
+        credentials: Some(CredentialSource::SecretRef {
+            namespace: "infra".to_string(),
+            name: "registry-creds".to_string(),
+            username_key: "user".to_string(),
+            password_key: "token".to_string(),
+        }),
+        ..Config::default()
+    };
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(reports[0].errors.is_empty());
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(
+        s["data"][".dockerconfigjson"],
+        want("registry.example.com", "robot", "s3cret")
+    );
+}
+
+#[tokio::test]
+async fn http_vault_kv() {
+    let (api, worker) = setup().await;
+    api.document(
+        "/v1/secret/data/registry",
+        Some("vault-token"),
+        json!({ "data": { "data": { "username": "robot", "password": "from-vault" } } }),
+    );
+    std::env::set_var("IPS_TEST_VAULT_TOKEN", "vault-token");
+    let cfg = Config {
+        server: "registry.example.com".to_string(),
+        namespaces: vec!["*".to_string()],
+        credentials: Some(CredentialSource::Http {
+            url: format!("{}/v1/secret/data/registry", api.url()),
+            token_env: Some("IPS_TEST_VAULT_TOKEN".to_string()),
+            token_header: "X-Vault-Token".to_string(),
+            username_pointer: "/data/data/username".to_string(),
+            password_pointer: "/data/data/password".to_string(),
+        }),
+        ..Config::default()
+    };
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(reports[0].errors.is_empty());
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(
+        s["data"][".dockerconfigjson"],
+        want("registry.example.com", "robot", "from-vault")
+    );
+}
+
+#[tokio::test]
+async fn failing_source_keeps_existing_secret() {
+    let (api, worker) = setup().await;
+    let ok = Config {
+        server: "ok.example.com".to_string(),
+        username: "u".to_string(),
+        password: "p".to_string(),
+        namespaces: vec!["*".to_string()],
+        ..Config::default()
+    };
+    let broken = Config {
+        server: "broken.example.com".to_string(),
+        namespaces: vec!["*".to_string()],
+        ..ok.clone()
+    };
+    worker
+        .ensure(vec![ns("team-a")], vec![ok.clone(), broken.clone()])
+        .await;
+    assert!(api.get("secrets", "team-a", "broken.example.com").is_some());
+
+    let broken = Config {
+        credentials: Some(CredentialSource::SecretRef {
+            namespace: "infra".to_string(),
+            name: "missing".to_string(),
+            username_key: "username".to_string(),
+            password_key: "password".to_string(),
+        }),
+        ..broken
+    };
+    let reports = worker
+        .ensure(vec![ns("team-a")], vec![ok.clone(), broken.clone()])
+        .await;
+
+    assert!(reports[0].deleted.is_empty());
+    assert!(api.get("secrets", "team-a", "broken.example.com").is_some());
+
+    // a merged secret keeps the entry of the broken registry
+    let settings = Settings {
+        merge_secret_name: Some("registries".to_string()),
+        ..Settings::default()
+    };
+    let merged = SyncWorker::new(api.client(), settings).unwrap();
+    let working = Config {
+        credentials: None,
+        ..broken.clone()
+    };
+    merged
+        .ensure(vec![ns("team-a")], vec![ok.clone(), working.clone()])
+        .await;
+    let rotated = Config {
+        password: "p2".to_string(),
+        ..ok
+    };
+    let reports = merged
+        .ensure(vec![ns("team-a")], vec![rotated.clone(), broken])
+        .await;
+
+    assert!(reports[0].errors.is_empty());
+    let s = api.get("secrets", "team-a", "registries").unwrap();
+    assert_eq!(
+        s["data"][".dockerconfigjson"],
+        RegistryAuth::from_configs(&[rotated, working]).base64_encode()
+    );
+    assert_eq!(
+        s["metadata"]["annotations"]["imagepullsecret-sync/server"],
+        "ok.example.com,broken.example.com"
+    );
+}
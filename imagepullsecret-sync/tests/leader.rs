@@ -0,0 +1,95 @@
+mod support;
+
+use chrono::{Duration, Utc};
+use imagepullsecret_sync::leader::LeaderElector;
+use serde_json::json;
+use support::MockApiServer;
+
+const LEASE: &str = "imagepullsecret-sync";
+
+fn elector(api: &MockApiServer, identity: &str) -> LeaderElector {
+    LeaderElector::new(
+        api.client(),
+        "infra",
+        LEASE,
+        identity,
+        Duration::seconds(15),
+    )
+}
+
+fn holder(api: &MockApiServer) -> serde_json::Value {
+    api.get("leases", "infra", LEASE).unwrap()["spec"]["holderIdentity"].clone()
+}
+
+#[tokio::test]
+async fn only_one_replica_leads() {
+    let api = MockApiServer::start().await;
+    let (a, b) = (elector(&api, "pod-a"), elector(&api, "pod-b"));
+
+    assert!(a.try_acquire_or_renew().await.unwrap());
+    assert!(!b.try_acquire_or_renew().await.unwrap());
+    assert_eq!(holder(&api), "pod-a");
+
+    // renewing keeps the lease
+    assert!(a.try_acquire_or_renew().await.unwrap());
+    assert!(!b.try_acquire_or_renew().await.unwrap());
+}
+
+#[tokio::test]
+async fn release_hands_over() {
+    let api = MockApiServer::start().await;
+    let (a, b) = (elector(&api, "pod-a"), elector(&api, "pod-b"));
+
+    assert!(a.try_acquire_or_renew().await.unwrap());
+    b.release().await.unwrap();
+    assert_eq!(holder(&api), "pod-a");
+
+    a.release().await.unwrap();
+    assert!(holder(&api).is_null());
+    assert!(b.try_acquire_or_renew().await.unwrap());
+
+    let lease = api.get("leases", "infra", LEASE).unwrap();
+    assert_eq!(lease["spec"]["holderIdentity"], "pod-b");
+    assert_eq!(lease["spec"]["leaseTransitions"], 1);
+}
+
+#[tokio::test]
+async fn expired_lease_is_taken_over() {
+    let api = MockApiServer::start().await;
+    let stale = (Utc::now() - Duration::seconds(60)).to_rfc3339();
+    api.insert(json!({
+        "apiVersion": "coordination.k8s.io/v1",
+        "kind": "Lease",
+        "metadata": { "name": LEASE, "namespace": "infra" },
+        "spec": {
+            "holderIdentity": "pod-gone",
+            "leaseDurationSeconds": 15,
+            "renewTime": stale,
+            "leaseTransitions": 3,
+        },
+    }));
+
+    let b = elector(&api, "pod-b");
+    assert!(b.try_acquire_or_renew().await.unwrap());
+
+    let lease = api.get("leases", "infra", LEASE).unwrap();
+    assert_eq!(lease["spec"]["holderIdentity"], "pod-b");
+    assert_eq!(lease["spec"]["leaseTransitions"], 4);
+}
+
+#[tokio::test]
+async fn lost_lease_ends_keep() {
+    let api = MockApiServer::start().await;
+    let a = elector(&api, "pod-a");
+    assert!(a.try_acquire_or_renew().await.unwrap());
+
+    // another replica took over behind our back
+    let mut lease = api.get("leases", "infra", LEASE).unwrap();
+    lease["spec"]["holderIdentity"] = json!("pod-b");
+    api.insert(lease);
+
+    let kept = a
+        .keep(std::time::Duration::from_millis(10), Duration::seconds(10))
+        .await;
+    assert!(kept.is_err());
+}
@@ -0,0 +1,154 @@
+mod support;
+
+use imagepullsecret_sync::{
+    config::Config,
+    credentials::CredentialSource,
+    plan::{self, Action},
+    settings::Settings,
+    worker::SyncWorker,
+};
+use serde_json::json;
+use support::{namespace, secret, service_account, MockApiServer};
+
+const MANAGED: &str = "app.kubernetes.io/managed-by";
+
+#[tokio::test]
+async fn plan_changes_nothing() {
+    let api = MockApiServer::start().await;
+    for n in ["team-a", "team-b"].iter() {
+        api.insert(namespace(n, json!({})));
+        api.insert(service_account(n, "default", &["old.example.com"]));
+    }
+    api.insert(secret(
+        "team-a",
+        "registry.example.com",
+        json!({ MANAGED: "imagepullsecret-sync" }),
+        json!({ ".dockerconfigjson": "b2xk" }),
+    ));
+    api.insert(secret(
+        "team-b",
+        "old.example.com",
+        json!({ MANAGED: "imagepullsecret-sync" }),
+        json!({ ".dockerconfigjson": "b2xk" }),
+    ));
+
+    let path = std::env::temp_dir().join(format!("plan-configs-{}.yaml", std::process::id()));
+    std::fs::write(
+        &path,
+        "- server: registry.example.com\n  username: user\n  password: pass\n  namespaces: ['*']\n",
+    )
+    .unwrap();
+
+    let plan = plan::plan(api.client(), Settings::default(), Some(&path))
+        .await
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(api.mutations().is_empty());
+    assert!(plan.errors.is_empty());
+
+    let changes: Vec<String> = plan.changes.iter().map(|c| c.to_string()).collect();
+    assert_eq!(
+        changes,
+        vec![
+            "~ secret team-a/registry.example.com (data, annotations)",
+            "~ serviceaccount team-a/default (imagePullSecrets +registry.example.com)",
+            "+ secret team-b/registry.example.com",
+            "~ serviceaccount team-b/default (imagePullSecrets +registry.example.com)",
+            "~ serviceaccount team-b/default (imagePullSecrets -old.example.com)",
+            "- secret team-b/old.example.com",
+        ]
+    );
+    assert_eq!(
+        plan.changes
+            .iter()
+            .filter(|c| c.action == Action::Delete)
+            .count(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn plan_covers_the_clusters_without_running_sources() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let edge = MockApiServer::start().await;
+    edge.insert(namespace("team-x", json!({})));
+    edge.insert(service_account("team-x", "default", &[]));
+    let down = MockApiServer::start().await;
+    down.fail("GET", "/api/v1/namespaces", 500);
+
+    let marker = std::env::temp_dir().join(format!("plan-exec-{}", std::process::id()));
+    let inline = Config {
+        server: "registry.example.com".to_string(),
+        username: "user".to_string(),
+        password: "pass".to_string(),
+        namespaces: vec!["*".to_string()],
+        ..Config::default()
+    };
+    let exec = Config {
+        server: "ecr.example.com".to_string(),
+        credentials: Some(CredentialSource::Exec {
+            command: "touch".to_string(),
+            args: vec![marker.display().to_string()],
+            username: Some("AWS".to_string()),
+            ttl: 3600,
+        }),
+        ..inline.clone()
+    };
+    let http = Config {
+        server: "vault.example.com".to_string(),
+        credentials: Some(CredentialSource::Http {
+            url: format!("{}/v1/secret/data/registry", api.url()),
+            token_env: None,
+            token_header: "X-Vault-Token".to_string(),
+            username_pointer: "/data/data/username".to_string(),
+            password_pointer: "/data/data/password".to_string(),
+        }),
+        ..inline.clone()
+    };
+
+    let worker = SyncWorker::new(api.client(), Settings::default()).unwrap();
+    let remotes = vec![
+        worker.remote("edge", edge.client()),
+        worker.remote("down", down.client()),
+    ];
+    let worker = worker.with_remotes(remotes);
+    let plan = plan::plan_configs(&worker, vec![inline, exec, http])
+        .await
+        .unwrap();
+
+    assert!(!marker.exists());
+    assert!(api.mutations().is_empty());
+    assert!(edge.mutations().is_empty());
+    let changes: Vec<String> = plan.changes.iter().map(|c| c.to_string()).collect();
+    assert_eq!(
+        changes,
+        vec![
+            "+ secret team-a/registry.example.com",
+            "~ serviceaccount team-a/default (imagePullSecrets +registry.example.com)",
+            "+ secret edge/team-x/registry.example.com",
+            "~ serviceaccount edge/team-x/default (imagePullSecrets +registry.example.com)",
+        ]
+    );
+    let skipped: Vec<(&str, &str)> = plan
+        .skipped
+        .iter()
+        .map(|s| (s.server.as_str(), s.reason.as_str()))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![
+            (
+                "ecr.example.com",
+                "exec credentials are not resolved by a plan"
+            ),
+            (
+                "vault.example.com",
+                "http credentials are not resolved by a plan"
+            ),
+        ]
+    );
+    assert_eq!(plan.errors.len(), 1);
+    assert_eq!(plan.errors[0].namespace, "down/*");
+}
@@ -0,0 +1,148 @@
+mod support;
+
+use imagepullsecret_sync::{settings::Settings, worker::SyncWorker};
+use serde_json::{json, Value};
+use support::{namespace, secret, service_account, MockApiServer};
+
+fn registry_credential(name: &str, created: &str, spec: Value) -> Value {
+    json!({
+        "apiVersion": "imagepullsecret-sync.io/v1alpha1",
+        "kind": "RegistryCredential",
+        "metadata": { "name": name, "creationTimestamp": created, "generation": 1 },
+        "spec": spec,
+    })
+}
+
+fn pull_secrets(api: &MockApiServer, ns: &str, sa: &str) -> Vec<String> {
+    let sa = api.get("serviceaccounts", ns, sa).unwrap();
+    sa["imagePullSecrets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn registry_credentials_are_synced() {
+    let api = MockApiServer::start().await;
+    api.insert(secret(
+        "infra",
+        "b-creds",
+        json!({}),
+        json!({ "username": base64::encode("b-user"), "password": base64::encode("b-pass") }),
+    ));
+    for n in ["team-a", "team-b"].iter() {
+        api.insert(namespace(n, json!({})));
+        api.insert(service_account(n, "default", &[]));
+        api.insert(service_account(n, "builder", &[]));
+    }
+    let legacy = "- server: a.io\n  username: u\n  password: p\n  namespaces: [team-a]\n";
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(legacy) }),
+    ));
+    api.insert(registry_credential(
+        "b",
+        "2021-01-01T00:00:00Z",
+        json!({
+            "server": "b.io",
+            "credentials": { "secret_ref": { "namespace": "infra", "name": "b-creds" } },
+            "namespaces": ["team-b"],
+            "service_accounts": ["builder"],
+        }),
+    ));
+    api.insert(registry_credential(
+        "a-again",
+        "2021-01-02T00:00:00Z",
+        json!({
+            "server": "a.io",
+            "credentials": { "secret_ref": { "namespace": "infra", "name": "b-creds" } },
+            "namespaces": ["*"],
+        }),
+    ));
+    // only references are accepted, an exec would run in the worker
+    api.insert(registry_credential(
+        "c-exec",
+        "2021-01-03T00:00:00Z",
+        json!({
+            "server": "c.io",
+            "credentials": { "exec": { "command": "sh", "args": ["-c", "echo token"] } },
+            "namespaces": ["*"],
+        }),
+    ));
+
+    let settings = Settings {
+        registry_credentials: true,
+        credential_namespaces: vec!["infra".to_string()],
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    let configs = worker.read_config().await.unwrap();
+    let servers: Vec<&str> = configs.iter().map(|c| c.server.as_str()).collect();
+    assert_eq!(servers, vec!["a.io", "b.io"]);
+
+    let all_ns = worker.get_all_ns().await.unwrap();
+    let reports = worker.ensure(all_ns, configs).await;
+    assert!(reports.iter().all(|r| r.errors.is_empty()));
+
+    assert!(api.get("secrets", "team-b", "b.io").is_some());
+    assert!(api.get("secrets", "team-b", "a.io").is_none());
+    // the service accounts of the RegistryCredential replace the default one
+    assert_eq!(pull_secrets(&api, "team-b", "builder"), vec!["b.io"]);
+    assert!(pull_secrets(&api, "team-b", "default").is_empty());
+    assert_eq!(pull_secrets(&api, "team-a", "default"), vec!["a.io"]);
+
+    let _ = tokio::time::timeout(
+        std::time::Duration::from_millis(300),
+        worker.report_status(),
+    )
+    .await;
+    let ready = |name: &str| {
+        let status = api.get("registrycredentials", "", name).unwrap()["status"].clone();
+        let condition = status["conditions"][0].clone();
+        (
+            status["syncedNamespaces"].as_u64(),
+            condition["status"].as_str().map(String::from),
+            condition["reason"].as_str().map(String::from),
+        )
+    };
+    assert_eq!(
+        ready("b"),
+        (
+            Some(1),
+            Some("True".to_string()),
+            Some("Synced".to_string())
+        )
+    );
+    assert_eq!(
+        ready("a-again"),
+        (
+            Some(0),
+            Some("False".to_string()),
+            Some("DuplicateServer".to_string())
+        )
+    );
+    assert_eq!(ready("c-exec").2.as_deref(), Some("InvalidSpec"));
+    assert_eq!(
+        api.owner("registrycredentials", "", "b", "/status/syncedNamespaces")
+            .as_deref(),
+        Some("imagepullsecret-sync")
+    );
+}
+
+#[tokio::test]
+async fn config_secret_is_optional() {
+    let api = MockApiServer::start().await;
+    let worker = SyncWorker::new(api.client(), Settings::default()).unwrap();
+    assert!(worker.read_config().await.is_err());
+
+    let settings = Settings {
+        registry_credentials: true,
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    assert!(worker.read_config().await.unwrap().is_empty());
+}
@@ -0,0 +1,672 @@
+//! A tiny in-memory kubernetes api server, just enough of the REST api for
+//! the sync worker: get/list/create/patch/delete of core and group
+//! resources, label selectors, server-side apply with field ownership and
+//! injected failures.
+#![allow(dead_code)]
+
+pub mod registry;
+
+use hyper::{
+    body::to_bytes,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use imagepullsecret_sync::selector::LabelSelector;
+use serde_json::{json, Map, Value};
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Default)]
+pub struct State {
+    // keyed by "<resource>/<namespace>/<name>", cluster scoped objects
+    // use an empty namespace
+    objects: BTreeMap<String, Value>,
+    // (method, path, code, once) answered with an error status instead,
+    // once only when `once` is set
+    failures: Vec<(String, String, u16, bool)>,
+    // the manager of every field, keyed by object key and json pointer
+    owners: BTreeMap<String, BTreeMap<String, String>>,
+    requests: Vec<(String, String)>,
+    resource_version: u64,
+    // fixed json documents outside of the kubernetes api, e.g. a fake vault,
+    // keyed by path with the expected X-Vault-Token
+    documents: BTreeMap<String, (Option<String>, Value)>,
+}
+
+#[derive(Clone)]
+pub struct MockApiServer {
+    state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+}
+
+impl MockApiServer {
+    pub async fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let svc_state = state.clone();
+        let make_svc = make_service_fn(move |_| {
+            let state = svc_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        MockApiServer { state, addr }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn client(&self) -> kube::Client {
+        kube::Client::new(kube::Config::new(self.url().parse().unwrap()))
+    }
+
+    /// Store an object, its resource is derived from `kind`. Its fields are
+    /// owned by the `kubectl` field manager.
+    pub fn insert(&self, obj: Value) {
+        let mut state = self.state.lock().unwrap();
+        let key = object_key(&obj);
+        state.own(&key, KUBECTL, None, &obj);
+        let obj = state.stamp(obj);
+        state.objects.insert(key, obj);
+    }
+
+    /// The field manager owning the field at the json `pointer`.
+    pub fn owner(&self, resource: &str, ns: &str, name: &str, pointer: &str) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .owners
+            .get(&format!("{}/{}/{}", resource, ns, name))
+            .and_then(|o| o.get(pointer))
+            .cloned()
+    }
+
+    pub fn get(&self, resource: &str, ns: &str, name: &str) -> Option<Value> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .get(&format!("{}/{}/{}", resource, ns, name))
+            .cloned()
+    }
+
+    pub fn list(&self, resource: &str) -> Vec<Value> {
+        let state = self.state.lock().unwrap();
+        let prefix = format!("{}/", resource);
+        state
+            .objects
+            .iter()
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    /// Answer `method` requests to exactly `path` with an error `code`.
+    pub fn fail(&self, method: &str, path: &str, code: u16) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .push((method.to_string(), path.to_string(), code, false));
+    }
+
+    /// Answer the next `method` request to `path` with an error `code`.
+    pub fn fail_once(&self, method: &str, path: &str, code: u16) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .push((method.to_string(), path.to_string(), code, true));
+    }
+
+    /// Serve `doc` on GET `path`, requiring the `X-Vault-Token` header when
+    /// `token` is set.
+    pub fn document(&self, path: &str, token: Option<&str>, doc: Value) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .documents
+            .insert(path.to_string(), (token.map(String::from), doc));
+    }
+
+    /// The (method, path) of every request which changed something.
+    pub fn mutations(&self) -> Vec<(String, String)> {
+        let state = self.state.lock().unwrap();
+        state
+            .requests
+            .iter()
+            .filter(|(m, _)| m != "GET")
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn namespace(name: &str, labels: Value) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Namespace",
+        "metadata": { "name": name, "labels": labels },
+        "status": { "phase": "Active" },
+    })
+}
+
+pub fn service_account(ns: &str, name: &str, pull_secrets: &[&str]) -> Value {
+    let refs: Vec<Value> = pull_secrets.iter().map(|n| json!({ "name": n })).collect();
+    json!({
+        "apiVersion": "v1",
+        "kind": "ServiceAccount",
+        "metadata": { "name": name, "namespace": ns },
+        "imagePullSecrets": refs,
+    })
+}
+
+pub fn secret(ns: &str, name: &str, labels: Value, data: Value) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Secret",
+        "metadata": { "name": name, "namespace": ns, "labels": labels },
+        "data": data,
+    })
+}
+
+impl State {
+    // `manager` takes over the fields of `new` which differ from `old`
+    fn own(&mut self, key: &str, manager: &str, old: Option<&Value>, new: &Value) {
+        let owners = self.owners.entry(key.to_string()).or_default();
+        for (pointer, value) in fields(new) {
+            if old.and_then(|o| o.pointer(&pointer)) != Some(&value) {
+                owners.insert(pointer, manager.to_string());
+            }
+        }
+    }
+
+    fn stamp(&mut self, mut obj: Value) -> Value {
+        self.resource_version += 1;
+        let meta = obj["metadata"].as_object_mut().unwrap();
+        meta.insert(
+            "resourceVersion".to_string(),
+            json!(self.resource_version.to_string()),
+        );
+        meta.entry("uid")
+            .or_insert_with(|| json!(format!("uid-{}", self.resource_version)));
+        obj
+    }
+}
+
+// the field manager of objects stored by the tests and of updates without one
+const KUBECTL: &str = "kubectl";
+
+// the leaf fields of an object as (json pointer, value), lists are atomic
+// like the ones without a merge key
+fn fields(obj: &Value) -> Vec<(String, Value)> {
+    fn walk(v: &Value, pointer: String, out: &mut Vec<(String, Value)>) {
+        match v {
+            Value::Object(map) => {
+                for (k, v) in map {
+                    let k = k.replace('~', "~0").replace('/', "~1");
+                    walk(v, format!("{}/{}", pointer, k), out);
+                }
+            }
+            v => out.push((pointer, v.clone())),
+        }
+    }
+    let mut out = Vec::new();
+    walk(obj, String::new(), &mut out);
+    out.retain(|(p, _)| {
+        ![
+            "/apiVersion",
+            "/kind",
+            "/metadata/name",
+            "/metadata/namespace",
+            "/metadata/resourceVersion",
+            "/metadata/uid",
+        ]
+        .contains(&p.as_str())
+    });
+    out
+}
+
+fn object_key(obj: &Value) -> String {
+    let kind = obj["kind"].as_str().unwrap();
+    format!(
+        "{}/{}/{}",
+        resource_of(kind),
+        obj["metadata"]["namespace"].as_str().unwrap_or(""),
+        obj["metadata"]["name"].as_str().unwrap()
+    )
+}
+
+fn resource_of(kind: &str) -> String {
+    let lower = kind.to_lowercase();
+    if lower.ends_with('s') {
+        format!("{}es", lower)
+    } else {
+        format!("{}s", lower)
+    }
+}
+
+fn kind_of(resource: &str, list: &[Value]) -> String {
+    list.first()
+        .and_then(|obj| obj["kind"].as_str())
+        .map(|k| format!("{}List", k))
+        .unwrap_or_else(|| format!("{}List", resource))
+}
+
+struct Route {
+    api_version: String,
+    resource: String,
+    ns: String,
+    name: Option<String>,
+    // only "status" is served
+    subresource: Option<String>,
+}
+
+fn route(path: &str) -> Option<Route> {
+    let segs: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (api_version, rest) = match segs.as_slice() {
+        ["api", v, rest @ ..] => (v.to_string(), rest),
+        ["apis", g, v, rest @ ..] => (format!("{}/{}", g, v), rest),
+        _ => return None,
+    };
+    let (resource, ns, name, subresource) = match rest {
+        ["namespaces"] => ("namespaces", "", None, None),
+        ["namespaces", name] => ("namespaces", "", Some(*name), None),
+        ["namespaces", ns, resource] => (*resource, *ns, None, None),
+        ["namespaces", ns, resource, name] => (*resource, *ns, Some(*name), None),
+        ["namespaces", ns, resource, name, "status"] => {
+            (*resource, *ns, Some(*name), Some("status"))
+        }
+        [resource] => (*resource, "", None, None),
+        [resource, name] => (*resource, "", Some(*name), None),
+        [resource, name, "status"] => (*resource, "", Some(*name), Some("status")),
+        _ => return None,
+    };
+
+    Some(Route {
+        api_version,
+        resource: resource.to_string(),
+        ns: ns.to_string(),
+        name: name.map(String::from),
+        subresource: subresource.map(String::from),
+    })
+}
+
+fn query_param(query: &str, key: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let mut it = pair.splitn(2, '=');
+        match (it.next(), it.next()) {
+            (Some(k), Some(v)) if k == key => Some(percent_decode(v)),
+            _ => None,
+        }
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(out).unwrap()
+}
+
+fn status(code: u16, reason: &str, message: &str) -> Response<Body> {
+    let body = json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "status": "Failure",
+        "message": message,
+        "reason": reason,
+        "code": code,
+    });
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn ok(code: StatusCode, body: &Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// RFC 7386 json merge patch
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(p) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let t = target.as_object_mut().unwrap();
+            for (k, v) in p {
+                if v.is_null() {
+                    t.remove(k);
+                } else {
+                    merge_patch(t.entry(k.clone()).or_insert(Value::Null), v);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+// check a server-side apply request, returns its field manager or the
+// error status: a missing field manager, a stale resourceVersion or, unless
+// forced, fields changed to other values than those of their managers
+fn apply(
+    patch: &Value,
+    existing: Option<&Value>,
+    query: &str,
+    owners: Option<&BTreeMap<String, String>>,
+) -> Result<String, (u16, &'static str, String)> {
+    let manager = match query_param(query, "fieldManager") {
+        Some(m) if !m.is_empty() => m,
+        _ => {
+            return Err((
+                422,
+                "Invalid",
+                "fieldManager: Required value: is required for apply patch".to_string(),
+            ))
+        }
+    };
+    let existing = match existing {
+        Some(obj) => obj,
+        None => return Ok(manager),
+    };
+
+    let version = &patch["metadata"]["resourceVersion"];
+    if !version.is_null() && version != &existing["metadata"]["resourceVersion"] {
+        return Err((409, "Conflict", "the object has been modified; please apply your changes to the latest version and try again".to_string()));
+    }
+
+    let force = query_param(query, "force").as_deref() == Some("true");
+    let conflicts: Vec<String> = fields(patch)
+        .into_iter()
+        .filter(|(pointer, value)| existing.pointer(pointer) != Some(value))
+        .filter_map(|(pointer, _)| {
+            let owner = owners.and_then(|o| o.get(&pointer))?;
+            if owner == &manager {
+                return None;
+            }
+            Some(format!("conflict with \"{}\": {}", owner, pointer))
+        })
+        .collect();
+    if !force && !conflicts.is_empty() {
+        return Err((
+            409,
+            "Conflict",
+            format!(
+                "Apply failed with {} conflicts: {}",
+                conflicts.len(),
+                conflicts.join(", ")
+            ),
+        ));
+    }
+    Ok(manager)
+}
+
+async fn handle(
+    state: Arc<Mutex<State>>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().unwrap_or("").to_string();
+    let content_type = req
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_string();
+    let vault_token = req
+        .headers()
+        .get("x-vault-token")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let body = to_bytes(req.into_body()).await.unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+    state.requests.push((method.to_string(), path.clone()));
+
+    if let Some(i) = state
+        .failures
+        .iter()
+        .position(|(m, p, _, _)| m == method.as_str() && p == &path)
+    {
+        let code = state.failures[i].2;
+        if state.failures[i].3 {
+            state.failures.remove(i);
+        }
+        let reason = match code {
+            403 => "Forbidden",
+            404 => "NotFound",
+            409 => "Conflict",
+            _ => "InternalError",
+        };
+        return Ok(status(
+            code,
+            reason,
+            &format!("injected {} for {}", code, path),
+        ));
+    }
+
+    if let Some((token, doc)) = state.documents.get(&path) {
+        if token.is_some() && token != &vault_token {
+            return Ok(status(403, "Forbidden", "permission denied"));
+        }
+        return Ok(ok(StatusCode::OK, doc));
+    }
+
+    let r = match route(&path) {
+        Some(r) => r,
+        None => return Ok(status(404, "NotFound", "unknown path")),
+    };
+    let key = |name: &str| format!("{}/{}/{}", r.resource, r.ns, name);
+
+    let resp = match (method, &r.name) {
+        (Method::GET, None) => {
+            let prefix = if r.ns.is_empty() {
+                format!("{}/", r.resource)
+            } else {
+                format!("{}/{}/", r.resource, r.ns)
+            };
+            let selector =
+                query_param(&query, "labelSelector").map(|s| LabelSelector::parse(&s).unwrap());
+            let name_field = query_param(&query, "fieldSelector").and_then(|f| {
+                f.split(',')
+                    .find_map(|t| t.strip_prefix("metadata.name=").map(String::from))
+            });
+            let items: Vec<Value> = state
+                .objects
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix))
+                .map(|(_, v)| v.clone())
+                .filter(|v| {
+                    let labels: BTreeMap<String, String> =
+                        serde_json::from_value(v["metadata"]["labels"].clone()).unwrap_or_default();
+                    selector.as_ref().is_none_or(|s| s.matches(&labels))
+                })
+                .filter(|v| {
+                    name_field
+                        .as_ref()
+                        .is_none_or(|n| v["metadata"]["name"].as_str() == Some(n))
+                })
+                .collect();
+            ok(
+                StatusCode::OK,
+                &json!({
+                    "apiVersion": r.api_version,
+                    "kind": kind_of(&r.resource, &items),
+                    "metadata": { "resourceVersion": state.resource_version.to_string() },
+                    "items": items,
+                }),
+            )
+        }
+        (Method::GET, Some(name)) => match state.objects.get(&key(name)) {
+            Some(obj) => ok(StatusCode::OK, obj),
+            None => status(
+                404,
+                "NotFound",
+                &format!("{} \"{}\" not found", r.resource, name),
+            ),
+        },
+        (Method::POST, None) => {
+            let obj: Value = serde_json::from_slice(&body).unwrap();
+            let name = obj["metadata"]["name"].as_str().unwrap_or("").to_string();
+            let obj_ns = obj["metadata"]["namespace"].as_str().unwrap_or("");
+            if !obj_ns.is_empty() && obj_ns != r.ns {
+                status(
+                    400,
+                    "BadRequest",
+                    "the namespace of the provided object does not match the namespace sent on the request",
+                )
+            } else if state.objects.contains_key(&key(&name)) {
+                status(
+                    409,
+                    "AlreadyExists",
+                    &format!("{} \"{}\" already exists", r.resource, name),
+                )
+            } else {
+                let mut obj = obj;
+                if !r.ns.is_empty() {
+                    obj["metadata"]["namespace"] = json!(r.ns);
+                }
+                state.own(&key(&name), KUBECTL, None, &obj);
+                let obj = state.stamp(obj);
+                state.objects.insert(key(&name), obj.clone());
+                ok(StatusCode::CREATED, &obj)
+            }
+        }
+        (Method::PUT, Some(name)) => {
+            let obj: Value = serde_json::from_slice(&body).unwrap();
+            let current = state.objects.get(&key(name)).cloned();
+            let version = &obj["metadata"]["resourceVersion"];
+            match current {
+                None => status(
+                    404,
+                    "NotFound",
+                    &format!("{} \"{}\" not found", r.resource, name),
+                ),
+                Some(cur)
+                    if !version.is_null() && version != &cur["metadata"]["resourceVersion"] =>
+                {
+                    status(
+                        409,
+                        "Conflict",
+                        &format!("the object {} \"{}\" has been modified", r.resource, name),
+                    )
+                }
+                Some(cur) => {
+                    state.own(&key(name), KUBECTL, Some(&cur), &obj);
+                    let obj = state.stamp(obj);
+                    state.objects.insert(key(name), obj.clone());
+                    ok(StatusCode::OK, &obj)
+                }
+            }
+        }
+        (Method::PATCH, Some(name)) if content_type.contains("apply-patch") => {
+            let mut patch: Value = serde_yaml::from_slice(&body).unwrap();
+            let existing = state.objects.get(&key(name)).cloned();
+            if r.subresource.is_some() {
+                // the status subresource only changes the status
+                patch = json!({
+                    "apiVersion": patch["apiVersion"],
+                    "kind": patch["kind"],
+                    "metadata": patch["metadata"],
+                    "status": patch["status"],
+                });
+                if existing.is_none() {
+                    return Ok(status(
+                        404,
+                        "NotFound",
+                        &format!("{} \"{}\" not found", r.resource, name),
+                    ));
+                }
+            }
+            match apply(
+                &patch,
+                existing.as_ref(),
+                &query,
+                state.owners.get(&key(name)),
+            ) {
+                Err((code, reason, message)) => status(code, reason, &message),
+                Ok(manager) => {
+                    let mut obj = existing.clone().unwrap_or_else(|| json!({}));
+                    let mut patch = patch;
+                    patch["metadata"]
+                        .as_object_mut()
+                        .map(|m| m.remove("resourceVersion"));
+                    merge_patch(&mut obj, &patch);
+                    // the applier owns every applied field, forced or not
+                    let owners = state.owners.entry(key(name)).or_default();
+                    for (pointer, _) in fields(&patch) {
+                        owners.insert(pointer, manager.clone());
+                    }
+                    let obj = state.stamp(obj);
+                    state.objects.insert(key(name), obj.clone());
+                    let code = match existing {
+                        Some(_) => StatusCode::OK,
+                        None => StatusCode::CREATED,
+                    };
+                    ok(code, &obj)
+                }
+            }
+        }
+        (Method::PATCH, Some(name)) => {
+            let patch: Value = serde_json::from_slice(&body).unwrap();
+            match state.objects.get(&key(name)).cloned() {
+                Some(old) => {
+                    let mut obj = old.clone();
+                    merge_patch(&mut obj, &patch);
+                    let manager = query_param(&query, "fieldManager");
+                    state.own(
+                        &key(name),
+                        manager.as_deref().unwrap_or(KUBECTL),
+                        Some(&old),
+                        &obj,
+                    );
+                    let obj = state.stamp(obj);
+                    state.objects.insert(key(name), obj.clone());
+                    ok(StatusCode::OK, &obj)
+                }
+                None => status(
+                    404,
+                    "NotFound",
+                    &format!("{} \"{}\" not found", r.resource, name),
+                ),
+            }
+        }
+        (Method::DELETE, Some(name)) => match state.objects.remove(&key(name)) {
+            Some(obj) => ok(StatusCode::OK, &obj),
+            None => status(
+                404,
+                "NotFound",
+                &format!("{} \"{}\" not found", r.resource, name),
+            ),
+        },
+        _ => status(405, "MethodNotAllowed", "method not allowed"),
+    };
+
+    Ok(resp)
+}
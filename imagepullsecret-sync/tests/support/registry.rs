@@ -0,0 +1,135 @@
+//! A docker registry speaking just the v2 auth handshake, with one user.
+#![allow(dead_code)]
+
+use hyper::{
+    header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde_json::json;
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+const TOKEN: &str = "registry-token";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Auth {
+    // /v2/ challenges for Basic auth
+    Basic,
+    // /v2/ points to the /token service
+    Bearer,
+    // /v2/ fails with a 503
+    Down,
+}
+
+struct State {
+    auth: Auth,
+    basic: String,
+    // the paths requested, with their query
+    requests: Vec<String>,
+}
+
+#[derive(Clone)]
+pub struct MockRegistry {
+    state: Arc<Mutex<State>>,
+    addr: SocketAddr,
+}
+
+impl MockRegistry {
+    pub async fn start(auth: Auth, username: &str, password: &str) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            auth,
+            basic: format!(
+                "Basic {}",
+                base64::encode(format!("{}:{}", username, password))
+            ),
+            requests: vec![],
+        }));
+
+        // the token realm needs the address before serving
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let base = format!("http://{}", addr);
+
+        let svc_state = state.clone();
+        let make_svc = make_service_fn(move |_| {
+            let (state, base) = (svc_state.clone(), base.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    let resp = handle(&state, req, &base);
+                    async move { Ok::<_, Infallible>(resp) }
+                }))
+            }
+        });
+        let server = Server::from_tcp(listener).unwrap().serve(make_svc);
+        tokio::spawn(server);
+
+        MockRegistry { state, addr }
+    }
+
+    /// The server of a config pointing to this registry.
+    pub fn server(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn set_auth(&self, auth: Auth) {
+        self.state.lock().unwrap().auth = auth;
+    }
+
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+}
+
+fn handle(state: &Mutex<State>, req: Request<Body>, base: &str) -> Response<Body> {
+    let mut state = state.lock().unwrap();
+    let path = req.uri().path().to_string();
+    state.requests.push(match req.uri().query() {
+        Some(q) => format!("{}?{}", path, q),
+        None => path.clone(),
+    });
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    let respond = |status: StatusCode, challenge: Option<String>, body: String| {
+        let mut resp = Response::builder().status(status);
+        if let Some(c) = challenge {
+            resp = resp.header(WWW_AUTHENTICATE, c);
+        }
+        resp.body(Body::from(body)).unwrap()
+    };
+    match (state.auth, path.as_str()) {
+        (Auth::Down, _) => respond(StatusCode::SERVICE_UNAVAILABLE, None, String::new()),
+        (Auth::Basic, "/v2/") if authorization == state.basic => {
+            respond(StatusCode::OK, None, "{}".to_string())
+        }
+        (Auth::Basic, "/v2/") => respond(
+            StatusCode::UNAUTHORIZED,
+            Some(r#"Basic realm="Registry Realm""#.to_string()),
+            String::new(),
+        ),
+        (Auth::Bearer, "/v2/") if authorization == format!("Bearer {}", TOKEN) => {
+            respond(StatusCode::OK, None, "{}".to_string())
+        }
+        (Auth::Bearer, "/v2/") => respond(
+            StatusCode::UNAUTHORIZED,
+            Some(format!(
+                r#"Bearer realm="{}/token",service="mock-registry""#,
+                base
+            )),
+            String::new(),
+        ),
+        (Auth::Bearer, "/token") if authorization == state.basic => {
+            let token = json!({ "token": TOKEN, "expires_in": 300 });
+            respond(StatusCode::OK, None, token.to_string())
+        }
+        (Auth::Bearer, "/token") => respond(StatusCode::UNAUTHORIZED, None, String::new()),
+        _ => respond(StatusCode::NOT_FOUND, None, String::new()),
+    }
+}
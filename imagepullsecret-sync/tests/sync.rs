@@ -0,0 +1,710 @@
+mod support;
+
+use imagepullsecret_sync::{
+    config::{Config, RegistryAuth},
+    error::SyncError,
+    settings::Settings,
+    worker::SyncWorker,
+};
+use k8s_openapi::api::core::v1::Namespace;
+use serde_json::json;
+use support::{namespace, secret, service_account, MockApiServer};
+
+const MANAGED: &str = "app.kubernetes.io/managed-by";
+
+fn config(server: &str, namespaces: &[&str]) -> Config {
+    Config {
+        server: server.to_string(),
+        username: "user".to_string(),
+        password: "pass".to_string(),
+        namespaces: namespaces.iter().map(|n| n.to_string()).collect(),
+        ..Config::default()
+    }
+}
+
+fn auth(cfg: &Config) -> String {
+    RegistryAuth::new(
+        cfg.username.clone(),
+        cfg.password.clone(),
+        cfg.server.clone(),
+    )
+    .base64_encode()
+}
+
+fn ns(name: &str) -> Namespace {
+    serde_json::from_value(namespace(name, json!({}))).unwrap()
+}
+
+fn pull_secrets(api: &MockApiServer, ns: &str, sa: &str) -> Vec<String> {
+    let sa = api.get("serviceaccounts", ns, sa).unwrap();
+    sa["imagePullSecrets"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["name"].as_str().unwrap().to_string())
+        .collect()
+}
+
+async fn setup(namespaces: &[&str]) -> (MockApiServer, SyncWorker) {
+    let api = MockApiServer::start().await;
+    for n in namespaces {
+        api.insert(namespace(n, json!({})));
+        api.insert(service_account(n, "default", &[]));
+    }
+    let worker = SyncWorker::new(api.client(), Settings::default()).unwrap();
+    (api, worker)
+}
+
+#[tokio::test]
+async fn creates_secret_in_target_namespace() {
+    let (api, worker) = setup(&["default", "team-a"]).await;
+    let cfg = config("registry.example.com", &["team-a"]);
+
+    let reports = worker
+        .ensure(vec![ns("default"), ns("team-a")], vec![cfg.clone()])
+        .await;
+
+    assert!(reports.iter().all(|r| r.errors.is_empty()));
+    assert!(api
+        .get("secrets", "default", "registry.example.com")
+        .is_none());
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(s["metadata"]["namespace"], "team-a");
+    assert_eq!(s["metadata"]["labels"][MANAGED], "imagepullsecret-sync");
+    assert_eq!(s["data"][".dockerconfigjson"], auth(&cfg));
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["registry.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn patches_outdated_secret_only() {
+    let (api, worker) = setup(&["team-a"]).await;
+    let cfg = config("registry.example.com", &["*"]);
+    api.insert(secret(
+        "team-a",
+        "registry.example.com",
+        json!({ MANAGED: "imagepullsecret-sync" }),
+        json!({ ".dockerconfigjson": "b2xk" }),
+    ));
+
+    worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(s["data"][".dockerconfigjson"], auth(&cfg));
+    let mutations = api.mutations().len();
+
+    // a second pass with the same config is a no-op
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(api.mutations().len(), mutations);
+
+    let metrics = worker.metrics();
+    assert_eq!(metrics.secret_count("team-a", "patched"), 1);
+    assert_eq!(metrics.secret_count("team-a", "created"), 0);
+}
+
+#[tokio::test]
+async fn stripped_label_is_restored() {
+    let (api, worker) = setup(&["team-a"]).await;
+    let cfg = config("registry.example.com", &["*"]);
+    worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+
+    // edited by hand, the data is still right
+    let mut s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    s["metadata"]["labels"] = json!({});
+    api.insert(s);
+    api.insert(service_account("team-a", "default", &[]));
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(reports[0].errors.is_empty());
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(s["metadata"]["labels"][MANAGED], "imagepullsecret-sync");
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["registry.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn forbidden_is_reported_per_namespace() {
+    let (api, worker) = setup(&["team-a", "team-b"]).await;
+    api.fail(
+        "PATCH",
+        "/api/v1/namespaces/team-a/secrets/registry.example.com",
+        403,
+    );
+    let cfg = config("registry.example.com", &["*"]);
+
+    let reports = worker
+        .ensure(vec![ns("team-a"), ns("team-b")], vec![cfg])
+        .await;
+
+    assert_eq!(reports[0].namespace, "team-a");
+    assert_eq!(reports[0].failed, vec!["registry.example.com"]);
+    assert_eq!(worker.metrics().secret_count("team-a", "failed"), 1);
+    assert_eq!(worker.metrics().secret_count("team-b", "created"), 1);
+    match reports[0].errors.as_slice() {
+        [SyncError::Forbidden { verb, kind, ns, .. }] => {
+            assert_eq!((*verb, *kind, ns.as_str()), ("apply", "secret", "team-a"));
+        }
+        errors => panic!("unexpected errors {:?}", errors),
+    }
+    assert!(pull_secrets(&api, "team-a", "default").is_empty());
+
+    assert!(reports[1].errors.is_empty());
+    assert_eq!(reports[1].synced, vec!["registry.example.com"]);
+}
+
+#[tokio::test]
+async fn apply_conflict_is_typed() {
+    let (api, worker) = setup(&["team-a"]).await;
+    api.fail(
+        "PATCH",
+        "/api/v1/namespaces/team-a/secrets/registry.example.com",
+        409,
+    );
+    let cfg = config("registry.example.com", &["*"]);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(matches!(
+        reports[0].errors.as_slice(),
+        [SyncError::Conflict { .. }]
+    ));
+}
+
+#[tokio::test]
+async fn edited_secret_is_taken_over() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let settings = Settings {
+        field_manager: "ips-test".to_string(),
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    let cfg = config("registry.example.com", &["*"]);
+    worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+    let data = "/data/.dockerconfigjson";
+    assert_eq!(
+        api.owner("secrets", "team-a", "registry.example.com", data),
+        Some("ips-test".to_string())
+    );
+
+    // kubectl edit takes over the data field
+    let mut s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    s["data"][".dockerconfigjson"] = json!("b2xk");
+    api.insert(s);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+
+    assert!(reports[0].errors.is_empty());
+    let s = api
+        .get("secrets", "team-a", "registry.example.com")
+        .unwrap();
+    assert_eq!(s["data"][".dockerconfigjson"], auth(&cfg));
+    assert_eq!(
+        api.owner("secrets", "team-a", "registry.example.com", data),
+        Some("ips-test".to_string())
+    );
+}
+
+#[tokio::test]
+async fn concurrent_sa_change_is_retried() {
+    let (api, worker) = setup(&["team-a"]).await;
+    api.insert(service_account("team-a", "default", &["other"]));
+    api.fail_once(
+        "PATCH",
+        "/api/v1/namespaces/team-a/serviceaccounts/default",
+        409,
+    );
+    let cfg = config("registry.example.com", &["*"]);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["other", "registry.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn missing_service_account_is_not_found() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    let worker = SyncWorker::new(api.client(), Settings::default()).unwrap();
+    let cfg = config("registry.example.com", &["*"]);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert_eq!(reports[0].synced, vec!["registry.example.com"]);
+    match reports[0].errors.as_slice() {
+        [SyncError::NotFound { kind, name, .. }] => {
+            assert_eq!((*kind, name.as_str()), ("serviceaccount", "default"));
+        }
+        errors => panic!("unexpected errors {:?}", errors),
+    }
+}
+
+#[tokio::test]
+async fn get_error_is_surfaced() {
+    let (api, worker) = setup(&["team-a"]).await;
+    api.fail(
+        "GET",
+        "/api/v1/namespaces/team-a/secrets/registry.example.com",
+        500,
+    );
+    let cfg = config("registry.example.com", &["*"]);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+
+    assert!(matches!(
+        reports[0].errors.as_slice(),
+        [SyncError::Kube { verb: "get", .. }]
+    ));
+    // only the SyncFailed event
+    assert!(api
+        .mutations()
+        .iter()
+        .all(|(_, path)| path.ends_with("/events")));
+}
+
+#[tokio::test]
+async fn garbage_collects_removed_registries() {
+    let (api, worker) = setup(&["team-a"]).await;
+    let keep = config("keep.example.com", &["*"]);
+    let drop = config("drop.example.com", &["*"]);
+    api.insert(secret("team-a", "manual", json!({}), json!({})));
+
+    worker
+        .ensure(vec![ns("team-a")], vec![keep.clone(), drop])
+        .await;
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["keep.example.com", "drop.example.com"]
+    );
+
+    // the secret stays until no service account references it
+    api.fail_once(
+        "PATCH",
+        "/api/v1/namespaces/team-a/serviceaccounts/default",
+        500,
+    );
+    let reports = worker.ensure(vec![ns("team-a")], vec![keep.clone()]).await;
+    assert_eq!(reports[0].errors.len(), 1);
+    assert!(reports[0].deleted.is_empty());
+    assert!(api.get("secrets", "team-a", "drop.example.com").is_some());
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![keep]).await;
+
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(reports[0].deleted, vec!["drop.example.com"]);
+    assert!(api.get("secrets", "team-a", "drop.example.com").is_none());
+    assert!(api.get("secrets", "team-a", "keep.example.com").is_some());
+    assert!(api.get("secrets", "team-a", "manual").is_some());
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["keep.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn garbage_collection_covers_former_targets() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let mut builder = service_account("team-a", "builder", &[]);
+    builder["metadata"]["labels"] = json!({ "pull": "yes" });
+    api.insert(builder);
+    let settings = Settings {
+        service_account_selector: Some("pull=yes".to_string()),
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    let keep = config("keep.example.com", &["*"]);
+    let drop = config("drop.example.com", &["*"]);
+    worker
+        .ensure(vec![ns("team-a")], vec![keep.clone(), drop])
+        .await;
+    assert_eq!(
+        pull_secrets(&api, "team-a", "builder"),
+        vec!["keep.example.com", "drop.example.com"]
+    );
+
+    // builder stops being a target but still references the secret
+    let mut builder = api.get("serviceaccounts", "team-a", "builder").unwrap();
+    builder["metadata"]["labels"] = json!({});
+    api.insert(builder);
+    let reports = worker.ensure(vec![ns("team-a")], vec![keep]).await;
+
+    assert!(reports[0].errors.is_empty(), "{:?}", reports[0].errors);
+    assert_eq!(reports[0].deleted, vec!["drop.example.com"]);
+    assert_eq!(
+        pull_secrets(&api, "team-a", "builder"),
+        vec!["keep.example.com"]
+    );
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["keep.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn renamed_secrets_are_migrated() {
+    let (api, worker) = setup(&["team-a"]).await;
+    let configs = vec![
+        config("registry.local:5000/team", &["*"]),
+        config("quay.io", &["*"]),
+    ];
+    // a secret of the old raw naming, next to a manual one
+    api.insert(service_account("team-a", "default", &["quay.io", "manual"]));
+    worker.ensure(vec![ns("team-a")], configs.clone()).await;
+    assert!(api
+        .get("secrets", "team-a", "registry.local-5000-team")
+        .is_some());
+
+    let settings = Settings {
+        secret_name_template: "regcred-{{host}}".to_string(),
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings.clone()).unwrap();
+    let plan = worker
+        .clone()
+        .with_dry_run(true)
+        .ensure(vec![ns("team-a")], configs.clone())
+        .await;
+    let renamed: Vec<String> = plan[0]
+        .changes
+        .iter()
+        .filter(|c| c.kind == "secret" && c.detail.is_some())
+        .map(|c| c.to_string())
+        .collect();
+    assert_eq!(
+        renamed,
+        vec![
+            "- secret team-a/quay.io (renamed to regcred-quay.io)",
+            "- secret team-a/registry.local-5000-team (renamed to regcred-registry.local)",
+        ]
+    );
+
+    let reports = worker.ensure(vec![ns("team-a")], configs).await;
+    assert!(reports[0].errors.is_empty());
+    assert!(api.get("secrets", "team-a", "quay.io").is_none());
+    assert!(api.get("secrets", "team-a", "regcred-quay.io").is_some());
+    // the references keep their position
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["regcred-quay.io", "manual", "regcred-registry.local"]
+    );
+
+    // the second registry on the same host collides and keeps nothing
+    let reports = worker
+        .ensure(
+            vec![ns("team-a")],
+            vec![
+                config("registry.local:5000/team", &["*"]),
+                config("registry.local:5001", &["*"]),
+            ],
+        )
+        .await;
+    assert_eq!(reports[0].synced, vec!["regcred-registry.local"]);
+    assert!(!reports[0].registries.contains_key("registry.local:5001"));
+}
+
+#[tokio::test]
+async fn merges_registries_into_one_secret() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let settings = Settings {
+        merge_secret_name: Some("regcred".to_string()),
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    let configs = vec![
+        config("a.example.com", &["*"]),
+        config("b.example.com", &["team-*"]),
+        config("c.example.com", &["other"]),
+    ];
+
+    let reports = worker.ensure(vec![ns("team-a")], configs.clone()).await;
+
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(reports[0].synced, vec!["regcred"]);
+    let s = api.get("secrets", "team-a", "regcred").unwrap();
+    let want = RegistryAuth::from_configs(configs.iter().take(2)).base64_encode();
+    assert_eq!(s["data"][".dockerconfigjson"], want);
+    assert_eq!(
+        s["metadata"]["annotations"]["imagepullsecret-sync/server"],
+        "a.example.com,b.example.com"
+    );
+    assert!(api.get("secrets", "team-a", "a.example.com").is_none());
+    assert_eq!(pull_secrets(&api, "team-a", "default"), vec!["regcred"]);
+}
+
+#[tokio::test]
+async fn queued_namespaces_are_coalesced() {
+    let (api, worker) = setup(&["team-a", "team-b"]).await;
+    let configs = "- server: registry.example.com\n  username: user\n  password: pass\n  namespaces: [team-a]\n";
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(configs) }),
+    ));
+    let worker = std::sync::Arc::new(worker);
+
+    // a burst of events before the workers run is reconciled once
+    for _ in 0..5 {
+        worker.enqueue("team-a", "test");
+    }
+    worker.enqueue("team-b", "test");
+    assert_eq!(worker.queue().len(), 2);
+
+    let w = worker.clone();
+    tokio::spawn(async move { w.process_queue().await });
+    for _ in 0..100 {
+        if worker.queue().is_idle() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    assert!(worker.queue().is_idle());
+    assert!(api
+        .get("secrets", "team-a", "registry.example.com")
+        .is_some());
+    assert!(api
+        .get("secrets", "team-b", "registry.example.com")
+        .is_none());
+    let applies = api
+        .mutations()
+        .iter()
+        .filter(|(m, p)| m == "PATCH" && p.ends_with("/secrets/registry.example.com"))
+        .count();
+    assert_eq!(applies, 1);
+}
+
+#[tokio::test]
+async fn configs_changed_while_not_watched_are_applied() {
+    let (api, worker) = setup(&["team-a", "team-b"]).await;
+    let config_secret = |namespaces: &str| {
+        let configs = format!(
+            "- server: registry.example.com\n  username: user\n  password: pass\n  namespaces: {}\n",
+            namespaces
+        );
+        secret(
+            "default",
+            "docker-registry",
+            json!({}),
+            json!({ "registry_secrets": base64::encode(configs) }),
+        )
+    };
+    api.insert(config_secret("[team-a]"));
+
+    // the initial sync is not done by the watchers
+    assert!(!worker.update_changed_configs("test").await.unwrap());
+    let configs = worker.read_config().await.unwrap();
+    worker.update_configs(configs, "test").await.unwrap();
+    assert!(!worker.update_changed_configs("test").await.unwrap());
+
+    api.insert(config_secret("[team-a, team-b]"));
+    assert!(worker.update_changed_configs("test").await.unwrap());
+    assert!(!worker.update_changed_configs("test").await.unwrap());
+    worker.reconcile("team-b").await.unwrap();
+    assert!(api
+        .get("secrets", "team-b", "registry.example.com")
+        .is_some());
+}
+
+#[tokio::test]
+async fn outcomes_are_published() {
+    let (api, worker) = setup(&["team-a", "team-b"]).await;
+    api.insert(secret("default", "docker-registry", json!({}), json!({})));
+    api.fail(
+        "PATCH",
+        "/api/v1/namespaces/team-b/secrets/registry.example.com",
+        403,
+    );
+    let cfg = config("registry.example.com", &["*"]);
+    let all = || vec![ns("team-a"), ns("team-b")];
+
+    worker.ensure(all(), vec![cfg.clone()]).await;
+    worker.ensure(all(), vec![cfg]).await;
+
+    let events = |ns: &str| -> Vec<serde_json::Value> {
+        api.list("events")
+            .into_iter()
+            .filter(|e| e["metadata"]["namespace"] == ns)
+            .collect()
+    };
+    match events("team-a").as_slice() {
+        [e] => {
+            assert_eq!(
+                (e["type"].as_str(), e["reason"].as_str()),
+                (Some("Normal"), Some("Synced"))
+            );
+            assert_eq!(e["involvedObject"]["kind"], "Namespace");
+        }
+        events => panic!("unexpected events {:?}", events),
+    }
+    // the repeated failure is counted on the first event
+    match events("team-b").as_slice() {
+        [e] => {
+            assert_eq!(e["reason"], "SyncFailed");
+            assert_eq!(e["count"], 2);
+        }
+        events => panic!("unexpected events {:?}", events),
+    }
+
+    // the first status is written right away
+    let _ = tokio::time::timeout(
+        std::time::Duration::from_millis(300),
+        worker.report_status(),
+    )
+    .await;
+    let cfg_secret = api.get("secrets", "default", "docker-registry").unwrap();
+    let status: serde_json::Value = serde_json::from_str(
+        cfg_secret["metadata"]["annotations"]["imagepullsecret-sync/status"]
+            .as_str()
+            .unwrap(),
+    )
+    .unwrap();
+    let registry = &status["registries"]["registry.example.com"];
+    assert_eq!(
+        (registry["synced"].as_u64(), registry["failed"].as_u64()),
+        (Some(1), Some(1))
+    );
+    assert_eq!(registry["failedNamespaces"], json!(["team-b"]));
+    assert!(registry["lastError"]
+        .as_str()
+        .unwrap()
+        .contains("forbidden"));
+    match events("default").as_slice() {
+        [e] => {
+            assert_eq!(
+                (e["type"].as_str(), e["reason"].as_str()),
+                (Some("Warning"), Some("SyncFailed"))
+            );
+            assert_eq!(e["involvedObject"]["name"], "docker-registry");
+        }
+        events => panic!("unexpected events {:?}", events),
+    }
+}
+
+#[tokio::test]
+async fn namespace_scoped_mode_keeps_secrets_of_selectors() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({ "team": "a" })));
+    api.insert(service_account(
+        "team-a",
+        "default",
+        &["registry.example.com"],
+    ));
+    let mut existing = secret(
+        "team-a",
+        "registry.example.com",
+        json!({ MANAGED: "imagepullsecret-sync" }),
+        json!({ ".dockerconfigjson": base64::encode("{\"auths\":{}}") }),
+    );
+    existing["metadata"]["annotations"] =
+        json!({ "imagepullsecret-sync/server": "registry.example.com" });
+    api.insert(existing);
+    let configs = "- server: registry.example.com\n  username: user\n  password: pass\n  namespace_selector: team=a\n";
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(configs) }),
+    ));
+    let settings = Settings {
+        watch_namespaces: vec!["team-a".to_string()],
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+
+    // the labels of a watch namespace can't be read, a selector would match nothing
+    let err = worker.read_config().await.unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("namespace_selector can't be used with --watch-namespaces"),
+        "{:#}",
+        err
+    );
+
+    let cfg = Config {
+        namespaces: vec![],
+        namespace_selector: Some("team=a".to_string()),
+        ..config("registry.example.com", &[])
+    };
+    let all_ns = worker.get_all_ns().await.unwrap();
+    let reports = worker.ensure(all_ns, vec![cfg]).await;
+    assert!(reports[0].deleted.is_empty());
+    assert!(api
+        .get("secrets", "team-a", "registry.example.com")
+        .is_some());
+    assert_eq!(
+        pull_secrets(&api, "team-a", "default"),
+        vec!["registry.example.com"]
+    );
+}
+
+#[tokio::test]
+async fn namespace_scoped_mode_reads_no_namespaces() {
+    let api = MockApiServer::start().await;
+    for n in ["team-a", "team-b", "team-c"].iter() {
+        api.insert(namespace(n, json!({})));
+        api.insert(service_account(n, "default", &[]));
+    }
+    // a Role can't grant any of these
+    api.fail("GET", "/api/v1/namespaces", 403);
+    for n in ["team-a", "team-b", "team-c"].iter() {
+        api.fail("GET", &format!("/api/v1/namespaces/{}", n), 403);
+    }
+    let configs = "- server: registry.example.com\n  username: user\n  password: pass\n  namespaces: [\"*\"]\n";
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(configs) }),
+    ));
+    let settings = Settings {
+        watch_namespaces: vec!["team-a".to_string(), "team-c".to_string()],
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+
+    let configs = worker.read_config().await.unwrap();
+    worker.update_configs(configs, "test").await.unwrap();
+    assert_eq!(worker.queue().len(), 2);
+    for n in ["team-a", "team-b", "team-c"].iter() {
+        let report = worker.reconcile(n).await.unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+    }
+
+    assert!(api
+        .get("secrets", "team-a", "registry.example.com")
+        .is_some());
+    assert!(api
+        .get("secrets", "team-b", "registry.example.com")
+        .is_none());
+    assert!(api
+        .get("secrets", "team-c", "registry.example.com")
+        .is_some());
+    assert_eq!(
+        pull_secrets(&api, "team-c", "default"),
+        vec!["registry.example.com"]
+    );
+}
@@ -0,0 +1,122 @@
+mod support;
+
+use imagepullsecret_sync::{
+    config::{Config, RegistryAuth},
+    credentials::CredentialSource,
+    settings::Settings,
+    worker::SyncWorker,
+};
+use k8s_openapi::api::core::v1::Namespace;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use support::{namespace, service_account, MockApiServer};
+
+fn ns(name: &str) -> Namespace {
+    serde_json::from_value(namespace(name, json!({}))).unwrap()
+}
+
+// a command printing `token-<n>` where n counts its runs in `counter`,
+// with the expiry `expires_at` when given
+fn exec_config(counter: &Path, expires_at: Option<&str>) -> Config {
+    let print = match expires_at {
+        Some(t) => format!(
+            r#"echo "{{\"token\":\"token-$n\",\"expires_at\":\"{}\"}}""#,
+            t
+        ),
+        None => "echo token-$n".to_string(),
+    };
+    let script = format!(
+        "n=$(cat {0} 2>/dev/null || echo 0); n=$((n+1)); echo $n > {0}; {1}",
+        counter.display(),
+        print
+    );
+    Config {
+        server: "ecr.example.com".to_string(),
+        namespaces: vec!["*".to_string()],
+        credentials: Some(CredentialSource::Exec {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script],
+            username: Some("AWS".to_string()),
+            ttl: 3600,
+        }),
+        ..Config::default()
+    }
+}
+
+fn counter(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ips-token-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn runs(counter: &Path) -> String {
+    std::fs::read_to_string(counter).unwrap().trim().to_string()
+}
+
+async fn setup(settings: Settings) -> (MockApiServer, SyncWorker) {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    (api, worker)
+}
+
+fn dockerconfig(api: &MockApiServer) -> serde_json::Value {
+    api.get("secrets", "team-a", "ecr.example.com").unwrap()["data"][".dockerconfigjson"].clone()
+}
+
+fn want(password: &str) -> String {
+    RegistryAuth::new(
+        "AWS".to_string(),
+        password.to_string(),
+        "ecr.example.com".to_string(),
+    )
+    .base64_encode()
+}
+
+#[tokio::test]
+async fn fresh_token_is_cached() {
+    let (api, worker) = setup(Settings::default()).await;
+    let counter = counter("cached");
+    let cfg = exec_config(&counter, None);
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(dockerconfig(&api), want("token-1"));
+
+    worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+    assert_eq!(runs(&counter), "1");
+    std::fs::remove_file(&counter).unwrap();
+}
+
+#[tokio::test]
+async fn due_token_is_refreshed() {
+    // every token is expired right away
+    let (api, worker) = setup(Settings::default()).await;
+    let counter = counter("due");
+    let cfg = exec_config(&counter, Some("2020-01-01T00:00:00Z"));
+
+    worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+    assert_eq!(dockerconfig(&api), want("token-1"));
+
+    let reports = worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+    assert!(reports[0].errors.is_empty());
+    assert_eq!(runs(&counter), "2");
+    assert_eq!(dockerconfig(&api), want("token-2"));
+    std::fs::remove_file(&counter).unwrap();
+}
+
+#[tokio::test]
+async fn short_lived_token_is_cached() {
+    // expires within --token-refresh-before, still used for half its lifetime
+    let (api, worker) = setup(Settings::default()).await;
+    let counter = counter("short");
+    let expires_at = (chrono::Utc::now() + chrono::Duration::seconds(60)).to_rfc3339();
+    let cfg = exec_config(&counter, Some(&expires_at));
+
+    worker.ensure(vec![ns("team-a")], vec![cfg.clone()]).await;
+    worker.ensure(vec![ns("team-a")], vec![cfg]).await;
+    assert_eq!(runs(&counter), "1");
+    assert_eq!(dockerconfig(&api), want("token-1"));
+    std::fs::remove_file(&counter).unwrap();
+}
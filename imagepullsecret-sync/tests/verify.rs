@@ -0,0 +1,109 @@
+mod support;
+
+use imagepullsecret_sync::{
+    config::Config,
+    settings::Settings,
+    verify::{Verifier, VerifyError},
+    worker::SyncWorker,
+};
+use serde_json::json;
+use support::{
+    namespace,
+    registry::{Auth, MockRegistry},
+    service_account, MockApiServer,
+};
+
+fn config(server: &str, password: &str) -> Config {
+    Config {
+        server: server.to_string(),
+        username: "ci".to_string(),
+        password: password.to_string(),
+        namespaces: vec!["*".to_string()],
+        ..Config::default()
+    }
+}
+
+#[tokio::test]
+async fn bearer_handshake() {
+    let registry = MockRegistry::start(Auth::Bearer, "ci", "s3cret").await;
+    let verifier = Verifier::new();
+
+    assert_eq!(
+        verifier.verify(&config(&registry.server(), "s3cret")).await,
+        Ok(())
+    );
+    assert_eq!(
+        registry.requests(),
+        vec!["/v2/", "/token?service=mock-registry", "/v2/"]
+    );
+
+    match verifier.verify(&config(&registry.server(), "typo")).await {
+        Err(VerifyError::Rejected { message, .. }) => assert!(message.contains("/token")),
+        other => panic!("unexpected {:?}", other),
+    }
+
+    // verified credentials are not checked again
+    let before = registry.requests().len();
+    verifier
+        .verify(&config(&registry.server(), "s3cret"))
+        .await
+        .unwrap();
+    assert_eq!(registry.requests().len(), before);
+}
+
+#[tokio::test]
+async fn basic_handshake() {
+    let registry = MockRegistry::start(Auth::Basic, "ci", "s3cret").await;
+    let verifier = Verifier::new();
+
+    assert!(verifier
+        .verify(&config(&registry.server(), "s3cret"))
+        .await
+        .is_ok());
+    assert!(matches!(
+        verifier.verify(&config(&registry.server(), "typo")).await,
+        Err(VerifyError::Rejected { .. })
+    ));
+
+    registry.set_auth(Auth::Down);
+    assert!(matches!(
+        verifier.verify(&config(&registry.server(), "other")).await,
+        Err(VerifyError::Unavailable { .. })
+    ));
+}
+
+#[tokio::test]
+async fn rejected_credentials_are_not_rolled_out() {
+    let registry = MockRegistry::start(Auth::Bearer, "ci", "s3cret").await;
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({})));
+    api.insert(service_account("team-a", "default", &[]));
+    let settings = Settings {
+        verify_credentials: true,
+        ..Settings::default()
+    };
+    let worker = SyncWorker::new(api.client(), settings).unwrap();
+    let name = registry.server().replace("http://", "").replace(':', "-");
+
+    let all_ns = worker.get_all_ns().await.unwrap();
+    let reports = worker
+        .ensure(all_ns.clone(), vec![config(&registry.server(), "s3cret")])
+        .await;
+    assert_eq!(reports[0].synced, vec![name.clone()]);
+    let synced = api.get("secrets", "team-a", &name).unwrap();
+
+    // the typo keeps the working secret in place
+    let reports = worker
+        .ensure(all_ns.clone(), vec![config(&registry.server(), "typo")])
+        .await;
+    assert!(reports[0].changes.is_empty());
+    assert_eq!(api.get("secrets", "team-a", &name).unwrap(), synced);
+
+    // an unreachable registry doesn't block the rollout
+    registry.set_auth(Auth::Down);
+    let reports = worker
+        .ensure(all_ns, vec![config(&registry.server(), "rotated")])
+        .await;
+    assert_eq!(reports[0].changes.len(), 1);
+    assert_ne!(api.get("secrets", "team-a", &name).unwrap(), synced);
+}
@@ -0,0 +1,263 @@
+mod support;
+
+use imagepullsecret_sync::{inject::Injector, settings::Settings, webhook};
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkey::PKey,
+    rsa::Rsa,
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use support::{namespace, secret, MockApiServer};
+
+// a self-signed certificate for localhost, as (cert, key) PEM files
+fn self_signed(dir: &str) -> (PathBuf, PathBuf, Vec<u8>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .ip("127.0.0.1")
+        .build(&cert.x509v3_context(None, None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let pem = cert.build().to_pem().unwrap();
+
+    let dir = std::env::temp_dir().join(format!("{}-{}", dir, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("tls.crt"), dir.join("tls.key"));
+    std::fs::write(&cert_path, &pem).unwrap();
+    std::fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert_path, key_path, pem)
+}
+
+// serve `webhook` on a free port, with a client trusting its certificate
+async fn start(dir: &str, webhook: webhook::Webhook) -> (String, reqwest::Client) {
+    let (cert, key, pem) = self_signed(dir);
+    let acceptor = webhook::ReloadingAcceptor::new(&cert, &key).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(webhook::serve(listener, acceptor, webhook));
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&pem).unwrap())
+        .build()
+        .unwrap();
+    (format!("https://localhost:{}", port), client)
+}
+
+#[tokio::test]
+async fn reviews_are_answered_over_tls() {
+    let webhook = webhook::Webhook::new(Settings::default());
+    let (base, client) = start("webhook-test", webhook).await;
+    let url = format!("{}{}", base, webhook::VALIDATE_PATH);
+    let post = |review: Value| {
+        let request = client.post(&url).json(&review).send();
+        async move {
+            let resp = request.await.unwrap();
+            assert_eq!(resp.status(), 200);
+            resp.json::<Value>().await.unwrap()
+        }
+    };
+
+    let mut review: Value =
+        serde_json::from_str(include_str!("admission/config-secret.json")).unwrap();
+    let answer = post(review.clone()).await;
+    assert_eq!(answer["apiVersion"], "admission.k8s.io/v1");
+    assert_eq!(answer["kind"], "AdmissionReview");
+    let response = &answer["response"];
+    assert_eq!(response["uid"], review["request"]["uid"]);
+    assert_eq!(response["allowed"], false);
+    assert_eq!(response["status"]["code"], 422);
+    assert_eq!(
+        response["status"]["message"],
+        "invalid registry configs in key 'registry_secrets': \
+         line 5, column 3: namespaces or namespace_selector is required, the registry selects no namespace; \
+         line 5, column 3: duplicate server 'registry.example.com/', first defined at line 1"
+    );
+
+    let valid =
+        "- server: registry.example.com\n  username: ci\n  password: s3cret\n  namespaces: ['*']\n";
+    review["request"]["object"]["data"]["registry_secrets"] = json!(base64::encode(valid));
+    let answer = post(review.clone()).await;
+    assert_eq!(answer["response"]["allowed"], true);
+
+    // a DELETE has no object
+    review["request"]["object"] = Value::Null;
+    let answer = post(review).await;
+    assert_eq!(answer["response"]["allowed"], true);
+
+    let resp = client.get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 405);
+    let resp = client.post(&url).body("{").send().await.unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // pods are only mutated with an injector
+    let url = format!("{}{}", base, webhook::MUTATE_PATH);
+    let resp = client.post(&url).body("{}").send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn pods_get_the_registry_secrets() {
+    let api = MockApiServer::start().await;
+    api.insert(namespace("team-a", json!({ "team": "a" })));
+    let configs = "- server: registry.example.com\n  username: ci\n  password: s3cret\n  namespace_selector: team=a\n\
+                   - server: docker.io\n  username: ci\n  password: s3cret\n  namespaces: ['*']\n\
+                   - server: quay.io\n  username: ci\n  password: s3cret\n  namespaces: [team-b]\n";
+    api.insert(secret(
+        "default",
+        "docker-registry",
+        json!({}),
+        json!({ "registry_secrets": base64::encode(configs) }),
+    ));
+
+    let injector = Injector::new(api.client(), Settings::default()).unwrap();
+    let webhook = webhook::Webhook::new(Settings::default()).with_injector(injector);
+    let (base, client) = start("webhook-inject-test", webhook).await;
+    let url = format!("{}{}", base, webhook::MUTATE_PATH);
+    let post = |review: Value| {
+        let request = client.post(&url).json(&review).send();
+        async move { request.await.unwrap().json::<Value>().await.unwrap() }
+    };
+
+    let mut review: Value =
+        serde_json::from_str(include_str!("admission/pod-create.json")).unwrap();
+    let answer = post(review.clone()).await;
+    let response = &answer["response"];
+    assert_eq!(response["uid"], review["request"]["uid"]);
+    assert_eq!(response["allowed"], true);
+    assert_eq!(response["patchType"], "JSONPatch");
+    // docker hub is already referenced, quay.io doesn't select team-a
+    let patch: Value =
+        serde_json::from_slice(&base64::decode(response["patch"].as_str().unwrap()).unwrap())
+            .unwrap();
+    assert_eq!(
+        patch,
+        json!([{
+            "op": "add",
+            "path": "/spec/imagePullSecrets/-",
+            "value": { "name": "registry.example.com" },
+        }])
+    );
+
+    // a pod which can't be read is admitted unchanged
+    review["request"]["object"]["spec"] = json!("broken");
+    let answer = post(review).await;
+    assert_eq!(answer["response"]["allowed"], true);
+    assert!(answer["response"].get("patch").is_none());
+}
+
+// a POST with a chunked body of `chunks` megabytes and a byte
+fn chunked_request(path: &str, chunks: usize) -> Vec<u8> {
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        path
+    )
+    .into_bytes();
+    for _ in 0..chunks {
+        request.extend_from_slice(format!("{:x}\r\n", 1 << 20).as_bytes());
+        request.extend(std::iter::repeat_n(b' ', 1 << 20));
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"1\r\n \r\n0\r\n\r\n");
+    request
+}
+
+// the status line of the answer to the raw `request`
+async fn status_line(base: &str, pem: &[u8], request: Vec<u8>) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let port: u16 = base.rsplit(':').next().unwrap().parse().unwrap();
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(native_tls::Certificate::from_pem(pem).unwrap())
+        .build()
+        .unwrap();
+    let connector = tokio_native_tls::TlsConnector::from(connector);
+    let tcp = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let tls = connector.connect("localhost", tcp).await.unwrap();
+    let (mut read, mut write) = tokio::io::split(tls);
+    // the server may answer before the request is sent completely
+    let writer = tokio::spawn(async move {
+        let _ = write.write_all(&request).await;
+        write
+    });
+    let mut response = Vec::new();
+    let _ = read.read_to_end(&mut response).await;
+    let _ = writer.await;
+    let response = String::from_utf8_lossy(&response);
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[tokio::test]
+async fn large_chunked_bodies_are_refused() {
+    let (cert, key, pem) = self_signed("webhook-chunked-test");
+    let acceptor = webhook::ReloadingAcceptor::new(&cert, &key).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!(
+        "https://localhost:{}",
+        listener.local_addr().unwrap().port()
+    );
+    let webhook = webhook::Webhook::new(Settings::default());
+    tokio::spawn(webhook::serve(listener, acceptor, webhook));
+
+    // without a Content-Length the limit applies while reading
+    let request = chunked_request(webhook::VALIDATE_PATH, 4);
+    assert_eq!(
+        status_line(&base, &pem, request).await,
+        "HTTP/1.1 413 Payload Too Large"
+    );
+    // a small chunked body is read, it is no AdmissionReview
+    let request = chunked_request(webhook::VALIDATE_PATH, 0);
+    assert_eq!(
+        status_line(&base, &pem, request).await,
+        "HTTP/1.1 400 Bad Request"
+    );
+}
+
+#[tokio::test]
+async fn renewed_certificate_is_served() {
+    let (cert, key, first) = self_signed("webhook-renew-test");
+    let acceptor = webhook::ReloadingAcceptor::new(&cert, &key).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!(
+        "https://localhost:{}{}",
+        listener.local_addr().unwrap().port(),
+        webhook::VALIDATE_PATH
+    );
+    tokio::spawn(webhook::serve(
+        listener,
+        acceptor,
+        webhook::Webhook::new(Settings::default()),
+    ));
+    let trusting = |pem: &[u8]| {
+        reqwest::Client::builder()
+            .add_root_certificate(reqwest::Certificate::from_pem(pem).unwrap())
+            .build()
+            .unwrap()
+    };
+    let resp = trusting(&first).get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 405);
+
+    // cert-manager renews the mounted secret
+    let (_, _, renewed) = self_signed("webhook-renew-test");
+    let resp = trusting(&renewed).get(&url).send().await.unwrap();
+    assert_eq!(resp.status(), 405);
+    assert!(trusting(&first).get(&url).send().await.is_err());
+}